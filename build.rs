@@ -0,0 +1,13 @@
+use std::process::Command;
+
+/// Passes the current commit to the build so preprocessed graphs can
+/// record which build wrote them
+fn main() {
+    let hash = Command::new("git")
+        .args(&["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=DZR_BUILD_HASH={}", hash.trim());
+}
@@ -0,0 +1,237 @@
+use graph::{Graph, Dijkstra, Route, RoutingGoal, Movement, Penalty};
+use provider::ProviderSelection;
+use towers::TechClass;
+
+use std::error::Error;
+use std::io::{Read, Write};
+
+use csv::{Reader, Writer};
+use rayon::prelude::*;
+
+/// Subsegments with at least this coverage count as covered
+const COVERED: f64 = 0.5;
+/// Queries that are routed one after another with the same `Dijkstra`
+const QUERIES_PER_TASK: usize = 64;
+
+/// Settings shared by all routes of a batch
+#[derive(Debug, Clone, Copy)]
+pub struct RouteOptions {
+    pub goal: RoutingGoal,
+    pub movement: Movement,
+    pub tech: TechClass,
+    pub penalty: Penalty,
+}
+
+/// A route between two coordinates
+#[derive(Debug)]
+pub struct RouteQuery {
+    pub id: String,
+    pub from: (f64, f64),
+    pub to: (f64, f64),
+    pub provider: Option<ProviderSelection>,
+}
+
+#[derive(Deserialize)]
+struct QueryRecord {
+    #[serde(default)]
+    id: Option<String>,
+    from_lat: f64,
+    from_lon: f64,
+    to_lat: f64,
+    to_lon: f64,
+    #[serde(default)]
+    provider: Option<String>,
+}
+
+/// Length and coverage of a calculated route
+#[derive(Debug, Serialize)]
+pub struct RouteSummary {
+    /// Length in km
+    pub distance: f64,
+    /// Travel time in hours
+    pub travel_time: f64,
+    /// Covered share of the length
+    pub covered: f64,
+    /// Length of the longest uncovered stretch in km
+    pub longest_gap: f64,
+}
+
+#[derive(Serialize)]
+struct SummaryRecord<'a> {
+    id: &'a str,
+    distance: Option<f64>,
+    travel_time: Option<f64>,
+    covered: Option<f64>,
+    longest_gap: Option<f64>,
+}
+
+/// Reads queries from a CSV file with the columns `from_lat`,
+/// `from_lon`, `to_lat`, `to_lon` and optionally `id` and `provider`.
+/// Rows without provider use `provider`, rows without id are numbered.
+pub fn read_queries<R: Read>(
+    r: R,
+    graph: &Graph,
+    provider: Option<ProviderSelection>,
+) -> Result<Vec<RouteQuery>, Box<Error>> {
+    let mut reader = Reader::from_reader(r);
+    let mut queries = Vec::new();
+    for (row, res) in reader.deserialize().enumerate() {
+        let record: QueryRecord = res?;
+        let provider = match record.provider {
+            Some(ref name) if !name.is_empty() => {
+                Some(graph.providers.parse_selection(name).ok_or_else(|| {
+                    format!("unknown provider {} in row {}", name, row + 1)
+                })?)
+            }
+            _ => provider,
+        };
+        queries.push(RouteQuery {
+            id: record.id.unwrap_or_else(|| (row + 1).to_string()),
+            from: (record.from_lat, record.from_lon),
+            to: (record.to_lat, record.to_lon),
+            provider,
+        });
+    }
+    Ok(queries)
+}
+
+/// Routes from the node next to the origin to the node next to the
+/// destination of `query`. Returns `None` if there is no route.
+pub fn route_query(
+    graph: &Graph,
+    dijkstra: &mut Dijkstra,
+    query: &RouteQuery,
+    options: &RouteOptions,
+) -> Option<RouteSummary> {
+    let source = graph.next_node_to(query.from.0, query.from.1, &options.movement)?;
+    let dest = graph.next_node_to(query.to.0, query.to.1, &options.movement)?;
+    let route = dijkstra.distance(
+        source.0,
+        dest.0,
+        options.goal,
+        options.movement,
+        query.provider,
+        options.tech,
+        options.penalty,
+    )?;
+    Some(summarize(graph, &route, query.provider, options.tech))
+}
+
+/// Routes all `queries` in parallel. Each task reuses a single
+/// `Dijkstra` for several queries. The results are in the order of
+/// `queries`.
+pub fn route_batch(
+    graph: &Graph,
+    queries: &[RouteQuery],
+    options: &RouteOptions,
+) -> Vec<Option<RouteSummary>> {
+    let chunks: Vec<Vec<Option<RouteSummary>>> = queries
+        .par_chunks(QUERIES_PER_TASK)
+        .map(|chunk| {
+            let mut dijkstra = graph.dijkstra();
+            chunk
+                .iter()
+                .map(|q| route_query(graph, &mut dijkstra, q, options))
+                .collect()
+        })
+        .collect();
+    chunks.into_iter().flat_map(|c| c).collect()
+}
+
+/// Writes a CSV row with the id and summary of every query. Queries
+/// without route get empty values.
+pub fn write_summaries<W: Write>(
+    w: W,
+    queries: &[RouteQuery],
+    summaries: &[Option<RouteSummary>],
+) -> Result<(), Box<Error>> {
+    let mut writer = Writer::from_writer(w);
+    for (query, summary) in queries.iter().zip(summaries) {
+        writer.serialize(SummaryRecord {
+            id: &query.id,
+            distance: summary.as_ref().map(|s| s.distance),
+            travel_time: summary.as_ref().map(|s| s.travel_time),
+            covered: summary.as_ref().map(|s| s.covered),
+            longest_gap: summary.as_ref().map(|s| s.longest_gap),
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Sums up the covered length and the longest gap along `route` using
+/// the coverage of its subsegments. Routes without length count as
+/// covered.
+fn summarize(
+    graph: &Graph,
+    route: &Route,
+    provider: Option<ProviderSelection>,
+    tech: TechClass,
+) -> RouteSummary {
+    let layer = graph.segment_coverage.get_all(provider, tech);
+    let mut length = 0.0;
+    let mut covered = 0.0;
+    let mut gap = 0.0;
+    let mut longest_gap: f64 = 0.0;
+    let nodes = route.node_seq.iter().zip(route.node_seq.iter().skip(1));
+    for (&source, &target) in nodes {
+        let edge = match graph.edge_between(source, target) {
+            Some(edge) => edge,
+            None => continue,
+        };
+        for (n, segment_length) in graph.shapes.segment_weights(edge) {
+            length += segment_length;
+            if layer.map_or(false, |l| l.get(n) >= COVERED) {
+                covered += segment_length;
+                gap = 0.0;
+            } else {
+                gap += segment_length;
+                longest_gap = longest_gap.max(gap);
+            }
+        }
+    }
+    RouteSummary {
+        distance: route.distance / 1000.0,
+        travel_time: route.travel_time / 1000.0,
+        covered: if length > 0.0 { covered / length } else { 1.0 },
+        longest_gap: longest_gap / 1000.0,
+    }
+}
+
+#[test]
+fn routes_of_a_batch() {
+    use graph::EdgeInfo;
+
+    let graph = Graph::test(vec![
+        EdgeInfo::new(1, 2, 1.0, 50),
+        EdgeInfo::new(2, 1, 1.0, 50),
+        EdgeInfo::new(2, 3, 1.0, 50),
+        EdgeInfo::new(3, 2, 1.0, 50),
+    ]);
+
+    let csv = "id,from_lat,from_lon,to_lat,to_lon,provider\n\
+               a,52.0,13.0,52.0,13.02,o2\n\
+               b,52.0,13.0,52.0,13.01,\n";
+    let queries = read_queries(csv.as_bytes(), &graph, None).unwrap();
+    assert_eq!(2, queries.len());
+    assert!(queries[1].provider.is_none());
+    let options = RouteOptions {
+        goal: RoutingGoal::Length,
+        movement: Movement::Car,
+        tech: TechClass::Any,
+        penalty: Penalty::Coverage,
+    };
+    let summaries = route_batch(&graph, &queries, &options);
+
+    let first = summaries[0].as_ref().unwrap();
+    assert!((first.distance - 1.37).abs() < 0.01);
+    assert!((first.covered - 0.5).abs() < 0.01);
+    assert!((first.longest_gap - first.distance / 2.0).abs() < 0.01);
+    // without provider nothing is covered
+    let second = summaries[1].as_ref().unwrap();
+    assert_eq!(0.0, second.covered);
+    assert_eq!(second.distance, second.longest_gap);
+
+    let unknown = "from_lat,from_lon,to_lat,to_lon,provider\n52.0,13.0,52.0,13.02,foo\n";
+    assert!(read_queries(unknown.as_bytes(), &graph, None).is_err());
+}
@@ -0,0 +1,306 @@
+use graph::{NodeInfo, OsmNodeId};
+use geom::{Coord, closest_on_segment, EARTH_RADIUS};
+use grid::BoundingBox;
+use towers::Tower;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::f64;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+/// Area an import is restricted to, a polygon with optional holes
+#[derive(Debug)]
+pub struct ClipArea {
+    /// Rings as closed lists of latitude and longitude
+    outer: Vec<Vec<(f64, f64)>>,
+    holes: Vec<Vec<(f64, f64)>>,
+    b_box: BoundingBox,
+}
+
+impl ClipArea {
+    pub fn from_bbox(lat_min: f64, lon_min: f64, lat_max: f64, lon_max: f64) -> ClipArea {
+        ClipArea::new(
+            vec![
+                vec![
+                    (lat_min, lon_min),
+                    (lat_min, lon_max),
+                    (lat_max, lon_max),
+                    (lat_max, lon_min),
+                    (lat_min, lon_min),
+                ],
+            ],
+            Vec::new(),
+        )
+    }
+
+    /// Parses `<lat min>,<lon min>,<lat max>,<lon max>` or loads the
+    /// polygon file `spec` if it ends in `.poly`
+    pub fn from_spec(spec: &str) -> Result<ClipArea, Box<Error>> {
+        if spec.ends_with(".poly") {
+            return ClipArea::from_poly_file(spec);
+        }
+        let values: Vec<f64> = spec
+            .split(',')
+            .map(|v| v.trim().parse())
+            .collect::<Result<_, _>>()?;
+        if values.len() != 4 {
+            return Err(format!("invalid bounding box {}", spec).into());
+        }
+        Ok(ClipArea::from_bbox(values[0], values[1], values[2], values[3]))
+    }
+
+    pub fn from_poly_file<P: AsRef<Path>>(p: P) -> Result<ClipArea, Box<Error>> {
+        ClipArea::from_poly(File::open(p)?)
+    }
+
+    /// Reads a polygon in the Osmosis polygon filter file format.
+    /// Sections whose name starts with `!` are holes.
+    pub fn from_poly<R: Read>(r: R) -> Result<ClipArea, Box<Error>> {
+        let mut outer = Vec::new();
+        let mut holes = Vec::new();
+        let mut lines = BufReader::new(r).lines();
+        // the first line holds the name of the polygon
+        lines.next().ok_or("empty polygon file")??;
+        let mut ring: Option<(bool, Vec<(f64, f64)>)> = None;
+        for line in lines {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line == "END" {
+                match ring.take() {
+                    Some((true, points)) => holes.push(close(points)),
+                    Some((false, points)) => outer.push(close(points)),
+                    None => break,
+                }
+                continue;
+            }
+            if ring.is_none() {
+                ring = Some((line.starts_with('!'), Vec::new()));
+                continue;
+            }
+            if let Some((_, ref mut points)) = ring {
+                let mut values = line.split_whitespace().map(str::parse::<f64>);
+                match (values.next(), values.next()) {
+                    (Some(lon), Some(lat)) => points.push((lat?, lon?)),
+                    _ => return Err(format!("invalid polygon point {}", line).into()),
+                }
+            }
+        }
+        if outer.iter().all(|r| r.len() < 4) {
+            return Err("polygon file without area".into());
+        }
+        Ok(ClipArea::new(outer, holes))
+    }
+
+    fn new(outer: Vec<Vec<(f64, f64)>>, holes: Vec<Vec<(f64, f64)>>) -> ClipArea {
+        let mut b_box = BoundingBox::new();
+        for point in outer.iter().flat_map(|r| r.iter()) {
+            b_box.add_coord(point);
+        }
+        ClipArea {
+            outer,
+            holes,
+            b_box,
+        }
+    }
+
+    pub fn contains<C: Coord>(&self, c: &C) -> bool {
+        self.b_box.contains_point(c.lat(), c.lon()) &&
+            self.outer.iter().any(|r| in_ring(r, c)) &&
+            !self.holes.iter().any(|r| in_ring(r, c))
+    }
+
+    /// Distance in meters from `c` to the area, zero inside of it
+    pub fn distance<C: Coord>(&self, c: &C) -> f64 {
+        if self.contains(c) {
+            return 0.0;
+        }
+        self.rings()
+            .flat_map(|r| r.windows(2))
+            .map(|pair| closest_on_segment(c, &pair[0], &pair[1]).1)
+            .fold(f64::INFINITY, f64::min)
+    }
+
+    /// Removes all towers that are farther away from the area than the
+    /// largest tower range. Returns the number of removed towers.
+    pub fn retain_towers(&self, towers: &mut Vec<Tower>) -> usize {
+        let max_range = towers.iter().map(|t| t.range).fold(0.0, f64::max);
+        let near = self.b_box_around(max_range);
+        let count = towers.len();
+        // only towers in the box around the area need the exact distance
+        towers.retain(|t| {
+            near.contains_point(t.lat, t.lon) && self.distance(t) <= max_range
+        });
+        count - towers.len()
+    }
+
+    /// Bounding box of everything within `dist` meters of the area
+    fn b_box_around(&self, dist: f64) -> BoundingBox {
+        // with a margin as great circles are shorter than parallels
+        let lat_delta = 1.1 * (dist / EARTH_RADIUS).to_degrees();
+        let lat_min = self.b_box.lat_min - lat_delta;
+        let lat_max = self.b_box.lat_max + lat_delta;
+        // degrees of longitude are shortest close to the poles
+        let max_lat = lat_min.abs().max(lat_max.abs()).min(90.0);
+        let long_delta = lat_delta / max_lat.to_radians().cos();
+        BoundingBox {
+            lat_min,
+            lat_max,
+            long_min: self.b_box.long_min - long_delta,
+            long_max: self.b_box.long_max + long_delta,
+        }
+    }
+
+    /// Cuts the way through `way` at the border of the area and
+    /// returns the parts inside of it. Points where the way crosses the
+    /// border become new nodes in `nodes` with ids counting up from
+    /// `next_id`.
+    pub fn clip_way(
+        &self,
+        way: &[OsmNodeId],
+        nodes: &mut HashMap<OsmNodeId, NodeInfo>,
+        next_id: &mut OsmNodeId,
+    ) -> Vec<Vec<OsmNodeId>> {
+        let mut parts = Vec::new();
+        let mut current: Vec<OsmNodeId> = Vec::new();
+        for pair in way.windows(2) {
+            let (a, b) = match (nodes.get(&pair[0]), nodes.get(&pair[1])) {
+                (Some(a), Some(b)) => ((a.lat, a.long), (b.lat, b.long)),
+                _ => {
+                    parts.push(current);
+                    current = Vec::new();
+                    continue;
+                }
+            };
+            let mut cuts = self.crossings(&a, &b);
+            cuts.push(1.0);
+            let mut start = (0.0, pair[0]);
+            for cut in cuts {
+                let end = if cut < 1.0 {
+                    let id = *next_id;
+                    *next_id += 1;
+                    let lat = a.0 + cut * (b.0 - a.0);
+                    let long = a.1 + cut * (b.1 - a.1);
+                    nodes.insert(id, NodeInfo::new(id, lat, long, 0));
+                    (cut, id)
+                } else {
+                    (1.0, pair[1])
+                };
+                let middle = (start.0 + end.0) / 2.0;
+                let middle = (a.0 + middle * (b.0 - a.0), a.1 + middle * (b.1 - a.1));
+                if self.contains(&middle) {
+                    if current.is_empty() {
+                        current.push(start.1);
+                    }
+                    current.push(end.1);
+                } else if !current.is_empty() {
+                    parts.push(current);
+                    current = Vec::new();
+                }
+                start = end;
+            }
+        }
+        parts.push(current);
+        parts.retain(|p| p.len() > 1);
+        parts
+    }
+
+    /// Fractions of the segment from `a` to `b` at which it crosses
+    /// the border of the area in ascending order
+    fn crossings(&self, a: &(f64, f64), b: &(f64, f64)) -> Vec<f64> {
+        let mut result: Vec<f64> = self.rings()
+            .flat_map(|r| r.windows(2))
+            .filter_map(|pair| intersect(a, b, &pair[0], &pair[1]))
+            .collect();
+        result.sort_by(|x, y| x.partial_cmp(y).unwrap());
+        result.dedup();
+        result
+    }
+
+    fn rings<'a>(&'a self) -> Box<Iterator<Item = &'a Vec<(f64, f64)>> + 'a> {
+        Box::new(self.outer.iter().chain(self.holes.iter()))
+    }
+}
+
+fn close(mut ring: Vec<(f64, f64)>) -> Vec<(f64, f64)> {
+    if ring.first() != ring.last() {
+        let first = ring[0];
+        ring.push(first);
+    }
+    ring
+}
+
+/// Even odd rule for the closed `ring`
+fn in_ring<C: Coord>(ring: &[(f64, f64)], c: &C) -> bool {
+    let (lat, lon) = (c.lat(), c.lon());
+    let mut inside = false;
+    for pair in ring.windows(2) {
+        let (a, b) = (pair[0], pair[1]);
+        if (a.0 > lat) != (b.0 > lat) && lon < (b.1 - a.1) * (lat - a.0) / (b.0 - a.0) + a.1 {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+/// Fraction of the segment from `a` to `b` at which it strictly
+/// crosses the segment from `c` to `d`
+fn intersect(a: &(f64, f64), b: &(f64, f64), c: &(f64, f64), d: &(f64, f64)) -> Option<f64> {
+    let r = (b.0 - a.0, b.1 - a.1);
+    let s = (d.0 - c.0, d.1 - c.1);
+    let denominator = r.0 * s.1 - r.1 * s.0;
+    if denominator == 0.0 {
+        return None;
+    }
+    let t = ((c.0 - a.0) * s.1 - (c.1 - a.1) * s.0) / denominator;
+    let u = ((c.0 - a.0) * r.1 - (c.1 - a.1) * r.0) / denominator;
+    if t > 0.0 && t < 1.0 && u >= 0.0 && u <= 1.0 {
+        Some(t)
+    } else {
+        None
+    }
+}
+
+#[test]
+fn clipping_ways_at_the_border() {
+    let poly = "test\n\
+                1\n\
+                \x20 13.0 52.0\n\
+                \x20 13.1 52.0\n\
+                \x20 13.1 52.1\n\
+                \x20 13.0 52.1\n\
+                END\n\
+                END\n";
+    let area = ClipArea::from_poly(poly.as_bytes()).unwrap();
+    assert!(area.contains(&(52.05, 13.05)));
+    assert!(!area.contains(&(52.05, 13.15)));
+
+    let mut nodes = HashMap::new();
+    nodes.insert(1, NodeInfo::new(1, 52.05, 12.95, 0));
+    nodes.insert(2, NodeInfo::new(2, 52.05, 13.05, 0));
+    nodes.insert(3, NodeInfo::new(3, 52.05, 13.15, 0));
+    nodes.insert(4, NodeInfo::new(4, 52.05, 13.2, 0));
+    let mut next_id = 100;
+    let parts = area.clip_way(&[1, 2, 3, 4], &mut nodes, &mut next_id);
+    assert_eq!(vec![vec![100, 2, 101]], parts);
+    assert!((nodes[&100].long - 13.0).abs() < 1e-9);
+    assert!((nodes[&101].long - 13.1).abs() < 1e-9);
+    assert_eq!(102, next_id);
+}
+
+#[test]
+fn retaining_towers_near_the_area() {
+    let area = ClipArea::from_bbox(52.0, 13.0, 52.1, 13.1);
+    let mut towers = vec![
+        Tower::test(1, 52.05, 13.05, 100.0),
+        Tower::test(1, 52.05, 13.11, 1000.0),
+        Tower::test(1, 52.05, 13.2, 100.0),
+        Tower::test(1, 53.0, 13.05, 100.0),
+    ];
+    assert_eq!(2, area.retain_towers(&mut towers));
+    assert_eq!(vec![13.05, 13.11], towers.iter().map(|t| t.lon).collect::<Vec<_>>());
+}
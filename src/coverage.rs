@@ -0,0 +1,671 @@
+use graph::NodeInfo;
+use provider::{Provider, ProviderRegistry, ProviderSelection};
+use towers::{sample_fractions, TechClass};
+use flat::Store;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::u64;
+
+use heapsize::HeapSizeOf;
+use rayon::prelude::*;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::{self, SeqAccess, Visitor};
+use serde::ser::SerializeTuple;
+
+/// Marks serialized coverage data that starts with a format version.
+/// Data written before versioning started with the number of layers
+/// which never reaches this value.
+const VERSION_MARKER: u64 = u64::MAX;
+/// Current version of the serialized format
+const FORMAT_VERSION: u32 = 2;
+/// Quantized value of a fully covered edge
+const FULL: f64 = 255.0;
+
+/// Identifies one layer of coverage values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, HeapSizeOf, Serialize, Deserialize)]
+pub struct LayerKey {
+    pub provider: ProviderSelection,
+    pub tech: TechClass,
+}
+
+/// Coverage of a single edge for every layer of `Coverage`. Values
+/// are stored per technology class in the order of `TechClass::all`.
+pub struct EdgeCoverage {
+    /// Covered share per provider in the order of the registry
+    pub providers: Vec<[f64; 3]>,
+    /// Share covered by at least `k` providers at once at index `k - 1`
+    pub at_least: Vec<[f64; 3]>,
+}
+
+impl EdgeCoverage {
+    pub fn new(provider_count: usize) -> EdgeCoverage {
+        EdgeCoverage {
+            providers: vec![[0.0; 3]; provider_count],
+            at_least: vec![[0.0; 3]; provider_count],
+        }
+    }
+
+    /// Value of the layer `key`. `key` has to be normalized.
+    fn value(&self, providers: &ProviderRegistry, key: &LayerKey) -> f64 {
+        let per_class = match key.provider {
+            ProviderSelection::Single(p) => providers.index_of(&p).map(|i| &self.providers[i]),
+            ProviderSelection::AtLeast(k) if k > 0 => self.at_least.get(k - 1),
+            _ => None,
+        };
+        per_class.map_or(0.0, |values| values[key.tech.index()])
+    }
+
+    /// Overwrites the coverage of all technologies of the providers in
+    /// `values`, given by their index, and adjusts the other values.
+    /// Newer technologies keep their coverage unless it exceeds the new
+    /// one. The shares covered by several providers are kept as far as
+    /// the new values allow.
+    pub fn override_any(&mut self, values: &[(usize, f64)]) {
+        let mut changed = [false; 3];
+        for &(i, value) in values {
+            let per_class = &mut self.providers[i];
+            if per_class[0] != value {
+                per_class[0] = value;
+                changed[0] = true;
+            }
+            for c in 1..3 {
+                if per_class[c] > per_class[c - 1] {
+                    per_class[c] = per_class[c - 1];
+                    changed[c] = true;
+                }
+            }
+        }
+        for c in 0..3 {
+            if changed[c] {
+                self.bound_at_least(c);
+            }
+        }
+    }
+
+    /// Moves the shares covered by at least `k` providers with
+    /// technology class `c` into the bounds the shares of the single
+    /// providers allow, without assuming anything about how their
+    /// covered sections overlap
+    fn bound_at_least(&mut self, c: usize) {
+        let mut shares: Vec<f64> = self.providers.iter().map(|p| p[c]).collect();
+        shares.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let n = shares.len();
+        let sum: f64 = shares.iter().sum();
+        let mut previous: f64 = 1.0;
+        for k in 1..n + 1 {
+            // a point covered by `k` providers is covered by one of any
+            // `n - k + 1` of them, and the shares sum up to the mean
+            // number of providers covering a point
+            let upper = shares[..n - k + 1]
+                .iter()
+                .sum::<f64>()
+                .min(sum / k as f64)
+                .min(previous);
+            let lower = if k == 1 {
+                shares[n - 1]
+            } else {
+                (sum - (k - 1) as f64) / (n - k + 1) as f64
+            };
+            let value = &mut self.at_least[k - 1][c];
+            *value = value.max(lower).min(upper);
+            previous = *value;
+        }
+    }
+}
+
+/// Drive test measurement of a single provider on a subsegment that is
+/// blended into the modeled coverage
+#[derive(Debug, Clone, Copy, HeapSizeOf, Serialize, Deserialize)]
+pub struct Measurement {
+    pub provider: Provider,
+    pub segment: usize,
+    /// Share of samples with signal
+    pub measured: f64,
+    /// Share of the measurement in the blended coverage
+    pub confidence: f64,
+}
+
+impl Measurement {
+    /// The modeled coverage `modeled` with this measurement blended in
+    pub fn blend(&self, modeled: f64) -> f64 {
+        modeled + self.confidence * (self.measured - modeled)
+    }
+}
+
+/// Anything that can tell how well an edge is covered by each provider
+pub trait CoverageSource: Sync {
+    /// Which providers cover the points at `fractions` of the edge
+    /// between `s` and `t`. `found[i][p][c]` tells if provider `p`
+    /// covers the `i`th point with technology class `c`.
+    fn covered_points(
+        &self,
+        s: &NodeInfo,
+        t: &NodeInfo,
+        fractions: &[f64],
+        providers: &ProviderRegistry,
+    ) -> Vec<Vec<[bool; 3]>>;
+
+    /// Coverage of the edge between `s` and `t`, sampled along the
+    /// edge unless a source knows better
+    fn edge_coverage(
+        &self,
+        s: &NodeInfo,
+        t: &NodeInfo,
+        providers: &ProviderRegistry,
+    ) -> EdgeCoverage {
+        let mut sampled = SampledCoverage::new(providers.len());
+        for found in self.covered_points(s, t, &sample_fractions(s, t), providers) {
+            sampled.add(&found);
+        }
+        sampled.finish()
+    }
+}
+
+impl<'a, S: CoverageSource + ?Sized> CoverageSource for &'a S {
+    fn covered_points(
+        &self,
+        s: &NodeInfo,
+        t: &NodeInfo,
+        fractions: &[f64],
+        providers: &ProviderRegistry,
+    ) -> Vec<Vec<[bool; 3]>> {
+        (**self).covered_points(s, t, fractions, providers)
+    }
+
+    fn edge_coverage(
+        &self,
+        s: &NodeInfo,
+        t: &NodeInfo,
+        providers: &ProviderRegistry,
+    ) -> EdgeCoverage {
+        (**self).edge_coverage(s, t, providers)
+    }
+}
+
+/// Combines two sources. Both are sampled at the same points along an
+/// edge and a point is covered by a provider if one of the sources
+/// says so.
+pub struct Combined<A, B>(pub A, pub B);
+
+impl<A: CoverageSource, B: CoverageSource> CoverageSource for Combined<A, B> {
+    fn covered_points(
+        &self,
+        s: &NodeInfo,
+        t: &NodeInfo,
+        fractions: &[f64],
+        providers: &ProviderRegistry,
+    ) -> Vec<Vec<[bool; 3]>> {
+        let mut found = self.0.covered_points(s, t, fractions, providers);
+        let other = self.1.covered_points(s, t, fractions, providers);
+        for (a, b) in found.iter_mut().flat_map(|p| p.iter_mut()).zip(
+            other.iter().flat_map(|p| p.iter()),
+        )
+        {
+            for c in 0..3 {
+                a[c] |= b[c];
+            }
+        }
+        found
+    }
+}
+
+/// Accumulates the coverage of points sampled along an edge
+pub struct SampledCoverage {
+    covered: Vec<[usize; 3]>,
+    at_least: Vec<[usize; 3]>,
+    samples: usize,
+}
+
+impl SampledCoverage {
+    pub fn new(provider_count: usize) -> SampledCoverage {
+        SampledCoverage {
+            covered: vec![[0; 3]; provider_count],
+            at_least: vec![[0; 3]; provider_count],
+            samples: 0,
+        }
+    }
+
+    /// Adds a sample point. `found[p][c]` tells if provider `p` covers
+    /// the point with technology class `c`.
+    pub fn add(&mut self, found: &[[bool; 3]]) {
+        self.samples += 1;
+        for c in 0..3 {
+            let mut providers_found = 0;
+            for (count, found) in self.covered.iter_mut().zip(found) {
+                if found[c] {
+                    count[c] += 1;
+                    providers_found += 1;
+                }
+            }
+            for count in &mut self.at_least[..providers_found] {
+                count[c] += 1;
+            }
+        }
+    }
+
+    /// Covered share of the edge as fraction of covered samples
+    pub fn finish(self) -> EdgeCoverage {
+        let samples = self.samples.max(1) as f64;
+        let fraction = |count: &[usize; 3]| {
+            let mut values = [0.0; 3];
+            for c in 0..3 {
+                values[c] = count[c] as f64 / samples;
+            }
+            values
+        };
+        EdgeCoverage {
+            providers: self.covered.iter().map(&fraction).collect(),
+            at_least: self.at_least.iter().map(&fraction).collect(),
+        }
+    }
+}
+
+/// Holds coverage values for all edge provider combination.
+///
+/// Besides the values of single providers the share of every edge
+/// covered by at least `k` providers at once is stored for all `k`.
+/// Values are quantized to a byte and stored edge by edge, so all
+/// layers of an edge lie next to each other.
+pub struct Coverage {
+    layers: Vec<LayerKey>,
+    provider_count: usize,
+    values: Store<u8>,
+}
+
+/// Read only view on the values of a single layer of `Coverage`
+#[derive(Clone, Copy)]
+pub struct CoverageLayer<'a> {
+    values: &'a [u8],
+    stride: usize,
+    offset: usize,
+}
+
+impl<'a> CoverageLayer<'a> {
+    /// Covered share of edge `n`
+    #[inline]
+    pub fn get(&self, n: usize) -> f64 {
+        self.values[n * self.stride + self.offset] as f64 / FULL
+    }
+}
+
+impl Coverage {
+    /// Calculates the coverage of `edge_count` edges in parallel.
+    /// `edge_coverage` is called with the index of every edge.
+    pub fn calculate<F>(
+        providers: &ProviderRegistry,
+        edge_count: usize,
+        edge_coverage: F,
+    ) -> Coverage
+    where
+        F: Fn(usize) -> EdgeCoverage + Sync,
+    {
+        let layers = Coverage::layers_for(providers);
+        let stride = layers.len();
+        let mut values = vec![0; edge_count * stride];
+        if stride > 0 {
+            values.par_chunks_mut(stride).enumerate().for_each(
+                |(n, chunk)| {
+                    Coverage::quantize_into(chunk, &layers, providers, &edge_coverage(n))
+                },
+            );
+        }
+        Coverage {
+            layers,
+            provider_count: providers.len(),
+            values: values.into(),
+        }
+    }
+
+    /// Whether there are values for exactly `count` edges
+    pub fn covers(&self, count: usize) -> bool {
+        self.values.len() == count * self.layers.len()
+    }
+
+    /// Overwrites the values of edge `n` for all layers
+    pub fn set_edge(&mut self, providers: &ProviderRegistry, n: usize, edge: &EdgeCoverage) {
+        let stride = self.layers.len();
+        let chunk = &mut self.values[n * stride..(n + 1) * stride];
+        Coverage::quantize_into(chunk, &self.layers, providers, edge);
+    }
+
+    /// Values of edge `n` for all layers
+    pub fn edge(&self, providers: &ProviderRegistry, n: usize) -> EdgeCoverage {
+        let mut edge = EdgeCoverage::new(self.provider_count);
+        let stride = self.layers.len();
+        for (offset, key) in self.layers.iter().enumerate() {
+            let per_class = match key.provider {
+                ProviderSelection::Single(p) => {
+                    providers.index_of(&p).map(|i| &mut edge.providers[i])
+                }
+                ProviderSelection::AtLeast(k) if k > 0 => edge.at_least.get_mut(k - 1),
+                _ => None,
+            };
+            if let Some(values) = per_class {
+                values[key.tech.index()] = self.values[n * stride + offset] as f64 / FULL;
+            }
+        }
+        edge
+    }
+
+    /// Combines groups of edges, like the subsegments of longer edges,
+    /// into `count` new edges. `parts` returns the edges of a group
+    /// with their weights, each value becomes the weighted mean.
+    pub fn mean<F>(&self, count: usize, parts: F) -> Coverage
+    where
+        F: Fn(usize) -> Vec<(usize, f64)> + Sync,
+    {
+        let stride = self.layers.len();
+        let mut values = vec![0; count * stride];
+        if stride > 0 {
+            values.par_chunks_mut(stride).enumerate().for_each(
+                |(n, chunk)| self.mean_into(chunk, &parts(n)),
+            );
+        }
+        Coverage {
+            layers: self.layers.clone(),
+            provider_count: self.provider_count,
+            values: values.into(),
+        }
+    }
+
+    /// Overwrites the values of edge `n` with the weighted mean of the
+    /// edges in `parts` of `from`, which has to have the same layers
+    pub fn set_mean(&mut self, n: usize, from: &Coverage, parts: &[(usize, f64)]) {
+        let stride = self.layers.len();
+        from.mean_into(&mut self.values[n * stride..(n + 1) * stride], parts);
+    }
+
+    fn mean_into(&self, chunk: &mut [u8], parts: &[(usize, f64)]) {
+        let stride = self.layers.len();
+        let total: f64 = parts.iter().map(|&(_, w)| w).sum();
+        for (offset, value) in chunk.iter_mut().enumerate() {
+            let sum: f64 = parts
+                .iter()
+                .map(|&(e, w)| self.values[e * stride + offset] as f64 * w)
+                .sum();
+            *value = if total > 0.0 {
+                (sum / total).round() as u8
+            } else {
+                0
+            };
+        }
+    }
+
+    /// Coverage values of all edges for the providers selected by `p`
+    /// counting only towers of technology class `tech`. Returns `None`
+    /// if no provider is given or there are no values for the selection.
+    pub fn get_all(&self, p: Option<ProviderSelection>, tech: TechClass) -> Option<CoverageLayer> {
+        let key = LayerKey {
+            provider: p?.normalize(self.provider_count),
+            tech,
+        };
+        let offset = self.layers.iter().position(|l| *l == key)?;
+        Some(CoverageLayer {
+            values: &self.values[..],
+            stride: self.layers.len(),
+            offset,
+        })
+    }
+
+    fn layers_for(providers: &ProviderRegistry) -> Vec<LayerKey> {
+        providers
+            .providers()
+            .iter()
+            .map(|p| ProviderSelection::Single(*p))
+            .chain((1..providers.len() + 1).map(ProviderSelection::AtLeast))
+            .flat_map(|provider| {
+                TechClass::all().iter().map(move |&tech| LayerKey { provider, tech })
+            })
+            .collect()
+    }
+
+    fn quantize_into(
+        chunk: &mut [u8],
+        layers: &[LayerKey],
+        providers: &ProviderRegistry,
+        edge: &EdgeCoverage,
+    ) {
+        for (value, key) in chunk.iter_mut().zip(layers) {
+            *value = quantize(edge.value(providers, key));
+        }
+    }
+}
+
+fn quantize(value: f64) -> u8 {
+    assert!(
+        0.0 <= value && 1.0 >= value,
+        format!("Value {} out of range [0.0,1.0]", value)
+    );
+    (value * FULL).round() as u8
+}
+
+impl HeapSizeOf for Coverage {
+    fn heap_size_of_children(&self) -> usize {
+        self.layers.heap_size_of_children() + self.values.heap_size_of_children()
+    }
+}
+
+impl Serialize for Coverage {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut tuple = serializer.serialize_tuple(5)?;
+        tuple.serialize_element(&VERSION_MARKER)?;
+        tuple.serialize_element(&FORMAT_VERSION)?;
+        tuple.serialize_element(&self.layers)?;
+        tuple.serialize_element(&self.provider_count)?;
+        tuple.serialize_element(&self.values)?;
+        tuple.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Coverage {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_tuple(::std::usize::MAX, CoverageVisitor)
+    }
+}
+
+struct CoverageVisitor;
+
+impl<'de> Visitor<'de> for CoverageVisitor {
+    type Value = Coverage;
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("versioned or unversioned coverage data")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Coverage, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let first: u64 = next(&mut seq)?;
+        if first != VERSION_MARKER {
+            return visit_unversioned(first as usize, seq);
+        }
+        let version: u32 = next(&mut seq)?;
+        match version {
+            FORMAT_VERSION => {
+                Ok(Coverage {
+                    layers: next(&mut seq)?,
+                    provider_count: next(&mut seq)?,
+                    values: next(&mut seq)?,
+                })
+            }
+            v => Err(de::Error::custom(
+                format!("unsupported coverage format version {}", v),
+            )),
+        }
+    }
+}
+
+/// Reads coverage data in the format before quantization and
+/// versioning, a map from layer to `f64` values followed by the number
+/// of edges and providers. `layer_count` is the already read length of
+/// the map.
+fn visit_unversioned<'de, A>(layer_count: usize, mut seq: A) -> Result<Coverage, A::Error>
+where
+    A: SeqAccess<'de>,
+{
+    let mut old: HashMap<LayerKey, Vec<f64>> = HashMap::new();
+    for _ in 0..layer_count {
+        let (provider, tech): (ProviderSelection, TechClass) = next(&mut seq)?;
+        old.insert(LayerKey { provider, tech }, next(&mut seq)?);
+    }
+    let size: usize = next(&mut seq)?;
+    let provider_count: usize = next(&mut seq)?;
+
+    let layers: Vec<LayerKey> = old.keys().cloned().collect();
+    let mut values = vec![0; size * layers.len()];
+    for (offset, key) in layers.iter().enumerate() {
+        let layer = &old[key];
+        if layer.len() != size {
+            return Err(de::Error::custom(
+                format!("expected {} coverage values but found {}", size, layer.len()),
+            ));
+        }
+        for (n, &value) in layer.iter().enumerate() {
+            if !(0.0 <= value && value <= 1.0) {
+                return Err(de::Error::custom(format!("invalid coverage value {}", value)));
+            }
+            values[n * layers.len() + offset] = quantize(value);
+        }
+    }
+    Ok(Coverage {
+        layers,
+        provider_count,
+        values: values.into(),
+    })
+}
+
+fn next<'de, A, T>(seq: &mut A) -> Result<T, A::Error>
+where
+    A: SeqAccess<'de>,
+    T: Deserialize<'de>,
+{
+    seq.next_element()?.ok_or_else(
+        || de::Error::custom("coverage data ended too early"),
+    )
+}
+
+#[test]
+fn quantized_values_are_close() {
+    for &v in &[0.0, 0.1, 0.5, 0.999, 1.0] {
+        assert!((quantize(v) as f64 / FULL - v).abs() <= 0.5 / FULL);
+    }
+}
+
+#[test]
+fn bincode_roundtrip() {
+    use bincode;
+    use provider::Provider;
+
+    let layers = vec![
+        LayerKey {
+            provider: ProviderSelection::Single(Provider::new(262, 1)),
+            tech: TechClass::Any,
+        },
+        LayerKey {
+            provider: ProviderSelection::AtLeast(1),
+            tech: TechClass::Lte,
+        },
+    ];
+    let coverage = Coverage {
+        layers,
+        provider_count: 1,
+        values: vec![0, 255, 128, 3].into(),
+    };
+    let bytes = bincode::serialize(&coverage, bincode::Infinite).unwrap();
+    let read: Coverage = bincode::deserialize(&bytes).unwrap();
+    assert_eq!(coverage.layers, read.layers);
+    assert_eq!(coverage.values, read.values);
+    let layer = read.get_all(Some(ProviderSelection::Any), TechClass::Lte)
+        .unwrap();
+    assert_eq!(1.0, layer.get(0));
+    assert_eq!(3.0 / FULL, layer.get(1));
+
+    // data written before versioning is converted
+    #[derive(Serialize)]
+    struct Unversioned {
+        layers: HashMap<(ProviderSelection, TechClass), Vec<f64>>,
+        size: usize,
+        provider_count: usize,
+    }
+    let mut layers = HashMap::new();
+    layers.insert((ProviderSelection::AtLeast(1), TechClass::Lte), vec![1.0, 0.2]);
+    let old = Unversioned {
+        layers,
+        size: 2,
+        provider_count: 1,
+    };
+    let bytes = bincode::serialize(&old, bincode::Infinite).unwrap();
+    let read: Coverage = bincode::deserialize(&bytes).unwrap();
+    let layer = read.get_all(Some(ProviderSelection::Any), TechClass::Lte)
+        .unwrap();
+    assert_eq!(1.0, layer.get(0));
+    assert!((layer.get(1) - 0.2).abs() <= 0.5 / FULL);
+
+    let mut newer = bincode::serialize(&coverage, bincode::Infinite).unwrap();
+    newer[8] = FORMAT_VERSION as u8 + 1;
+    assert!(bincode::deserialize::<Coverage>(&newer).is_err());
+}
+
+#[test]
+fn weighted_mean_of_segments() {
+    use provider::Provider;
+
+    let segments = Coverage {
+        layers: vec![
+            LayerKey {
+                provider: ProviderSelection::Single(Provider::new(262, 1)),
+                tech: TechClass::Any,
+            },
+        ],
+        provider_count: 1,
+        values: vec![255, 0, 0, 100].into(),
+    };
+    let edges = segments.mean(2, |e| if e == 0 {
+        vec![(0, 3.0), (1, 1.0)]
+    } else {
+        vec![(2, 1.0), (3, 1.0)]
+    });
+    let layer = edges
+        .get_all(
+            Some(ProviderSelection::Single(Provider::new(262, 1))),
+            TechClass::Any,
+        )
+        .unwrap();
+    assert_eq!(191.0 / FULL, layer.get(0));
+    assert_eq!(50.0 / FULL, layer.get(1));
+}
+
+#[test]
+fn overriding_coverage_derives_the_other_values() {
+    let mut edge = EdgeCoverage {
+        providers: vec![[0.8, 0.6, 0.2], [0.5, 0.5, 0.5]],
+        at_least: vec![[0.9, 0.8, 0.6], [0.4, 0.3, 0.1]],
+    };
+    // nothing changes, so the overlap is kept
+    edge.override_any(&[(1, 0.5)]);
+    assert_eq!([0.9, 0.8, 0.6], edge.at_least[0]);
+    assert_eq!([0.4, 0.3, 0.1], edge.at_least[1]);
+
+    edge.override_any(&[(0, 0.2)]);
+    assert_eq!([0.2, 0.2, 0.2], edge.providers[0]);
+    assert_eq!([0.5, 0.5, 0.5], edge.providers[1]);
+    let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+    // at most the sum of both and the share of the smaller one
+    assert!(close(0.7, edge.at_least[0][0]) && close(0.2, edge.at_least[1][0]));
+    assert!(close(0.7, edge.at_least[0][1]) && close(0.2, edge.at_least[1][1]));
+    // LTE was not changed
+    assert_eq!(0.6, edge.at_least[0][2]);
+    assert_eq!(0.1, edge.at_least[1][2]);
+
+    // at least as much as the larger one
+    edge.override_any(&[(0, 1.0)]);
+    assert_eq!(1.0, edge.at_least[0][0]);
+    assert!(close(0.5, edge.at_least[1][0]));
+}
@@ -0,0 +1,343 @@
+use error::Error;
+use preprocessed::{checksum, write_preprocessed};
+use SharedState;
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use zstd;
+
+use std::cmp;
+use std::io::{self, Cursor, Read, Write};
+use std::mem;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
+
+/// Size of the chunks passed from the writing thread to the response
+const CHUNK_SIZE: usize = 1 << 20;
+/// Chunks that may wait for the client before writing blocks
+const CHUNKS_IN_FLIGHT: usize = 4;
+
+/// Content encodings offered for graph downloads
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Zstd,
+}
+
+impl Encoding {
+    /// Picks the best encoding accepted by an `Accept-Encoding` header,
+    /// zstd before gzip
+    pub fn negotiate(accept: Option<&str>) -> Encoding {
+        let accept = match accept {
+            Some(accept) => accept,
+            None => return Encoding::Identity,
+        };
+        let accepted = |name: &str| {
+            accept.split(',').any(|item| {
+                let mut parts = item.split(';').map(str::trim);
+                let coding = parts.next().unwrap_or("");
+                let quality = parts
+                    .find(|p| p.starts_with("q="))
+                    .and_then(|p| p[2..].parse::<f64>().ok())
+                    .unwrap_or(1.0);
+                (coding == name || coding == "*") && quality > 0.0
+            })
+        };
+        if accepted("zstd") {
+            Encoding::Zstd
+        } else if accepted("gzip") {
+            Encoding::Gzip
+        } else {
+            Encoding::Identity
+        }
+    }
+
+    /// Value of the `Content-Encoding` header
+    pub fn header(&self) -> Option<&'static str> {
+        match *self {
+            Encoding::Identity => None,
+            Encoding::Gzip => Some("gzip"),
+            Encoding::Zstd => Some("zstd"),
+        }
+    }
+}
+
+/// Requested bytes of a `Range` header. Only single byte ranges are
+/// supported.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ByteRange {
+    /// First and optional last byte
+    From(u64, Option<u64>),
+    /// Number of bytes at the end
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// Returns `None` for anything but a single byte range
+    pub fn parse(header: &str) -> Option<ByteRange> {
+        let spec = header.trim();
+        if !spec.starts_with("bytes=") || spec.contains(',') {
+            return None;
+        }
+        let mut bounds = spec[6..].splitn(2, '-').map(str::trim);
+        let (start, end) = (bounds.next()?, bounds.next()?);
+        if start.is_empty() {
+            return end.parse().ok().map(ByteRange::Suffix);
+        }
+        let start = start.parse().ok()?;
+        if end.is_empty() {
+            return Some(ByteRange::From(start, None));
+        }
+        match end.parse() {
+            Ok(end) if end >= start => Some(ByteRange::From(start, Some(end))),
+            _ => None,
+        }
+    }
+
+    /// First and last byte of the range in a file of `len` bytes or
+    /// `None` if the range can not be satisfied
+    pub fn resolve(&self, len: u64) -> Option<(u64, u64)> {
+        match *self {
+            ByteRange::From(start, _) if start >= len => None,
+            ByteRange::From(start, end) => Some((start, end.map_or(len - 1, |e| e.min(len - 1)))),
+            ByteRange::Suffix(0) => None,
+            ByteRange::Suffix(n) => Some((len - cmp::min(n, len), len - 1)),
+        }
+    }
+}
+
+/// Hands chunks of written data to a `ChunkReader` in another thread
+struct ChunkWriter {
+    chunks: SyncSender<Vec<u8>>,
+    buffer: Vec<u8>,
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = mem::replace(&mut self.buffer, Vec::with_capacity(CHUNK_SIZE));
+        self.chunks.send(chunk).map_err(|_| {
+            io::Error::new(io::ErrorKind::BrokenPipe, "download was aborted")
+        })
+    }
+}
+
+/// Sends what is left in the buffer, also when writing stopped early
+/// because of an error
+impl Drop for ChunkWriter {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Reads the chunks of a `ChunkWriter` until it is dropped
+pub struct ChunkReader {
+    chunks: Receiver<Vec<u8>>,
+    current: Cursor<Vec<u8>>,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            match self.chunks.recv() {
+                Ok(chunk) => self.current = Cursor::new(chunk),
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
+/// A preprocessed graph that is sent to a client
+pub struct GraphStream {
+    pub body: Box<Read + Send>,
+    pub encoding: Encoding,
+    /// Checksum of the whole file, changes with every change of the
+    /// graph
+    pub etag: String,
+    /// First and last byte and the size of the whole file if only a
+    /// range is sent
+    pub range: Option<(u64, u64, u64)>,
+}
+
+pub enum Download {
+    Stream(GraphStream),
+    /// The requested range lies outside of the file of this size
+    Unsatisfiable(u64),
+}
+
+/// Writes the preprocessed graph of `state` into memory and sends it
+/// from there, so the read lock is only held while writing and not
+/// for the whole download. `range` is only honoured if `if_range` is
+/// missing or matches the ETag, otherwise the whole file is sent, so
+/// a resumed download never mixes two different graphs. Ranges are
+/// always sent without compression as the size of the compressed
+/// file is unknown.
+pub fn stream_graph(
+    state: &SharedState,
+    encoding: Encoding,
+    range: Option<ByteRange>,
+    if_range: Option<&str>,
+) -> Result<Download, Error> {
+    let mut bytes = Vec::new();
+    write_preprocessed(&mut bytes, &state.read().unwrap())?;
+    let etag = format!("\"{:016x}\"", checksum(&bytes));
+    let len = bytes.len() as u64;
+    let range = match range {
+        Some(_) if if_range.map_or(false, |tag| tag.trim() != etag) => None,
+        Some(range) => {
+            match range.resolve(len) {
+                Some((first, last)) => Some((first, last, len)),
+                None => return Ok(Download::Unsatisfiable(len)),
+            }
+        }
+        None => None,
+    };
+    let (body, encoding): (Box<Read + Send>, Encoding) = match range {
+        Some((first, last, _)) => {
+            bytes.truncate(last as usize + 1);
+            bytes.drain(..first as usize);
+            (Box::new(Cursor::new(bytes)), Encoding::Identity)
+        }
+        None if encoding == Encoding::Identity => (Box::new(Cursor::new(bytes)), encoding),
+        None => {
+            let (sender, receiver) = sync_channel(CHUNKS_IN_FLIGHT);
+            thread::spawn(move || {
+                let writer = ChunkWriter {
+                    chunks: sender,
+                    buffer: Vec::with_capacity(CHUNK_SIZE),
+                };
+                // errors mean that the client went away, broken
+                // downloads fail the checksum on loading
+                let _ = write_encoded(&bytes, writer, encoding);
+            });
+            let reader = ChunkReader {
+                chunks: receiver,
+                current: Cursor::new(Vec::new()),
+            };
+            (Box::new(reader), encoding)
+        }
+    };
+    Ok(Download::Stream(GraphStream {
+        body,
+        encoding,
+        etag,
+        range,
+    }))
+}
+
+fn write_encoded(bytes: &[u8], writer: ChunkWriter, encoding: Encoding) -> io::Result<()> {
+    match encoding {
+        Encoding::Identity => {
+            let mut writer = writer;
+            writer.write_all(bytes)?;
+            writer.flush()
+        }
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(writer, Compression::default());
+            encoder.write_all(bytes)?;
+            encoder.finish()?.flush()
+        }
+        Encoding::Zstd => {
+            let mut encoder = zstd::stream::write::Encoder::new(writer, 0)?;
+            encoder.write_all(bytes)?;
+            encoder.finish()?.flush()
+        }
+    }
+}
+
+#[test]
+fn negotiating_encodings() {
+    assert_eq!(Encoding::Identity, Encoding::negotiate(None));
+    assert_eq!(Encoding::Gzip, Encoding::negotiate(Some("gzip, deflate")));
+    assert_eq!(Encoding::Zstd, Encoding::negotiate(Some("gzip, zstd")));
+    assert_eq!(Encoding::Gzip, Encoding::negotiate(Some("zstd;q=0, gzip;q=0.5")));
+    assert_eq!(Encoding::Identity, Encoding::negotiate(Some("br")));
+}
+
+#[test]
+fn resolving_ranges() {
+    assert_eq!(Some((10, 99)), ByteRange::parse("bytes=10-").unwrap().resolve(100));
+    assert_eq!(Some((10, 19)), ByteRange::parse("bytes=10-19").unwrap().resolve(100));
+    assert_eq!(Some((90, 99)), ByteRange::parse("bytes=-10").unwrap().resolve(100));
+    assert_eq!(None, ByteRange::parse("bytes=100-").unwrap().resolve(100));
+    assert_eq!(None, ByteRange::parse("bytes=0-1,5-6"));
+    assert_eq!(None, ByteRange::parse("bytes=5-1"));
+}
+
+#[test]
+fn streaming_ranges_of_a_graph() {
+    use graph::{Graph, NodeInfo, EdgeInfo};
+    use preprocessed::write_preprocessed;
+    use ApplicationState;
+    use std::sync::{Arc, RwLock};
+
+    let mut towers = Vec::new();
+    let graph = Graph::new(
+        vec![
+            NodeInfo::new(1, 52.0, 13.0, 0),
+            NodeInfo::new(2, 52.001, 13.0, 0),
+        ],
+        vec![EdgeInfo::new(1, 2, 1.0, 50)],
+        &mut towers,
+        Default::default(),
+        &Default::default(),
+    ).unwrap();
+    let state = Arc::new(RwLock::new(ApplicationState {
+        graph,
+        towers,
+        coverage: Default::default(),
+        sources: Vec::new(),
+    }));
+    let mut file = Vec::new();
+    write_preprocessed(&mut file, &state.read().unwrap()).unwrap();
+
+    let read_all = |range: Option<ByteRange>, if_range: Option<&str>| {
+        match stream_graph(&state, Encoding::Gzip, range, if_range).unwrap() {
+            Download::Stream(mut stream) => {
+                let mut body = Vec::new();
+                stream.body.read_to_end(&mut body).unwrap();
+                (body, stream.encoding, stream.range, stream.etag)
+            }
+            Download::Unsatisfiable(_) => panic!("range has to be satisfiable"),
+        }
+    };
+    let (body, encoding, range, etag) = read_all(ByteRange::parse("bytes=5-20"), None);
+    assert_eq!(Encoding::Identity, encoding);
+    assert_eq!(Some((5, 20, file.len() as u64)), range);
+    assert_eq!(&file[5..21], &body[..]);
+
+    let (body, _, _, _) = read_all(ByteRange::parse("bytes=-10"), Some(&etag[..]));
+    assert_eq!(&file[file.len() - 10..], &body[..]);
+
+    // the range of another graph is not resumed
+    state.write().unwrap().graph.node_info[0].height = 10;
+    let (body, encoding, range, changed) = read_all(ByteRange::parse("bytes=-10"), Some(&etag[..]));
+    assert!(range.is_none());
+    assert!(etag != changed);
+    assert_eq!(Encoding::Gzip, encoding);
+    assert!(body.len() > 10);
+
+    let (body, encoding, _, _) = read_all(None, None);
+    assert_eq!(Encoding::Gzip, encoding);
+    let mut decoded = Vec::new();
+    ::flate2::read::GzDecoder::new(&body[..])
+        .unwrap()
+        .read_to_end(&mut decoded)
+        .unwrap();
+    assert_eq!(file, decoded);
+}
@@ -0,0 +1,300 @@
+use graph::{Graph, Movement};
+use coverage::Measurement;
+use gpx::{read_track, TrackPoint};
+use matching::match_track;
+use provider::{Provider, ProviderSelection};
+use towers::TechClass;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use csv::Reader;
+use rayon::prelude::*;
+
+/// Weakest RSRP in dBm that still counts as having signal
+const MIN_RSRP: f64 = -120.0;
+/// Number of samples on a subsegment from which on the measurement gets
+/// the full weight
+const SAMPLES_FOR_FULL_CONFIDENCE: f64 = 5.0;
+
+/// A single measurement of a drive test
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub time: Option<String>,
+    pub lat: f64,
+    pub lon: f64,
+    pub provider: Provider,
+    pub covered: bool,
+}
+
+#[derive(Deserialize)]
+struct SampleRecord {
+    #[serde(default)]
+    timestamp: Option<String>,
+    lat: f64,
+    lon: f64,
+    mcc: u16,
+    mnc: u16,
+    #[serde(default)]
+    rsrp: Option<f64>,
+    #[serde(default)]
+    has_signal: Option<bool>,
+}
+
+fn is_covered(rsrp: Option<f64>, has_signal: Option<bool>) -> Result<bool, String> {
+    match (has_signal, rsrp) {
+        (Some(has_signal), _) => Ok(has_signal),
+        (None, Some(rsrp)) => Ok(rsrp >= MIN_RSRP),
+        (None, None) => Err("neither rsrp nor has_signal given".to_string()),
+    }
+}
+
+/// Loads the samples of a drive test log. Files ending in `.gpx` are
+/// read as GPX tracks whose points carry `provider` ("mcc-mnc") and
+/// `rsrp` or `has_signal` as extensions, everything else as CSV with
+/// the columns `timestamp`, `lat`, `lon`, `mcc`, `mnc` and `rsrp` or
+/// `has_signal`.
+pub fn load_samples<P: AsRef<Path>>(p: P) -> Result<Vec<Sample>, Box<Error>> {
+    let is_gpx = p.as_ref().extension().map_or(false, |e| e == "gpx");
+    if is_gpx {
+        read_gpx_samples(BufReader::new(File::open(p)?))
+    } else {
+        let mut reader = Reader::from_path(p)?;
+        let mut samples = Vec::new();
+        for res in reader.deserialize() {
+            let record: SampleRecord = res?;
+            samples.push(Sample {
+                time: record.timestamp,
+                lat: record.lat,
+                lon: record.lon,
+                provider: Provider::new(record.mcc, record.mnc),
+                covered: is_covered(record.rsrp, record.has_signal)?,
+            });
+        }
+        Ok(samples)
+    }
+}
+
+fn read_gpx_samples<R: ::std::io::Read>(r: R) -> Result<Vec<Sample>, Box<Error>> {
+    let mut samples = Vec::new();
+    for point in read_track(r)? {
+        let provider = point.extensions.get("provider").ok_or(
+            "track point without provider",
+        )?;
+        let mut parts = provider.splitn(2, '-');
+        let provider = match (parts.next(), parts.next()) {
+            (Some(mcc), Some(mnc)) => Provider::new(mcc.parse()?, mnc.parse()?),
+            _ => return Err(format!("invalid provider {}", provider).into()),
+        };
+        let rsrp = match point.extensions.get("rsrp") {
+            Some(rsrp) => Some(rsrp.parse()?),
+            None => None,
+        };
+        let has_signal = match point.extensions.get("has_signal") {
+            Some(has_signal) => Some(has_signal.parse()?),
+            None => None,
+        };
+        samples.push(Sample {
+            covered: is_covered(rsrp, has_signal)?,
+            time: point.time,
+            lat: point.lat,
+            lon: point.lon,
+            provider,
+        });
+    }
+    Ok(samples)
+}
+
+/// Comparison of measured and modeled coverage of a single provider
+#[derive(Debug, Default)]
+pub struct ProviderComparison {
+    pub name: String,
+    pub samples: usize,
+    /// Number of measured subsegments of edges
+    pub segments: usize,
+    /// Mean modeled coverage of the measured subsegments before calibration
+    pub modeled: f64,
+    /// Mean measured coverage of the measured subsegments
+    pub measured: f64,
+    pub mean_error: f64,
+    /// Subsegments the model considers covered but measured mostly without signal
+    pub missed_dead_zones: usize,
+    /// Subsegments the model considers uncovered but measured mostly with signal
+    pub false_dead_zones: usize,
+}
+
+/// Result of calibrating the coverage with drive test samples
+#[derive(Debug, Default)]
+pub struct CalibrationReport {
+    pub samples: usize,
+    /// Samples that could not be matched onto an edge
+    pub unmatched: usize,
+    /// Samples of providers without towers in the graph
+    pub unknown_provider: usize,
+    pub providers: Vec<ProviderComparison>,
+}
+
+impl fmt::Display for CalibrationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} samples, {} not matched, {} of unknown providers",
+            self.samples,
+            self.unmatched,
+            self.unknown_provider
+        )?;
+        for p in &self.providers {
+            writeln!(
+                f,
+                "  {}: {} samples on {} segments, modeled {:.2}, measured {:.2}, \
+                 mean error {:.2}, {} missed and {} false dead zones",
+                p.name,
+                p.samples,
+                p.segments,
+                p.modeled,
+                p.measured,
+                p.mean_error,
+                p.missed_dead_zones,
+                p.false_dead_zones
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Matches the samples of every provider as a track onto the edges of
+/// `graph` and blends the measured coverage into the modeled coverage
+/// of every measured subsegment.
+/// The measurement gets the share `weight` between 0 and 1 once a
+/// subsegment has enough samples, a `weight` of 1.0 overrides the
+/// model. As the samples carry no radio technology only the coverage of
+/// single providers for `TechClass::Any` is measured, the other layers
+/// are derived from it. The measurements are kept in the graph, so
+/// `graph` must not be calibrated already.
+pub fn calibrate(graph: &mut Graph, samples: &[Sample], weight: f64) -> CalibrationReport {
+    assert!(
+        0.0 <= weight && weight <= 1.0,
+        "measurement weight {} out of range [0.0,1.0]",
+        weight
+    );
+    let mut report = CalibrationReport {
+        samples: samples.len(),
+        ..Default::default()
+    };
+    // the samples of every provider in the order they were taken
+    let mut tracks: Vec<Vec<&Sample>> = vec![Vec::new(); graph.providers.len()];
+    for sample in samples {
+        match graph.providers.index_of(&sample.provider) {
+            Some(index) => tracks[index].push(sample),
+            None => report.unknown_provider += 1,
+        }
+    }
+    let matched: Vec<Vec<Option<usize>>> = {
+        let graph: &Graph = graph;
+        tracks
+            .par_iter()
+            .map(|track| {
+                let points: Vec<TrackPoint> = track
+                    .iter()
+                    .map(|s| {
+                        TrackPoint {
+                            lat: s.lat,
+                            lon: s.lon,
+                            time: s.time.clone(),
+                            extensions: HashMap::new(),
+                        }
+                    })
+                    .collect();
+                match_track(graph, &points, &Movement::Car)
+                    .positions
+                    .into_iter()
+                    .map(|p| p.map(|p| p.segment))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    };
+
+    // covered and total samples per provider and subsegment
+    let mut counts: HashMap<(usize, usize), (usize, usize)> = HashMap::new();
+    let mut per_provider = vec![0; graph.providers.len()];
+    for (index, (track, positions)) in tracks.iter().zip(matched).enumerate() {
+        for (sample, position) in track.iter().zip(positions) {
+            let segment = match position {
+                Some(segment) => segment,
+                None => {
+                    report.unmatched += 1;
+                    continue;
+                }
+            };
+            per_provider[index] += 1;
+            let count = counts.entry((index, segment)).or_insert((0, 0));
+            if sample.covered {
+                count.0 += 1;
+            }
+            count.1 += 1;
+        }
+    }
+
+    let mut comparisons: Vec<ProviderComparison> = (0..graph.providers.len())
+        .map(|i| {
+            ProviderComparison {
+                name: graph.providers.name(i).to_string(),
+                samples: per_provider[i],
+                ..Default::default()
+            }
+        })
+        .collect();
+    let mut measurements = Vec::new();
+    for (&(index, segment), &(covered, total)) in &counts {
+        let provider = graph.providers.providers()[index];
+        let modeled = graph
+            .segment_coverage
+            .get_all(Some(ProviderSelection::Single(provider)), TechClass::Any)
+            .map_or(0.0, |layer| layer.get(segment));
+        let measured = covered as f64 / total as f64;
+        measurements.push(Measurement {
+            provider,
+            segment,
+            measured,
+            confidence: weight * (total as f64 / SAMPLES_FOR_FULL_CONFIDENCE).min(1.0),
+        });
+
+        let c = &mut comparisons[index];
+        c.segments += 1;
+        c.modeled += modeled;
+        c.measured += measured;
+        c.mean_error += (measured - modeled).abs();
+        if modeled >= 0.5 && measured < 0.5 {
+            c.missed_dead_zones += 1;
+        } else if modeled < 0.5 && measured >= 0.5 {
+            c.false_dead_zones += 1;
+        }
+    }
+    graph.add_calibration(measurements);
+
+    for c in &mut comparisons {
+        if c.segments > 0 {
+            let segments = c.segments as f64;
+            c.modeled /= segments;
+            c.measured /= segments;
+            c.mean_error /= segments;
+        }
+    }
+    report.providers = comparisons
+        .into_iter()
+        .filter(|c| c.samples > 0)
+        .collect();
+    report
+}
+
+#[test]
+fn coverage_from_signal() {
+    assert_eq!(Ok(true), is_covered(Some(-130.0), Some(true)));
+    assert_eq!(Ok(false), is_covered(Some(-130.0), None));
+    assert_eq!(Ok(true), is_covered(Some(-100.0), None));
+    assert!(is_covered(None, None).is_err());
+}
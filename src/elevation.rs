@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+use geom::{Coord, haversine_distance, EARTH_RADIUS};
+
+/// Value SRTM uses for samples without valid data
+const VOID: i16 = -32_768;
+/// Distance in meters between terrain samples on a line of sight,
+/// roughly the resolution of SRTM3 tiles
+const LOS_STEP: f64 = 90.0;
+/// Effective earth radius factor accounting for atmospheric refraction
+const REFRACTION: f64 = 4.0 / 3.0;
+
+/// A single SRTM tile covering one degree of latitude and
+/// longitude. Samples are stored row by row from north to south and
+/// from west to east inside each row.
+struct HgtTile {
+    samples: usize,
+    heights: Vec<i16>,
+}
+
+impl HgtTile {
+    fn sample(&self, row: usize, col: usize) -> Option<f64> {
+        let h = self.heights[row * self.samples + col];
+        if h == VOID { None } else { Some(h as f64) }
+    }
+}
+
+/// Digital elevation model assembled from SRTM `.hgt` tiles
+pub struct Dem {
+    tiles: HashMap<(i32, i32), HgtTile>,
+}
+
+impl Dem {
+    /// Loads all `.hgt` files inside of `dir`. Tiles have to follow
+    /// the SRTM naming scheme, e.g. `N48E009.hgt`, and can be either
+    /// SRTM1 (3601x3601) or SRTM3 (1201x1201) tiles.
+    pub fn from_dir<P: AsRef<Path>>(dir: P) -> Result<Dem, Box<Error>> {
+        let mut tiles = HashMap::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let is_hgt = path.extension()
+                .and_then(|e| e.to_str())
+                .map(|e| e.eq_ignore_ascii_case("hgt"))
+                .unwrap_or(false);
+            if !is_hgt {
+                continue;
+            }
+            let corner = match path.file_stem().and_then(|s| s.to_str()).and_then(
+                parse_tile_name,
+            ) {
+                Some(c) => c,
+                None => {
+                    println!("Skipping elevation file with unknown name {:?}", path);
+                    continue;
+                }
+            };
+            let mut bytes = Vec::new();
+            File::open(&path)?.read_to_end(&mut bytes)?;
+            tiles.insert(corner, HgtTile::from_bytes(&bytes)?);
+        }
+        println!("Loaded {} elevation tiles", tiles.len());
+        Ok(Dem { tiles })
+    }
+
+    /// Bilinearly interpolated height in meters at the given
+    /// position. Returns `None` if no tile covers the position or
+    /// all surrounding samples are voids.
+    pub fn height_at(&self, lat: f64, lon: f64) -> Option<f64> {
+        let south = lat.floor();
+        let west = lon.floor();
+        let tile = self.tiles.get(&(south as i32, west as i32))?;
+
+        let max = (tile.samples - 1) as f64;
+        let y = (south + 1.0 - lat) * max;
+        let x = (lon - west) * max;
+        let row = (y.floor() as usize).min(tile.samples - 2);
+        let col = (x.floor() as usize).min(tile.samples - 2);
+        let dy = y - row as f64;
+        let dx = x - col as f64;
+
+        let corners = [
+            (tile.sample(row, col), (1.0 - dx) * (1.0 - dy)),
+            (tile.sample(row, col + 1), dx * (1.0 - dy)),
+            (tile.sample(row + 1, col), (1.0 - dx) * dy),
+            (tile.sample(row + 1, col + 1), dx * dy),
+        ];
+        // voids are left out and the remaining weights renormalized
+        let (sum, weight) = corners.iter().fold((0.0, 0.0), |acc, &(h, w)| match h {
+            Some(h) => (acc.0 + h * w, acc.1 + w),
+            None => acc,
+        });
+        if weight <= 0.0 {
+            corners.iter().filter_map(|c| c.0).next()
+        } else {
+            Some(sum / weight)
+        }
+    }
+
+    /// Checks if the straight line between `from` at `from_height`
+    /// and `to` at `to_height` meters above ground clears the
+    /// terrain. The earth's curvature is taken into account. Positions
+    /// without elevation data never block the line.
+    pub fn line_of_sight<C1: Coord, C2: Coord>(
+        &self,
+        from: &C1,
+        from_height: f64,
+        to: &C2,
+        to_height: f64,
+    ) -> bool {
+        let dist = haversine_distance(from, to);
+        let steps = (dist / LOS_STEP).ceil() as usize;
+        if steps < 2 {
+            return true;
+        }
+        let start = self.height_at(from.lat(), from.lon()).unwrap_or(0.0) + from_height;
+        let end = self.height_at(to.lat(), to.lon()).unwrap_or(0.0) + to_height;
+
+        (1..steps).all(|i| {
+            let f = i as f64 / steps as f64;
+            let lat = from.lat() + f * (to.lat() - from.lat());
+            let lon = from.lon() + f * (to.lon() - from.lon());
+            let terrain = match self.height_at(lat, lon) {
+                Some(h) => h,
+                None => return true,
+            };
+            let d1 = f * dist;
+            let d2 = dist - d1;
+            let bulge = d1 * d2 / (2.0 * REFRACTION * EARTH_RADIUS);
+            terrain + bulge <= start + f * (end - start)
+        })
+    }
+}
+
+impl HgtTile {
+    fn from_bytes(bytes: &[u8]) -> Result<HgtTile, Box<Error>> {
+        let count = bytes.len() / 2;
+        let samples = (count as f64).sqrt() as usize;
+        if samples < 2 || samples * samples * 2 != bytes.len() {
+            return Err(From::from(
+                format!("hgt file of {} bytes is not a square tile", bytes.len()),
+            ));
+        }
+        let heights = bytes
+            .chunks(2)
+            .map(|b| ((b[0] as u16) << 8 | b[1] as u16) as i16)
+            .collect();
+        Ok(HgtTile { samples, heights })
+    }
+}
+
+/// Parses tile names like `N48E009` into the south west corner of the tile
+fn parse_tile_name(name: &str) -> Option<(i32, i32)> {
+    let name = name.to_uppercase();
+    if name.len() != 7 {
+        return None;
+    }
+    let lat: i32 = name[1..3].parse().ok()?;
+    let lon: i32 = name[4..7].parse().ok()?;
+    let lat = match &name[0..1] {
+        "N" => lat,
+        "S" => -lat,
+        _ => return None,
+    };
+    let lon = match &name[3..4] {
+        "E" => lon,
+        "W" => -lon,
+        _ => return None,
+    };
+    Some((lat, lon))
+}
+
+/// Walking speed relative to flat ground according to Tobler's hiking
+/// function. `grade` is the height difference per meter.
+pub fn tobler_factor(grade: f64) -> f64 {
+    (-3.5 * (grade + 0.05).abs()).exp() / (-3.5 * 0.05f64).exp()
+}
+
+#[test]
+fn tile_names() {
+    assert_eq!(Some((48, 9)), parse_tile_name("N48E009"));
+    assert_eq!(Some((-12, -77)), parse_tile_name("s12w077"));
+    assert_eq!(None, parse_tile_name("X48E009"));
+}
+
+#[test]
+fn bilinear_interpolation() {
+    let mut tiles = HashMap::new();
+    tiles.insert(
+        (48, 9),
+        HgtTile {
+            samples: 2,
+            heights: vec![100, 200, 300, VOID],
+        },
+    );
+    let dem = Dem { tiles };
+    assert_eq!(Some(300.0), dem.height_at(48.0, 9.0));
+    assert_eq!(Some(200.0), dem.height_at(48.5, 9.0));
+    // the void in the south east corner is left out
+    assert_eq!(Some(200.0), dem.height_at(48.5, 9.5));
+    assert_eq!(None, dem.height_at(50.5, 9.5));
+}
+
+#[test]
+fn hill_blocks_line_of_sight() {
+    let mut tiles = HashMap::new();
+    let mut heights = vec![0; 11 * 11];
+    // a ridge running north to south in the middle of the tile
+    for row in 0..11 {
+        heights[row * 11 + 5] = 500;
+    }
+    tiles.insert((48, 9), HgtTile { samples: 11, heights });
+    let dem = Dem { tiles };
+    assert!(!dem.line_of_sight(&(48.5, 9.1), 30.0, &(48.5, 9.9), 1.5));
+    assert!(dem.line_of_sight(&(48.5, 9.1), 30.0, &(48.5, 9.3), 1.5));
+    assert!(dem.line_of_sight(&(48.5, 9.1), 2000.0, &(48.5, 9.9), 1.5));
+}
+
+#[test]
+fn tobler_is_fastest_slightly_downhill() {
+    assert!((tobler_factor(0.0) - 1.0).abs() < 1e-9);
+    assert!(tobler_factor(-0.05) > 1.0);
+    assert!(tobler_factor(0.1) < tobler_factor(-0.1));
+}
@@ -0,0 +1,114 @@
+use bincode;
+use csv;
+use osmpbfreader;
+use towers::RowError;
+
+use std::error;
+use std::fmt;
+use std::io;
+
+/// Errors while importing or loading graphs and towers
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Pbf(osmpbfreader::Error),
+    Csv(csv::Error),
+    Preprocessed(bincode::Error),
+    /// A row of a tower file in strict mode
+    InvalidRow(RowError),
+    /// A coordinate that can not be placed into a grid
+    InvalidCoordinate(f64, f64),
+    /// A damaged or incompatible preprocessed graph file
+    InvalidGraphFile(String),
+    /// Format version of a preprocessed graph and the supported one
+    UnsupportedVersion(u32, u32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "io error: {}", e),
+            Error::Pbf(ref e) => write!(f, "could not read pbf file: {}", e),
+            Error::Csv(ref e) => write!(f, "could not read csv file: {}", e),
+            Error::Preprocessed(ref e) => write!(f, "could not read preprocessed graph: {}", e),
+            Error::InvalidRow(ref e) => write!(f, "invalid {}", e),
+            Error::InvalidCoordinate(lat, lon) => write!(f, "invalid coordinate {}, {}", lat, lon),
+            Error::InvalidGraphFile(ref reason) => {
+                write!(f, "invalid preprocessed graph: {}", reason)
+            }
+            Error::UnsupportedVersion(found, supported) if found < supported => {
+                write!(
+                    f,
+                    "preprocessed graph has the old format version {}, preprocess it again",
+                    found
+                )
+            }
+            Error::UnsupportedVersion(found, supported) => {
+                write!(
+                    f,
+                    "preprocessed graph has format version {}, only version {} is supported",
+                    found,
+                    supported
+                )
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Io(ref e) => e.description(),
+            Error::Pbf(ref e) => e.description(),
+            Error::Csv(ref e) => e.description(),
+            Error::Preprocessed(ref e) => e.description(),
+            Error::InvalidRow(ref e) => e.description(),
+            Error::InvalidCoordinate(..) => "invalid coordinate",
+            Error::InvalidGraphFile(ref reason) => reason,
+            Error::UnsupportedVersion(..) => "unsupported format version",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            Error::Pbf(ref e) => Some(e),
+            Error::Csv(ref e) => Some(e),
+            Error::Preprocessed(ref e) => Some(e),
+            Error::InvalidRow(ref e) => Some(e),
+            Error::InvalidCoordinate(..) |
+            Error::InvalidGraphFile(_) |
+            Error::UnsupportedVersion(..) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<osmpbfreader::Error> for Error {
+    fn from(e: osmpbfreader::Error) -> Error {
+        Error::Pbf(e)
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(e: csv::Error) -> Error {
+        Error::Csv(e)
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Error {
+        Error::Preprocessed(e)
+    }
+}
+
+impl From<RowError> for Error {
+    fn from(e: RowError) -> Error {
+        Error::InvalidRow(e)
+    }
+}
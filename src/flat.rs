@@ -0,0 +1,316 @@
+use bincode;
+use heapsize::HeapSizeOf;
+use memmap::Mmap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::{self, DeserializeOwned};
+
+use std::cell::RefCell;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::slice;
+use std::sync::Arc;
+
+/// Alignment of every section inside of a flat file
+pub const ALIGN: usize = 8;
+
+/// Types that consist of plain data without pointers and padding, for
+/// which every bit pattern is a valid value. Their memory can be
+/// written to a file and used in place after mapping the file on a
+/// machine with the same word size and byte order.
+pub unsafe trait Flat: Sized {
+    /// Types of the fields in memory order, stored with every section
+    /// so files with a different layout are rejected
+    const LAYOUT: &'static str;
+}
+
+unsafe impl Flat for u8 {
+    const LAYOUT: &'static str = "u8";
+}
+unsafe impl Flat for usize {
+    const LAYOUT: &'static str = "usize";
+}
+
+/// Position and element type of an array inside of a flat file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Section {
+    /// Byte offset inside of the backing
+    pub offset: usize,
+    /// Length in bytes
+    pub length: usize,
+    /// Size of a single element in bytes
+    pub size: usize,
+    /// `Flat::LAYOUT` of the elements
+    pub layout: String,
+}
+
+/// Memory the sections of a flat file are used from
+pub enum Backing {
+    Map(Mmap),
+    /// File content read into memory, stored as words to keep it
+    /// aligned, with its length in bytes
+    Heap(Vec<u64>, usize),
+}
+
+impl Backing {
+    pub fn from_bytes(bytes: &[u8]) -> Backing {
+        let mut words = vec![0u64; (bytes.len() + 7) / 8];
+        unsafe {
+            let target = slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, bytes.len());
+            target.copy_from_slice(bytes);
+        }
+        Backing::Heap(words, bytes.len())
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        match *self {
+            Backing::Map(ref map) => &map[..],
+            Backing::Heap(ref words, len) => unsafe {
+                slice::from_raw_parts(words.as_ptr() as *const u8, len)
+            },
+        }
+    }
+}
+
+/// An array that is either owned or used in place from a section of
+/// a `Backing`. Mutable access copies mapped arrays into memory first.
+pub enum Store<T> {
+    Owned(Vec<T>),
+    Mapped {
+        backing: Arc<Backing>,
+        offset: usize,
+        len: usize,
+        _type: PhantomData<T>,
+    },
+}
+
+impl<T> Deref for Store<T> {
+    type Target = [T];
+
+    #[inline]
+    fn deref(&self) -> &[T] {
+        match *self {
+            Store::Owned(ref v) => v,
+            Store::Mapped {
+                ref backing,
+                offset,
+                len,
+                ..
+            } => unsafe {
+                slice::from_raw_parts(backing.bytes()[offset..].as_ptr() as *const T, len)
+            },
+        }
+    }
+}
+
+impl<T: Clone> Store<T> {
+    /// The owned array, copied out of the backing if needed
+    pub fn to_mut(&mut self) -> &mut Vec<T> {
+        if let Store::Mapped { .. } = *self {
+            let copy = self.to_vec();
+            *self = Store::Owned(copy);
+        }
+        match *self {
+            Store::Owned(ref mut v) => v,
+            Store::Mapped { .. } => unreachable!(),
+        }
+    }
+}
+
+impl<T: Clone> DerefMut for Store<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.to_mut()
+    }
+}
+
+impl<T> From<Vec<T>> for Store<T> {
+    fn from(v: Vec<T>) -> Store<T> {
+        Store::Owned(v)
+    }
+}
+
+impl<T> Default for Store<T> {
+    fn default() -> Store<T> {
+        Store::Owned(Vec::new())
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Store<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self[..].fmt(f)
+    }
+}
+
+impl<T: PartialEq> PartialEq for Store<T> {
+    fn eq(&self, other: &Store<T>) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl<T: HeapSizeOf> HeapSizeOf for Store<T> {
+    /// Mapped arrays do not count as they live in the page cache
+    fn heap_size_of_children(&self) -> usize {
+        match *self {
+            Store::Owned(ref v) => v.heap_size_of_children(),
+            Store::Mapped { .. } => 0,
+        }
+    }
+}
+
+thread_local! {
+    /// Sections collected while serializing with `serialize_flat`
+    static WRITTEN: RefCell<Option<Vec<(*const u8, Section)>>> = RefCell::new(None);
+    /// Backing and sections while deserializing with `deserialize_flat`
+    static MAPPED: RefCell<Option<(Arc<Backing>, Vec<Section>)>> = RefCell::new(None);
+}
+
+/// Outside of `serialize_flat` arrays are serialized like a `Vec`,
+/// inside only the index of their section is written
+impl<T: Flat + Serialize> Serialize for Store<T> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let section = WRITTEN.with(|w| {
+            w.borrow_mut().as_mut().map(|sections| {
+                let section = Section {
+                    offset: 0,
+                    length: self.len() * mem::size_of::<T>(),
+                    size: mem::size_of::<T>(),
+                    layout: T::LAYOUT.to_string(),
+                };
+                sections.push((self.as_ptr() as *const u8, section));
+                sections.len() as u64 - 1
+            })
+        });
+        match section {
+            Some(index) => index.serialize(serializer),
+            None => self[..].serialize(serializer),
+        }
+    }
+}
+
+impl<'de, T: Flat + Deserialize<'de>> Deserialize<'de> for Store<T> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let mapped = MAPPED.with(|m| m.borrow().clone());
+        let (backing, sections) = match mapped {
+            Some(mapped) => mapped,
+            None => return Vec::deserialize(deserializer).map(Store::Owned),
+        };
+        let index = u64::deserialize(deserializer)? as usize;
+        let section = sections.get(index).ok_or_else(|| {
+            de::Error::custom(format!("section {} does not exist", index))
+        })?;
+        if section.size != mem::size_of::<T>() || section.layout != T::LAYOUT {
+            return Err(de::Error::custom(format!(
+                "section {} holds ({}) of {} bytes instead of ({}) of {} bytes",
+                index,
+                section.layout,
+                section.size,
+                T::LAYOUT,
+                mem::size_of::<T>()
+            )));
+        }
+        let (offset, bytes) = (section.offset, section.length);
+        if offset % mem::align_of::<T>() != 0 || bytes % mem::size_of::<T>() != 0 ||
+            offset.checked_add(bytes).map_or(true, |end| end > backing.bytes().len())
+        {
+            return Err(de::Error::custom(format!("section {} is invalid", index)));
+        }
+        Ok(Store::Mapped {
+            backing,
+            offset,
+            len: bytes / mem::size_of::<T>(),
+            _type: PhantomData,
+        })
+    }
+}
+
+/// Serializes `value` with every `Store` replaced by the index of its
+/// section. Returns the serialized rest and the sections with their
+/// content, which borrows from `value`. The offsets of the sections
+/// are left to the caller.
+pub fn serialize_flat<'a, T: Serialize>(
+    value: &'a T,
+) -> Result<(Vec<u8>, Vec<(Section, &'a [u8])>), bincode::Error> {
+    WRITTEN.with(|w| *w.borrow_mut() = Some(Vec::new()));
+    let rest = bincode::serialize(value, bincode::Infinite);
+    let sections = WRITTEN.with(|w| w.borrow_mut().take()).unwrap_or_default();
+    let sections = sections
+        .into_iter()
+        .map(|(ptr, section)| {
+            let bytes = unsafe { slice::from_raw_parts(ptr, section.length) };
+            (section, bytes)
+        })
+        .collect();
+    Ok((rest?, sections))
+}
+
+/// Reverses `serialize_flat`. The arrays are used in place from the
+/// `sections` of `backing`, which must hold the elements they were
+/// written with.
+pub fn deserialize_flat<T: DeserializeOwned>(
+    rest: &[u8],
+    backing: Arc<Backing>,
+    sections: Vec<Section>,
+) -> Result<T, bincode::Error> {
+    MAPPED.with(|m| *m.borrow_mut() = Some((backing, sections)));
+    let value = bincode::deserialize(rest);
+    MAPPED.with(|m| *m.borrow_mut() = None);
+    value
+}
+
+#[test]
+fn flat_roundtrip() {
+    let stores: (Store<u8>, Store<usize>) = (vec![1, 2, 3].into(), vec![4, 5].into());
+    let (rest, sections) = serialize_flat(&stores).unwrap();
+    let mut bytes = Vec::new();
+    let mut table = Vec::new();
+    for (mut section, content) in sections {
+        while bytes.len() % ALIGN != 0 {
+            bytes.push(0);
+        }
+        section.offset = bytes.len();
+        table.push(section);
+        bytes.extend_from_slice(content);
+    }
+    let backing = Arc::new(Backing::from_bytes(&bytes));
+    let mut read: (Store<u8>, Store<usize>) =
+        deserialize_flat(&rest, backing.clone(), table.clone()).unwrap();
+    assert_eq!(stores, read);
+    match read.1 {
+        Store::Mapped { .. } => (),
+        Store::Owned(_) => panic!("arrays have to be used in place"),
+    }
+    read.1[0] = 6;
+    assert_eq!(&[6, 5], &read.1[..]);
+
+    // a section of bytes can't be used as an array of words
+    table.swap(0, 1);
+    let wrong: Result<(Store<u8>, Store<usize>), _> = deserialize_flat(&rest, backing, table);
+    assert!(wrong.is_err());
+}
+
+/// Sum of the sizes of the fields in `layout`, which equals the size
+/// of a type without padding
+#[cfg(test)]
+pub fn layout_size(layout: &str) -> usize {
+    layout
+        .split(',')
+        .map(|field| match field {
+            "u8" => 1,
+            "i16" => 2,
+            "i32" => 4,
+            "f64" => 8,
+            "usize" => mem::size_of::<usize>(),
+            array => {
+                let count = array.trim_left_matches("[u8;").trim_right_matches(']');
+                count.parse().expect("unknown field type")
+            }
+        })
+        .sum()
+}
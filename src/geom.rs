@@ -4,7 +4,7 @@ use ordered_float::OrderedFloat;
 
 use graph::Length;
 
-const EARTH_RADIUS: f64 = 6_371_007.2;
+pub const EARTH_RADIUS: f64 = 6_371_007.2;
 
 /// Allow uniform access to structs with spherical coordinates
 pub trait Coord {
@@ -90,6 +90,79 @@ pub fn intersect<P: Point>(a: &P, b: &P, center: &P, r: f64) -> SegmentSection {
     SegmentSection::new(t1, t2)
 }
 
+/// Intersect a line segment defined by points `a` and `b` with a
+/// circular sector with center `center` and radius `r`. The sector
+/// opens in direction `azimuth` (degrees clockwise from north, which
+/// is the x axis of projected points) and is `beamwidth` degrees wide.
+/// Sectors wider than 180 degrees are not convex and may cut the
+/// segment into two sections.
+pub fn intersect_sector<P: Point>(
+    a: &P,
+    b: &P,
+    center: &P,
+    r: f64,
+    azimuth: f64,
+    beamwidth: f64,
+) -> Vec<SegmentSection> {
+    let disk = intersect(a, b, center, r);
+    if disk.is_empty() || beamwidth >= 360.0 {
+        return vec![disk];
+    }
+    let first = (azimuth - beamwidth / 2.0).to_radians();
+    let last = (azimuth + beamwidth / 2.0).to_radians();
+    let first = (first.cos(), first.sin());
+    let last = (last.cos(), last.sin());
+    // points clockwise of `first` and counterclockwise of `last`
+    let after_first = half_plane(a, b, center, &first, 1.0);
+    let before_last = half_plane(a, b, center, &last, -1.0);
+
+    if beamwidth <= 180.0 {
+        vec![disk.intersection(&after_first.intersection(&before_last))]
+    } else {
+        vec![disk.intersection(&after_first), disk.intersection(&before_last)]
+    }
+}
+
+/// Checks whether `p` lies inside of the (infinite) sector with apex
+/// `center` that opens in direction `azimuth` and is `beamwidth` degrees wide
+pub fn in_sector<P: Point>(p: &P, center: &P, azimuth: f64, beamwidth: f64) -> bool {
+    if beamwidth >= 360.0 {
+        return true;
+    }
+    let v = p.sub(center);
+    let angle = v.y().atan2(v.x()).to_degrees();
+    let mut diff = (angle - azimuth) % 360.0;
+    if diff > 180.0 {
+        diff -= 360.0;
+    } else if diff < -180.0 {
+        diff += 360.0;
+    }
+    diff.abs() <= beamwidth / 2.0
+}
+
+/// Section of the segment from `a` to `b` for which the cross product
+/// of `dir` and the vector from `center` to the point has the same
+/// sign as `side`
+fn half_plane<P: Point>(a: &P, b: &P, center: &P, dir: &TuplePoint, side: f64) -> SegmentSection {
+    let cross = |v: TuplePoint| side * (dir.x() * v.y() - dir.y() * v.x());
+    let k = cross(a.sub(center));
+    let m = cross(b.sub(a));
+    if m == 0.0 {
+        return if k >= 0.0 {
+            SegmentSection::new(0.0, 1.0)
+        } else {
+            SegmentSection::empty()
+        };
+    }
+    let t = -k / m;
+    let (start, end) = if m > 0.0 { (t, 1.0) } else { (0.0, t) };
+    if start >= end {
+        SegmentSection::empty()
+    } else {
+        SegmentSection::new(start, end)
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
 pub struct SegmentSection {
     start: OrderedFloat<f64>,
@@ -150,6 +223,30 @@ impl SegmentSection {
     pub fn length(&self) -> f64 {
         self.end.into_inner() - self.start.into_inner()
     }
+
+    pub fn start(&self) -> f64 {
+        self.start.into_inner()
+    }
+
+    pub fn end(&self) -> f64 {
+        self.end.into_inner()
+    }
+
+    /// Checks if the point at `fraction` of the segment lies in the section
+    pub fn contains(&self, fraction: f64) -> bool {
+        !self.is_empty() && self.start() <= fraction && fraction <= self.end()
+    }
+
+    /// Part of the segment contained in both sections
+    pub fn intersection(&self, other: &Self) -> SegmentSection {
+        let start = self.start.max(other.start);
+        let end = self.end.min(other.end);
+        if start >= end {
+            SegmentSection::empty()
+        } else {
+            SegmentSection { start, end }
+        }
+    }
 }
 
 /// Calculate the haversine distance. Adapted from https://github.com/georust/rust-geo
@@ -164,6 +261,31 @@ pub fn haversine_distance<C1: Coord, C2: Coord>(a: &C1, b: &C2) -> Length {
     EARTH_RADIUS * c
 }
 
+/// Finds the point on the segment from `a` to `b` that is closest to
+/// `p`. Returns its position as fraction of the segment and its
+/// distance to `p` in meters.
+pub fn closest_on_segment<C1: Coord, C2: Coord, C3: Coord>(
+    p: &C1,
+    a: &C2,
+    b: &C3,
+) -> (f64, Length) {
+    let lat0 = p.lat().to_radians();
+    let p = project(p, lat0);
+    let a = project(a, lat0);
+    let b = project(b, lat0);
+    let v = b.sub(&a);
+    let w = p.sub(&a);
+    let squared_length = v.mul(&v).sum();
+    let fraction = if squared_length > 0.0 {
+        (w.mul(&v).sum() / squared_length).max(0.0).min(1.0)
+    } else {
+        0.0
+    };
+    let closest = (a.0 + fraction * v.0, a.1 + fraction * v.1);
+    let d = p.sub(&closest);
+    (fraction, d.mul(&d).sum().sqrt())
+}
+
 #[test]
 fn empty_circle_segment_intersection() {
     let result = intersect(&(1.0, 1.0), &(2.0, 2.0), &(5.0, 5.0), 1.0);
@@ -209,3 +331,42 @@ fn merge_segments() {
     assert_eq!(SegmentSection::new(0.1, 0.6), sec1.merge(&sec2));
     assert_eq!(sec2.merge(&sec1), sec1.merge(&sec2));
 }
+
+#[test]
+fn sector_facing_segment() {
+    // sector opens to the north (positive x) with 90 degrees
+    let result = intersect_sector(&(-2.0, 0.0), &(2.0, 0.0), &(0.0, 0.0), 1.0, 0.0, 90.0);
+    assert_eq!(vec![SegmentSection::new(0.5, 0.75)], result);
+}
+
+#[test]
+fn sector_facing_away_from_segment() {
+    let result = intersect_sector(&(1.0, -2.0), &(1.0, 2.0), &(0.0, 0.0), 5.0, 180.0, 90.0);
+    assert!(result.iter().all(|s| s.is_empty()));
+}
+
+#[test]
+fn wide_sector_cuts_segment_twice() {
+    // sector opening to the south with 270 degrees only misses the
+    // northern quarter
+    let result = intersect_sector(&(1.0, -2.0), &(1.0, 2.0), &(0.0, 0.0), 5.0, 180.0, 270.0);
+    let covered: f64 = result.iter().map(|s| s.length()).sum();
+    assert!((covered - 0.5).abs() < 1e-9);
+}
+
+#[test]
+fn point_in_sector() {
+    assert!(in_sector(&(1.0, 0.1), &(0.0, 0.0), 0.0, 90.0));
+    assert!(in_sector(&(0.1, 1.0), &(0.0, 0.0), 90.0, 30.0));
+    assert!(!in_sector(&(-1.0, 0.0), &(0.0, 0.0), 0.0, 90.0));
+    assert!(in_sector(&(1.0, -0.1), &(0.0, 0.0), 350.0, 30.0));
+}
+
+#[test]
+fn closest_point_on_segment() {
+    let (fraction, dist) = closest_on_segment(&(52.001, 13.005), &(52.0, 13.0), &(52.0, 13.01));
+    assert!((fraction - 0.5).abs() < 1e-6);
+    assert!((dist - 111.2).abs() < 0.5);
+    let (fraction, _) = closest_on_segment(&(52.0, 12.9), &(52.0, 13.0), &(52.0, 13.01));
+    assert_eq!(0.0, fraction);
+}
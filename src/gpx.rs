@@ -0,0 +1,98 @@
+use geom::Coord;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Read;
+
+use xml::reader::{EventReader, XmlEvent};
+
+/// A single `trkpt` of a GPX track
+#[derive(Debug, Clone, Default)]
+pub struct TrackPoint {
+    pub lat: f64,
+    pub lon: f64,
+    pub time: Option<String>,
+    /// Text of all elements inside of the point's `extensions` by
+    /// their local name
+    pub extensions: HashMap<String, String>,
+}
+
+impl Coord for TrackPoint {
+    #[inline]
+    fn lat(&self) -> f64 {
+        self.lat
+    }
+    #[inline]
+    fn lon(&self) -> f64 {
+        self.lon
+    }
+}
+
+/// Reads the points of all tracks and segments of a GPX file in order
+pub fn read_track<R: Read>(r: R) -> Result<Vec<TrackPoint>, Box<Error>> {
+    let mut points = Vec::new();
+    let mut current: Option<TrackPoint> = None;
+    let mut element = String::new();
+    let mut in_extensions = false;
+    for event in EventReader::new(r) {
+        match event? {
+            XmlEvent::StartElement {
+                name, attributes, ..
+            } => {
+                if name.local_name == "trkpt" {
+                    let mut point = TrackPoint::default();
+                    for attribute in attributes {
+                        match attribute.name.local_name.as_str() {
+                            "lat" => point.lat = attribute.value.parse()?,
+                            "lon" => point.lon = attribute.value.parse()?,
+                            _ => (),
+                        }
+                    }
+                    current = Some(point);
+                } else if name.local_name == "extensions" {
+                    in_extensions = true;
+                }
+                element = name.local_name;
+            }
+            XmlEvent::Characters(text) => {
+                if let Some(ref mut point) = current {
+                    if in_extensions {
+                        point.extensions.insert(element.clone(), text);
+                    } else if element == "time" {
+                        point.time = Some(text);
+                    }
+                }
+            }
+            XmlEvent::EndElement { name } => {
+                if name.local_name == "trkpt" {
+                    points.extend(current.take());
+                } else if name.local_name == "extensions" {
+                    in_extensions = false;
+                }
+                element.clear();
+            }
+            _ => (),
+        }
+    }
+    Ok(points)
+}
+
+#[test]
+fn reading_gpx_tracks() {
+    let gpx = r#"<?xml version="1.0"?>
+<gpx version="1.1" xmlns="http://www.topografix.com/GPX/1/1">
+  <trk><trkseg>
+    <trkpt lat="52.5" lon="13.4">
+      <time>2017-09-01T10:00:00Z</time>
+      <extensions><provider>262-01</provider><rsrp>-95</rsrp></extensions>
+    </trkpt>
+    <trkpt lat="52.6" lon="13.5"></trkpt>
+  </trkseg></trk>
+</gpx>"#;
+    let points = read_track(gpx.as_bytes()).unwrap();
+    assert_eq!(2, points.len());
+    assert_eq!(52.5, points[0].lat);
+    assert_eq!(Some("2017-09-01T10:00:00Z".to_string()), points[0].time);
+    assert_eq!("-95", points[0].extensions["rsrp"]);
+    assert!(points[1].extensions.is_empty());
+}
@@ -1,5 +1,8 @@
 use super::{Graph, NodeId, Length};
-use towers::Provider;
+use towers::TechClass;
+use provider::ProviderSelection;
+use coverage::CoverageLayer;
+use propagation::EdgeSignal;
 
 use std::cmp::Ordering;
 use std::f64;
@@ -11,6 +14,7 @@ impl Graph {
     pub fn dijkstra(&self) -> Dijkstra {
         Dijkstra {
             dist: vec![f64::MAX.into(); self.node_count()],
+            prev: vec![(0, 0); self.node_count()],
             touched: Default::default(),
             graph: self,
         }
@@ -41,6 +45,9 @@ impl PartialOrd for NodeCost {
 
 pub struct Dijkstra<'a> {
     dist: Vec<OrderedFloat<f64>>,
+    /// Predecessor and edge from there of the nodes reached by
+    /// `paths_to`, valid for touched nodes
+    prev: Vec<(NodeId, usize)>,
     touched: Vec<NodeId>,
     graph: &'a Graph,
 }
@@ -48,6 +55,8 @@ pub struct Dijkstra<'a> {
 pub struct Route {
     pub distance: Length,
     pub travel_time: f64,
+    pub ascent: f64,
+    pub descent: f64,
     pub node_seq: NodeSequence,
 }
 
@@ -58,14 +67,24 @@ impl<'a> Dijkstra<'a> {
         dest: NodeId,
         goal: RoutingGoal,
         movement: Movement,
-        provider: Option<Provider>,
+        provider: Option<ProviderSelection>,
+        tech: TechClass,
+        penalty: Penalty,
     ) -> Option<Route> {
         use std::collections::BinaryHeap;
         let goal = match movement {
             Movement::Car => goal,
             Movement::Foot => RoutingGoal::Length,
         };
-        let coverage = self.graph.coverage.get_all(provider);
+        let coverage = self.graph.coverage.get_all(provider, tech);
+        // graphs without signal levels and combined provider
+        // selections fall back to the coverage penalty
+        let signal = match penalty {
+            Penalty::Signal => self.graph.signal.as_ref().and_then(|s| {
+                s.get_all(provider.and_then(|p| p.single()))
+            }),
+            Penalty::Coverage => None,
+        };
 
         let mut prev: Vec<usize> = (0..self.graph.node_count()).collect();
 
@@ -94,10 +113,13 @@ impl<'a> Dijkstra<'a> {
                     cur = prev[cur];
                 }
                 path.push_front(cur);
+                let (ascent, descent) = self.elevation_change(&path);
                 return Some(Route {
                     node_seq: path,
                     distance: distance.into_inner(),
                     travel_time: time.into_inner(),
+                    ascent,
+                    descent,
                 });
             }
 
@@ -108,11 +130,18 @@ impl<'a> Dijkstra<'a> {
                 if edge.is_not_for(&movement) {
                     continue;
                 }
-                let scaling_factor = self.calculate_scaling_factor(coverage, n);
+                let scaling_factor = match signal {
+                    Some(signal) => self.signal_scaling_factor(signal, n),
+                    None => self.calculate_scaling_factor(coverage, n),
+                };
+                let walking_factor = match movement {
+                    Movement::Foot => self.graph.walking_factor(n),
+                    Movement::Car => 1.0,
+                };
                 let next = NodeCost {
                     node: edge.endpoint,
                     cost: (cost.into_inner() + edge.get_cost(&goal) * scaling_factor).into(),
-                    time: (time.into_inner() + edge.get_time(&movement)).into(),
+                    time: (time.into_inner() + edge.get_time(&movement, walking_factor)).into(),
                     distance: (distance.into_inner() + edge.length).into(),
                 };
                 if next.cost < self.dist[next.node] {
@@ -126,6 +155,87 @@ impl<'a> Dijkstra<'a> {
         None
     }
 
+    /// Shortest paths from `source` to `targets` that are at most
+    /// `max_distance` meters long using only edges usable with
+    /// `movement`. The search stops once all targets are reached.
+    /// Returns the length and the edges of the path to every target.
+    pub fn paths_to(
+        &mut self,
+        source: NodeId,
+        targets: &[NodeId],
+        max_distance: Length,
+        movement: &Movement,
+    ) -> Vec<Option<(Length, Vec<usize>)>> {
+        use std::collections::BinaryHeap;
+
+        let mut open: Vec<NodeId> = targets.to_vec();
+        open.sort();
+        open.dedup();
+        let mut reached = Vec::with_capacity(open.len());
+        self.reset_state();
+        self.dist[source] = 0.0.into();
+        self.touched.push(source);
+        let mut heap = BinaryHeap::new();
+        heap.push(NodeCost {
+            node: source,
+            cost: 0.0.into(),
+            time: 0.0.into(),
+            distance: 0.0.into(),
+        });
+
+        while let Some(NodeCost { node, cost, .. }) = heap.pop() {
+            if cost > self.dist[node] {
+                continue;
+            }
+            if let Ok(i) = open.binary_search(&node) {
+                open.remove(i);
+                reached.push(node);
+                if open.is_empty() {
+                    break;
+                }
+            }
+            for (n, edge) in self.graph.outgoing_edges_for(node) {
+                if edge.is_not_for(movement) {
+                    continue;
+                }
+                let next = cost.into_inner() + edge.length;
+                if next <= max_distance && OrderedFloat::from(next) < self.dist[edge.endpoint] {
+                    self.dist[edge.endpoint] = next.into();
+                    self.prev[edge.endpoint] = (node, n);
+                    self.touched.push(edge.endpoint);
+                    heap.push(NodeCost {
+                        node: edge.endpoint,
+                        cost: next.into(),
+                        time: 0.0.into(),
+                        distance: next.into(),
+                    });
+                }
+            }
+        }
+
+        targets
+            .iter()
+            .map(|target| if reached.contains(target) {
+                Some((self.dist[*target].into_inner(), self.path_edges(source, *target)))
+            } else {
+                None
+            })
+            .collect()
+    }
+
+    /// Edges of the path to `target` found by the last search
+    fn path_edges(&self, source: NodeId, target: NodeId) -> Vec<usize> {
+        let mut edges = Vec::new();
+        let mut node = target;
+        while node != source {
+            let (prev, edge) = self.prev[node];
+            edges.push(edge);
+            node = prev;
+        }
+        edges.reverse();
+        edges
+    }
+
     #[inline]
     fn reset_state(&mut self) {
         for node in self.touched.drain(..) {
@@ -133,22 +243,53 @@ impl<'a> Dijkstra<'a> {
         }
 
     }
+    /// Sums up the cumulative ascent and descent in meters along
+    /// `path` including the shape points of its edges
+    fn elevation_change(&self, path: &NodeSequence) -> (f64, f64) {
+        let points = self.graph.path_points(path);
+        points.iter().zip(points.iter().skip(1)).fold(
+            (0.0, 0.0),
+            |(up, down), (a, b)| {
+                let diff = b.height as f64 - a.height as f64;
+                if diff > 0.0 {
+                    (up + diff, down)
+                } else {
+                    (up, down - diff)
+                }
+            },
+        )
+    }
+
     #[inline]
-    fn calculate_scaling_factor(&self, coverage: Option<&Vec<f64>>, index: usize) -> f64 {
+    fn calculate_scaling_factor(&self, coverage: Option<CoverageLayer>, index: usize) -> f64 {
         match coverage {
-            Some(cov) => (1.0 + f64::EPSILON) / (3.0 * cov[index] + f64::EPSILON),
+            Some(cov) => (1.0 + f64::EPSILON) / (3.0 * cov.get(index) + f64::EPSILON),
             None => 1.0,
         }
     }
+
+    #[inline]
+    fn signal_scaling_factor(&self, signal: &[EdgeSignal], index: usize) -> f64 {
+        (1.0 + f64::EPSILON) / (3.0 * signal[index].quality() + f64::EPSILON)
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum Movement {
     Car,
     Foot,
 }
 
-#[derive(Debug)]
+/// Determines what makes the router avoid an edge
+#[derive(Debug, Clone, Copy)]
+pub enum Penalty {
+    /// Share of the edge that is inside of a tower's range
+    Coverage,
+    /// Estimated signal level along the edge
+    Signal,
+}
+
+#[derive(Debug, Clone, Copy)]
 pub enum RoutingGoal {
     Length,
     Speed,
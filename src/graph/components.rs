@@ -0,0 +1,176 @@
+use super::{Graph, Movement, NodeId};
+
+use std::usize;
+
+const UNVISITED: usize = usize::MAX;
+
+/// Nodes of the largest strongly connected component for every
+/// `Movement`. Routes from or to nodes outside of it may not exist.
+#[derive(Debug, Default, HeapSizeOf, Serialize, Deserialize)]
+pub struct MainComponents {
+    car: Vec<bool>,
+    foot: Vec<bool>,
+}
+
+impl MainComponents {
+    pub fn new(graph: &Graph) -> MainComponents {
+        MainComponents {
+            car: largest_component(graph, &Movement::Car),
+            foot: largest_component(graph, &Movement::Foot),
+        }
+    }
+
+    /// Checks if `node` is part of the main component for `movement`
+    pub fn contains(&self, node: NodeId, movement: &Movement) -> bool {
+        let nodes = match *movement {
+            Movement::Car => &self.car,
+            Movement::Foot => &self.foot,
+        };
+        nodes.get(node).cloned().unwrap_or(false)
+    }
+}
+
+/// Marks the nodes of the largest strongly connected component using
+/// only edges usable with `movement` and logs how the nodes are split
+/// up into components
+fn largest_component(graph: &Graph, movement: &Movement) -> Vec<bool> {
+    let (component, count) = strongly_connected_components(graph, movement);
+    let mut sizes = vec![0; count];
+    for &c in &component {
+        sizes[c] += 1;
+    }
+    let largest = (0..count).max_by_key(|&c| sizes[c]);
+    let largest_size = largest.map_or(0, |c| sizes[c]);
+    println!(
+        "{:?}: {} components, the largest has {} of {} nodes, {} nodes in components \
+         with at least 10 nodes",
+        movement,
+        count,
+        largest_size,
+        component.len(),
+        sizes.iter().filter(|&&s| s >= 10).sum::<usize>()
+    );
+    component.iter().map(|&c| Some(c) == largest).collect()
+}
+
+/// Tarjan's algorithm without recursion. Returns the component of
+/// every node and the number of components.
+fn strongly_connected_components(graph: &Graph, movement: &Movement) -> (Vec<usize>, usize) {
+    let mut tarjan = Tarjan {
+        index: vec![UNVISITED; graph.node_info.len()],
+        low: vec![0; graph.node_info.len()],
+        on_stack: vec![false; graph.node_info.len()],
+        stack: Vec::new(),
+        component: vec![UNVISITED; graph.node_info.len()],
+        next_index: 0,
+        count: 0,
+    };
+    for root in 0..graph.node_info.len() {
+        if tarjan.index[root] != UNVISITED {
+            continue;
+        }
+        // nodes currently visited with the next of their edges to look at
+        let mut calls: Vec<(NodeId, usize)> = Vec::new();
+        tarjan.visit(root);
+        calls.push((root, graph.node_offsets[root].0));
+        loop {
+            let (node, position) = match calls.last() {
+                Some(&call) => call,
+                None => break,
+            };
+            if position < graph.node_offsets[node + 1].0 {
+                calls.last_mut().unwrap().1 += 1;
+                let edge = &graph.edges[position];
+                if edge.is_not_for(movement) {
+                    continue;
+                }
+                let next = edge.endpoint;
+                if tarjan.index[next] == UNVISITED {
+                    tarjan.visit(next);
+                    calls.push((next, graph.node_offsets[next].0));
+                } else if tarjan.on_stack[next] {
+                    tarjan.low[node] = tarjan.low[node].min(tarjan.index[next]);
+                }
+            } else {
+                calls.pop();
+                if let Some(&(parent, _)) = calls.last() {
+                    tarjan.low[parent] = tarjan.low[parent].min(tarjan.low[node]);
+                }
+                if tarjan.low[node] == tarjan.index[node] {
+                    tarjan.close_component(node);
+                }
+            }
+        }
+    }
+    (tarjan.component, tarjan.count)
+}
+
+struct Tarjan {
+    index: Vec<usize>,
+    low: Vec<usize>,
+    on_stack: Vec<bool>,
+    stack: Vec<NodeId>,
+    component: Vec<usize>,
+    next_index: usize,
+    count: usize,
+}
+
+impl Tarjan {
+    fn visit(&mut self, node: NodeId) {
+        self.index[node] = self.next_index;
+        self.low[node] = self.next_index;
+        self.next_index += 1;
+        self.stack.push(node);
+        self.on_stack[node] = true;
+    }
+
+    /// Pops all nodes up to `root` from the stack into a new component
+    fn close_component(&mut self, root: NodeId) {
+        loop {
+            let node = self.stack.pop().unwrap();
+            self.on_stack[node] = false;
+            self.component[node] = self.count;
+            if node == root {
+                break;
+            }
+        }
+        self.count += 1;
+    }
+}
+
+#[test]
+fn islands_are_not_in_main_component() {
+    use super::{NodeInfo, EdgeInfo};
+
+    let mut towers = Vec::new();
+    let mut one_way = EdgeInfo::new(3, 4, 1.0, 50);
+    one_way.not_for_pedestrians();
+    let g = Graph::new(
+        vec![
+            NodeInfo::new(1, 52.0, 13.0, 0),
+            NodeInfo::new(2, 52.001, 13.0, 0),
+            NodeInfo::new(3, 52.002, 13.0, 0),
+            NodeInfo::new(4, 52.003, 13.0, 0),
+            NodeInfo::new(5, 52.01, 13.01, 0),
+            NodeInfo::new(6, 52.011, 13.01, 0),
+        ],
+        vec![
+            EdgeInfo::new(1, 2, 1.0, 50),
+            EdgeInfo::new(2, 1, 1.0, 50),
+            EdgeInfo::new(2, 3, 1.0, 50),
+            EdgeInfo::new(3, 2, 1.0, 50),
+            one_way,
+            EdgeInfo::new(4, 3, 1.0, 50),
+            EdgeInfo::new(5, 6, 1.0, 50),
+            EdgeInfo::new(6, 5, 1.0, 50),
+        ],
+        &mut towers,
+        Default::default(),
+        &Default::default(),
+    ).unwrap();
+    let island = g.node_info.iter().position(|n| n.osm_id == 5).unwrap();
+    let end = g.node_info.iter().position(|n| n.osm_id == 4).unwrap();
+    assert!(!g.components.contains(island, &Movement::Car));
+    assert!(g.components.contains(end, &Movement::Car));
+    assert!(!g.components.contains(end, &Movement::Foot));
+}
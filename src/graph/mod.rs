@@ -1,15 +1,25 @@
 mod alg;
-
-pub use self::alg::{RoutingGoal, Movement};
+mod components;
+mod shape;
+mod road;
+
+pub use self::alg::{RoutingGoal, Movement, Penalty, Dijkstra, Route};
+pub use self::components::MainComponents;
+pub use self::shape::{EdgeShapes, SegmentRef};
+pub use self::road::RoadClass;
 use grid::{Grid, NodeInfoWithIndex};
 use geom::{Coord, haversine_distance};
+use elevation::tobler_factor;
 use towers::*;
+use provider::ProviderRegistry;
+use coverage::{Coverage, CoverageSource, Measurement};
+use propagation::{SignalLevels, polyline_signal};
+use error::Error;
+use flat::{Flat, Store};
 
-use std::time::Instant;
-use std::path::Path;
+use std::collections::HashSet;
 
 use rayon::prelude::*;
-use bincode;
 
 pub type NodeId = usize;
 pub type OsmNodeId = usize;
@@ -17,14 +27,22 @@ pub type Latitude = f64;
 pub type Longitude = f64;
 pub type Length = f64;
 pub type Speed = usize;
-pub type Height = usize;
+pub type Height = i16;
+
+/// Distance in meters up to which coordinates are snapped to roads
+const SNAP_DISTANCE: f64 = 500.0;
 
 #[derive(HeapSizeOf, Default, Debug, Clone, Serialize, Deserialize)]
+#[repr(C)]
 pub struct NodeInfo {
     pub osm_id: OsmNodeId,
     pub lat: Latitude,
     pub long: Longitude,
     pub height: Height,
+    /// Keeps the compiler from adding padding of unknown content
+    #[ignore_heap_size_of = "padding"]
+    #[serde(skip_serializing, skip_deserializing)]
+    _padding: [u8; 6],
 }
 
 impl NodeInfo {
@@ -34,6 +52,7 @@ impl NodeInfo {
             lat: lat,
             long: long,
             height: height,
+            _padding: [0; 6],
         }
     }
 }
@@ -45,6 +64,10 @@ impl PartialEq for NodeInfo {
 }
 impl Eq for NodeInfo {}
 
+unsafe impl Flat for NodeInfo {
+    const LAYOUT: &'static str = "usize,f64,f64,i16,[u8;6]";
+}
+
 #[derive(PartialEq, Debug, HeapSizeOf)]
 pub struct EdgeInfo {
     pub source: NodeId,
@@ -53,6 +76,9 @@ pub struct EdgeInfo {
     speed: Speed,
     for_cars: bool,
     for_pedestrians: bool,
+    pub road_class: RoadClass,
+    /// Nodes of a contracted chain between `source` and `dest`
+    pub shape: Vec<NodeInfo>,
 }
 
 impl Coord for NodeInfo {
@@ -75,6 +101,8 @@ impl EdgeInfo {
             speed: speed,
             for_cars: true,
             for_pedestrians: true,
+            road_class: RoadClass::Other,
+            shape: Vec::new(),
         }
     }
 
@@ -91,12 +119,23 @@ impl EdgeInfo {
 /// HalfEdge structs do not need both endpoints as one of them can be
 /// concluded from its position in the offset array
 #[derive(HeapSizeOf, Debug, PartialEq, Serialize, Deserialize)]
+#[repr(C)]
 pub struct HalfEdge {
     pub endpoint: NodeId,
     length: f64,
     time: f64,
-    for_cars: bool,
-    for_pedestrians: bool,
+    /// Flags are stored as 0 or 1, as mapped memory may hold any value
+    for_cars: u8,
+    for_pedestrians: u8,
+    /// Index of the `RoadClass`
+    road_class: u8,
+    #[ignore_heap_size_of = "padding"]
+    #[serde(skip_serializing, skip_deserializing)]
+    _padding: [u8; 5],
+}
+
+unsafe impl Flat for HalfEdge {
+    const LAYOUT: &'static str = "usize,f64,f64,u8,u8,u8,[u8;5]";
 }
 
 impl HalfEdge {
@@ -104,11 +143,15 @@ impl HalfEdge {
     #[inline]
     pub fn is_not_for(&self, movement: &Movement) -> bool {
         match *movement {
-            Movement::Car => !self.for_cars,
-            Movement::Foot => !self.for_pedestrians,
+            Movement::Car => self.for_cars == 0,
+            Movement::Foot => self.for_pedestrians == 0,
         }
     }
 
+    pub fn road_class(&self) -> RoadClass {
+        RoadClass::from_index(self.road_class)
+    }
+
     /// Extract cost according to given routing goal
     #[inline]
     pub fn get_cost(&self, goal: &RoutingGoal) -> f64 {
@@ -118,19 +161,26 @@ impl HalfEdge {
         }
     }
 
-    /// calculate needed time according to given routing goal
+    /// calculate needed time according to given routing goal. Walking
+    /// speed is scaled by `walking_factor`, see `Graph::walking_factor`.
     #[inline]
-    pub fn get_time(&self, movement: &Movement) -> f64 {
+    pub fn get_time(&self, movement: &Movement, walking_factor: f64) -> f64 {
         match *movement {
             Movement::Car => self.time,
-            Movement::Foot => self.length / 3.0,
+            Movement::Foot => self.length / (3.0 * walking_factor),
         }
     }
 }
 
 
 #[derive(Clone, PartialEq, Debug, HeapSizeOf, Serialize, Deserialize)]
+#[repr(C)]
 struct NodeOffset(usize);
+
+unsafe impl Flat for NodeOffset {
+    const LAYOUT: &'static str = "usize";
+}
+
 impl NodeOffset {
     pub fn new(out_start: usize) -> NodeOffset {
         NodeOffset(out_start)
@@ -139,11 +189,24 @@ impl NodeOffset {
 
 #[derive(HeapSizeOf, Serialize, Deserialize)]
 pub struct Graph {
-    pub node_info: Vec<NodeInfo>,
-    node_offsets: Vec<NodeOffset>,
-    pub edges: Vec<HalfEdge>,
+    pub node_info: Store<NodeInfo>,
+    node_offsets: Store<NodeOffset>,
+    pub edges: Store<HalfEdge>,
+    pub shapes: EdgeShapes,
     pub grid: Grid,
+    /// Spatial index over the towers the coverage was calculated from
+    pub tower_grid: Grid,
+    /// Coverage of whole edges as used for routing
     pub coverage: Coverage,
+    /// Coverage of every subsegment of the edges
+    pub segment_coverage: Coverage,
+    pub signal: Option<SignalLevels>,
+    pub providers: ProviderRegistry,
+    /// Nodes routes can be calculated between
+    pub components: MainComponents,
+    /// Drive test measurements blended into the segment coverage, kept
+    /// to blend them in again whenever the coverage is recalculated
+    pub calibration: Vec<Measurement>,
 }
 
 
@@ -152,21 +215,109 @@ impl Graph {
         mut node_info: Vec<NodeInfo>,
         mut edge_infos: Vec<EdgeInfo>,
         towers: &mut Vec<Tower>,
-    ) -> Graph {
-        let grid = Grid::new(&mut node_info, 100);
+        providers: ProviderRegistry,
+        options: &CoverageOptions,
+    ) -> Result<Graph, Error> {
+        let grid = Grid::new(&mut node_info, 100)?;
         Graph::rename_node_ids_and_calculate_distance(&node_info, &mut edge_infos);
         let node_count = node_info.len();
         let (node_offsets, edges) = Graph::calc_node_offsets(node_count, &mut edge_infos);
-        let coverage = Graph::calculate_coverage(&node_info, &mut edge_infos, towers);
-
-        Graph {
-            node_info,
-            node_offsets,
-            edges,
+        let shapes = EdgeShapes::new(&node_info, &edge_infos)?;
+        let TowerCoverage {
+            tower_grid,
+            coverage,
+            segment_coverage,
+            signal,
+            providers,
+        } = TowerCoverage::calculate(&shapes, towers, providers, options)?;
+
+        let mut graph = Graph {
+            node_info: node_info.into(),
+            node_offsets: node_offsets.into(),
+            edges: edges.into(),
+            shapes,
             grid,
+            tower_grid,
             coverage,
-        }
+            segment_coverage,
+            signal,
+            providers,
+            components: Default::default(),
+            calibration: Vec::new(),
+        };
+        graph.components = MainComponents::new(&graph);
+        Ok(graph)
+    }
 
+    /// Calculates the coverage of all edges for a new set of `towers`
+    /// without changing the graph. `towers` are sorted for the tower
+    /// grid.
+    pub fn tower_coverage(
+        &self,
+        towers: &mut Vec<Tower>,
+        providers: ProviderRegistry,
+        options: &CoverageOptions,
+    ) -> Result<TowerCoverage, Error> {
+        TowerCoverage::calculate(&self.shapes, towers, providers, options)
+    }
+
+    /// Replaces everything derived from the towers with `c` and blends
+    /// the drive test measurements into the new coverage
+    pub fn set_tower_coverage(&mut self, c: TowerCoverage) {
+        self.tower_grid = c.tower_grid;
+        self.coverage = c.coverage;
+        self.segment_coverage = c.segment_coverage;
+        self.signal = c.signal;
+        self.providers = c.providers;
+        let mut segments: Vec<usize> = self.calibration.iter().map(|m| m.segment).collect();
+        segments.sort();
+        segments.dedup();
+        let shapes = self.apply_calibration(&segments);
+        let edges = self.edges_along(&shapes);
+        self.refresh_edge_coverage(&edges);
+    }
+
+    /// Blends `measurements` into the coverage of their subsegments,
+    /// which has to be the modeled one, and keeps them
+    pub fn add_calibration(&mut self, measurements: Vec<Measurement>) {
+        let mut segments: Vec<usize> = measurements.iter().map(|m| m.segment).collect();
+        segments.sort();
+        segments.dedup();
+        self.calibration.extend(measurements);
+        let shapes = self.apply_calibration(&segments);
+        let edges = self.edges_along(&shapes);
+        self.refresh_edge_coverage(&edges);
+    }
+
+    /// Blends the stored measurements of the sorted `segments` into
+    /// their modeled coverage. Returns the shapes of the changed
+    /// subsegments, the coverage of their edges is not updated.
+    fn apply_calibration(&mut self, segments: &[usize]) -> Vec<usize> {
+        let mut measurements: Vec<Measurement> = self.calibration
+            .iter()
+            .filter(|m| segments.binary_search(&m.segment).is_ok())
+            .cloned()
+            .collect();
+        measurements.sort_by_key(|m| m.segment);
+        let mut shapes = Vec::new();
+        let mut i = 0;
+        while i < measurements.len() {
+            let n = measurements[i].segment;
+            let mut edge = self.segment_coverage.edge(&self.providers, n);
+            let mut values = Vec::new();
+            while i < measurements.len() && measurements[i].segment == n {
+                let m = &measurements[i];
+                if let Some(p) = self.providers.index_of(&m.provider) {
+                    values.push((p, m.blend(edge.providers[p][0])));
+                }
+                i += 1;
+            }
+            edge.override_any(&values);
+            self.segment_coverage.set_edge(&self.providers, n, &edge);
+            shapes.push(self.shapes.shape_of_segment(n));
+        }
+        shapes.dedup();
+        shapes
     }
 
     /// Returns an iterator over HalfEdges going out of node with ID id.
@@ -176,7 +327,7 @@ impl Graph {
             start: self.node_offsets[id].0,
             stop: self.node_offsets[id + 1].0,
             position: self.node_offsets[id].0,
-            edges: &self.edges,
+            edges: &self.edges[..],
         }
     }
 
@@ -187,8 +338,6 @@ impl Graph {
         node_count: usize,
         edges: &mut Vec<EdgeInfo>,
     ) -> (Vec<NodeOffset>, Vec<HalfEdge>) {
-        use std::cmp::Ordering;
-
         fn calc_offset_inner(edges: &[EdgeInfo], node_offsets: &mut Vec<NodeOffset>) {
 
             let mut last_id = 0;
@@ -208,12 +357,13 @@ impl Graph {
         let mut node_offsets = vec![NodeOffset::new(0); node_count + 1];
 
         edges.sort_by(|a, b| {
-            let ord = a.source.cmp(&b.source);
-            match ord {
-                Ordering::Equal => a.dest.cmp(&b.dest),
-                _ => ord,
-            }
+            a.source
+                .cmp(&b.source)
+                .then(a.dest.cmp(&b.dest))
+                .then_with(|| a.length.partial_cmp(&b.length).unwrap())
         });
+        // of parallel edges only the shortest is kept, the contraction
+        // of ways splits parallel chains so these have no shape points
         edges.dedup_by_key(|edge| (edge.source, edge.dest));
 
         calc_offset_inner(edges, &mut node_offsets);
@@ -230,8 +380,10 @@ impl Graph {
                     endpoint: e.dest,
                     length: e.length,
                     time: e.length / e.speed as f64,
-                    for_cars: e.for_cars,
-                    for_pedestrians: e.for_pedestrians,
+                    for_cars: e.for_cars as u8,
+                    for_pedestrians: e.for_pedestrians as u8,
+                    road_class: e.road_class.index(),
+                    _padding: [0; 5],
                 }
             })
             .collect()
@@ -241,7 +393,47 @@ impl Graph {
         self.node_offsets.len()
     }
 
-    fn rename_node_ids_and_calculate_distance(nodes: &[NodeInfo], edges: &mut [EdgeInfo]) {
+    /// Checks that the arrays of a graph read from a file fit together,
+    /// with `tower_count` towers next to it
+    pub fn validate(&self, tower_count: usize) -> Result<(), Error> {
+        let invalid = |what: &str| Err(Error::InvalidGraphFile(format!("invalid {}", what)));
+        let nodes = self.node_info.len();
+        let offsets = &self.node_offsets;
+        if offsets.len() != nodes + 1 || offsets.first().map_or(true, |o| o.0 != 0) ||
+            offsets.windows(2).any(|w| w[0].0 > w[1].0) ||
+            offsets.last().map(|o| o.0) != Some(self.edges.len())
+        {
+            return invalid("node offsets");
+        }
+        let classes = RoadClass::all().len() as u8;
+        for edge in self.edges.iter() {
+            if edge.endpoint >= nodes || edge.for_cars > 1 || edge.for_pedestrians > 1 ||
+                edge.road_class >= classes
+            {
+                return invalid("edges");
+            }
+        }
+        if !self.grid.is_valid_for(nodes) || !self.tower_grid.is_valid_for(tower_count) {
+            return invalid("grid");
+        }
+        if !self.shapes.is_valid_for(self.edges.len()) {
+            return invalid("edge shapes");
+        }
+        if !self.coverage.covers(self.edges.len()) ||
+            !self.segment_coverage.covers(self.shapes.segment_count())
+        {
+            return invalid("coverage");
+        }
+        let segments = self.shapes.segment_count();
+        if self.calibration.iter().any(|m| m.segment >= segments) {
+            return invalid("calibration");
+        }
+        Ok(())
+    }
+
+    /// Edges whose end points are missing in `nodes` are dropped with
+    /// a warning
+    fn rename_node_ids_and_calculate_distance(nodes: &[NodeInfo], edges: &mut Vec<EdgeInfo>) {
         use std::collections::hash_map::HashMap;
 
         let map: HashMap<OsmNodeId, (usize, &NodeInfo)> = nodes
@@ -249,46 +441,237 @@ impl Graph {
             .enumerate()
             .map(|n| (n.1.osm_id, n))
             .collect();
+        let count = edges.len();
+        edges.retain(|e| map.contains_key(&e.source) && map.contains_key(&e.dest));
+        if edges.len() < count {
+            println!(
+                "Warning: skipped {} of {} edges with missing end nodes",
+                count - edges.len(),
+                count
+            );
+        }
         edges.par_iter_mut().for_each(|e| {
             let (source_id, source) = map[&e.source];
             let (dest_id, dest) = map[&e.dest];
             e.source = source_id;
             e.dest = dest_id;
-            e.length = haversine_distance(source, dest);
+            let mut length = 0.0;
+            let mut last = source;
+            for point in e.shape.iter().chain(Some(dest)) {
+                length += haversine_distance(last, point);
+                last = point;
+            }
+            e.length = length;
         });
 
     }
 
-    pub fn calculate_coverage(
-        nodes: &[NodeInfo],
-        edges: &mut Vec<EdgeInfo>,
-        towers: &mut Vec<Tower>,
-    ) -> Coverage {
-
-        let grid = Grid::new(towers, 100);
-        let coverage = Coverage::new(edges.len());
-
-        edges.par_iter_mut().enumerate().for_each(|(n, e)| {
-            let source = &nodes[e.source];
-            let dest = &nodes[e.dest];
-            let (tele, voda, o2) = edge_coverage(
-                source,
-                dest,
-                grid.adjacent_towers(source, 15000.0, towers)
-                    .unwrap_or_default(),
-            );
+    /// Recalculates coverage and signal levels of all edges that are
+    /// within range of one of the `changed` towers, given by their
+    /// index in `towers`. Returns the number of updated edges.
+    pub fn update_coverage(
+        &mut self,
+        towers: &[Tower],
+        changed: &[usize],
+        options: &CoverageOptions,
+    ) -> usize {
+        let mut affected = Vec::new();
+        for tower in changed.iter().map(|&i| &towers[i]) {
+            for (r, _, _) in self.shapes.segments_near(tower, tower.range) {
+                affected.push((r.shape, r.segment));
+            }
+        }
+        affected.sort();
+        affected.dedup();
+
+        {
+            let source = options.source(towers, &self.tower_grid);
+            for &(_, n) in &affected {
+                let (s, t) = self.shapes.segment_points(n);
+                let coverage = source.edge_coverage(&s, &t, &self.providers);
+                self.segment_coverage.set_edge(&self.providers, n, &coverage);
+            }
+        }
+        let mut segments: Vec<usize> = affected.iter().map(|&(_, n)| n).collect();
+        segments.sort();
+        self.apply_calibration(&segments);
+        let mut shapes: Vec<usize> = affected.into_iter().map(|(shape, _)| shape).collect();
+        shapes.dedup();
+        let edges = self.edges_along(&shapes);
+        self.refresh_edge_coverage(&edges);
+
+        if let (Some(signal), Some(model)) = (self.signal.as_mut(), options.signal_model) {
+            let towers = TowerSource {
+                towers,
+                grid: &self.tower_grid,
+                los: None,
+            };
+            for &edge in &edges {
+                let levels = polyline_signal(
+                    &self.shapes.points(edge),
+                    |n| towers.adjacent(n),
+                    &self.providers,
+                    model,
+                );
+                signal.set_edge(&self.providers, edge, &levels);
+            }
+        }
+        edges.len()
+    }
 
-            coverage.set(&Provider::Telekom, n, tele);
-            coverage.set(&Provider::Vodafone, n, voda);
-            coverage.set(&Provider::O2, n, o2);
-        });
+    /// The edges in both directions along `shapes`
+    pub fn edges_along(&self, shapes: &[usize]) -> Vec<usize> {
+        shapes
+            .iter()
+            .flat_map(|&shape| self.shapes.shape_edges(shape))
+            .collect()
+    }
 
-        coverage
+    /// Recalculates the coverage of `edges` from the coverage of
+    /// their subsegments
+    pub fn refresh_edge_coverage(&mut self, edges: &[usize]) {
+        for &edge in edges {
+            let weights = self.shapes.segment_weights(edge);
+            self.coverage.set_mean(edge, &self.segment_coverage, &weights);
+        }
     }
 
-    pub fn next_node_to(&self, lat: f64, long: f64) -> Option<NodeInfoWithIndex> {
-        self.grid.nearest_neighbor(lat, long, &self.node_info).ok()
+    /// The node `edge` goes out of
+    pub fn edge_source(&self, edge: usize) -> NodeId {
+        // the last node whose edges start at or before `edge`
+        let mut low = 0;
+        let mut high = self.node_info.len();
+        while high - low > 1 {
+            let middle = (low + high) / 2;
+            if self.node_offsets[middle].0 <= edge {
+                low = middle;
+            } else {
+                high = middle;
+            }
+        }
+        low
     }
+
+    /// Index of the edge from `source` to `target`
+    pub fn edge_between(&self, source: NodeId, target: NodeId) -> Option<usize> {
+        self.outgoing_edges_for(source)
+            .find(|&(_, e)| e.endpoint == target)
+            .map(|(n, _)| n)
+    }
+
+    /// All points along the path through `nodes` including the shape
+    /// points of the edges in between
+    pub fn path_points<'b, I>(&self, nodes: I) -> Vec<NodeInfo>
+    where
+        I: IntoIterator<Item = &'b NodeId>,
+    {
+        let mut points = Vec::new();
+        let mut last: Option<NodeId> = None;
+        for &node in nodes {
+            match last.and_then(|prev| self.edge_between(prev, node)) {
+                Some(edge) => points.extend(self.shapes.points(edge).into_iter().skip(1)),
+                None => points.push(self.node_info[node].clone()),
+            }
+            last = Some(node);
+        }
+        points
+    }
+
+    /// Factor of the walking speed along edge `n`. Every subsegment is
+    /// walked at the speed given by `tobler_factor` for its grade, so
+    /// walking the edge takes as long as walking all its subsegments.
+    pub fn walking_factor(&self, n: usize) -> f64 {
+        let mut length = 0.0;
+        let mut time = 0.0;
+        for pair in self.shapes.points(n).windows(2) {
+            let segment_length = haversine_distance(&pair[0], &pair[1]);
+            if segment_length <= 0.0 {
+                continue;
+            }
+            let grade = (pair[1].height as f64 - pair[0].height as f64) / segment_length;
+            length += segment_length;
+            time += segment_length / tobler_factor(grade);
+        }
+        if time > 0.0 { length / time } else { 1.0 }
+    }
+
+    /// A node that is part of the main component for `movement`, so
+    /// routes to all other such nodes exist. Coordinates next to a
+    /// road snap to the closer end of the road, even if the junction
+    /// of another road is closer. Further away than `SNAP_DISTANCE`
+    /// from all roads the closest node is used.
+    pub fn next_node_to(
+        &self,
+        lat: f64,
+        long: f64,
+        movement: &Movement,
+    ) -> Option<NodeInfoWithIndex> {
+        let road = self.edges_near(lat, long, SNAP_DISTANCE)
+            .into_iter()
+            .find(|p| {
+                let edge = &self.edges[p.edge];
+                !edge.is_not_for(movement) && self.components.contains(p.source, movement) &&
+                    self.components.contains(edge.endpoint, movement)
+            });
+        if let Some(p) = road {
+            let node = if p.fraction < 0.5 {
+                p.source
+            } else {
+                self.edges[p.edge].endpoint
+            };
+            return Some(NodeInfoWithIndex(node, self.node_info[node].clone()));
+        }
+        self.grid
+            .nearest_neighbor_where(lat, long, &self.node_info, |n| {
+                self.components.contains(n, movement)
+            })
+            .ok()
+    }
+
+    /// All edges that pass within `max_dist` meters of the given
+    /// coordinate, closest first
+    pub fn edges_near(&self, lat: f64, long: f64, max_dist: f64) -> Vec<EdgePosition> {
+        let mut result = Vec::new();
+        for (r, fraction, distance) in self.shapes.segments_near(&(lat, long), max_dist) {
+            for edge in self.shapes.shape_edges(r.shape) {
+                let fraction = match self.shapes.shape(edge) {
+                    (_, true) => 1.0 - fraction,
+                    (_, false) => fraction,
+                };
+                result.push(EdgePosition {
+                    edge,
+                    source: self.edge_source(edge),
+                    segment: r.segment,
+                    fraction: self.shapes.edge_fraction(edge, r.segment, fraction),
+                    distance,
+                });
+            }
+        }
+        result.sort_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap());
+        let mut seen = HashSet::new();
+        result.retain(|p| seen.insert(p.edge));
+        result
+    }
+
+    /// The edge closest to the given coordinate if it is at most
+    /// `max_dist` meters away
+    pub fn nearest_edge(&self, lat: f64, long: f64, max_dist: f64) -> Option<EdgePosition> {
+        self.edges_near(lat, long, max_dist).into_iter().next()
+    }
+}
+
+/// Position on an edge a coordinate was snapped to
+#[derive(Debug, Clone, Copy)]
+pub struct EdgePosition {
+    pub edge: usize,
+    /// Node the edge goes out of
+    pub source: NodeId,
+    /// Subsegment of the edge closest to the coordinate
+    pub segment: usize,
+    /// Position along the edge as fraction of its length
+    pub fraction: f64,
+    /// Distance in meters between the coordinate and the edge
+    pub distance: f64,
 }
 #[test]
 fn graph_creation() {
@@ -310,7 +693,9 @@ fn graph_creation() {
             EdgeInfo::new(53, 78, 1.0, 1),
         ],
         &mut towers,
-    );
+        Default::default(),
+        &Default::default(),
+    ).unwrap();
     let exp = vec![
         NodeOffset::new(0),
         NodeOffset::new(3),
@@ -320,7 +705,7 @@ fn graph_creation() {
         NodeOffset::new(5),
     ];
     assert_eq!(g.node_offsets.len(), exp.len());
-    assert_eq!(g.node_offsets, exp);
+    assert_eq!(&g.node_offsets[..], &exp[..]);
 
     assert_eq!(g.outgoing_edges_for(0).len(), 3);
     let mut iter = g.outgoing_edges_for(2);
@@ -331,8 +716,10 @@ fn graph_creation() {
                 endpoint: 3,
                 length: 0.0,
                 time: 0.0,
-                for_cars: true,
-                for_pedestrians: true,
+                for_cars: 1,
+                for_pedestrians: 1,
+                road_class: RoadClass::Other.index(),
+                _padding: [0; 5],
             },
         )),
         iter.next()
@@ -344,8 +731,10 @@ fn graph_creation() {
                 endpoint: 4,
                 length: 15718.742925384355,
                 time: 15718.742925384355,
-                for_cars: true,
-                for_pedestrians: true,
+                for_cars: 1,
+                for_pedestrians: 1,
+                road_class: RoadClass::Other.index(),
+                _padding: [0; 5],
             },
         )),
         iter.next()
@@ -359,7 +748,7 @@ pub struct EdgeIter<'a> {
     start: usize,
     stop: usize,
     position: usize,
-    edges: &'a Vec<HalfEdge>,
+    edges: &'a [HalfEdge],
 }
 
 impl<'a> Iterator for EdgeIter<'a> {
@@ -381,20 +770,247 @@ impl<'a> EdgeIter<'a> {
     }
 }
 
-pub fn load_preprocessed_graph<P: AsRef<Path>>(path: P) -> super::ApplicationState {
-    use std::fs::File;
-    use std::io::BufReader;
-
-    let start = Instant::now();
-    let mut reader = BufReader::new(File::open(path).expect(
-        "Preprocessed Graph file could not be opened",
-    ));
-    let g = bincode::deserialize_from(&mut reader, bincode::Infinite)
-        .expect("Could not deserialize preprocessed graph");
-    let end = Instant::now();
-    println!(
-        "loaded preprocessed graph in {:?}",
-        end.duration_since(start)
-    );
-    g
+/// Everything in a `Graph` that is derived from the towers
+pub struct TowerCoverage {
+    tower_grid: Grid,
+    coverage: Coverage,
+    segment_coverage: Coverage,
+    signal: Option<SignalLevels>,
+    providers: ProviderRegistry,
+}
+
+impl TowerCoverage {
+    fn calculate(
+        shapes: &EdgeShapes,
+        towers: &mut Vec<Tower>,
+        providers: ProviderRegistry,
+        options: &CoverageOptions,
+    ) -> Result<TowerCoverage, Error> {
+        let tower_grid = Grid::new(towers, 100)?;
+        let towers: &[Tower] = towers;
+        let segment_coverage = {
+            let source = options.source(towers, &tower_grid);
+            Coverage::calculate(&providers, shapes.segment_count(), |n| {
+                let (s, t) = shapes.segment_points(n);
+                source.edge_coverage(&s, &t, &providers)
+            })
+        };
+        let coverage = segment_coverage.mean(shapes.edge_count(), |e| shapes.segment_weights(e));
+        let signal = options.signal_model.map(|model| {
+            let towers = TowerSource {
+                towers,
+                grid: &tower_grid,
+                los: None,
+            };
+            SignalLevels::calculate(shapes.edge_count(), &providers, |e| {
+                polyline_signal(&shapes.points(e), |n| towers.adjacent(n), &providers, model)
+            })
+        });
+        Ok(TowerCoverage {
+            tower_grid,
+            coverage,
+            segment_coverage,
+            signal,
+            providers,
+        })
+    }
+}
+
+#[cfg(test)]
+impl Graph {
+    /// Three nodes in a row at latitude 52, 0.01 degrees apart,
+    /// connected by `edges` and covered by a tower of provider 262-01,
+    /// named O2, with a range of 1 km at the first node
+    pub fn test(edges: Vec<EdgeInfo>) -> Graph {
+        use std::collections::HashMap;
+
+        // the other towers only span the tower grid over all nodes
+        let mut towers = vec![
+            Tower::test(1, 51.99, 12.99, 1.0),
+            Tower::test(1, 52.0, 13.0, 1000.0),
+            Tower::test(1, 52.01, 13.03, 1.0),
+        ];
+        let mut names = HashMap::new();
+        names.insert(towers[0].provider(), "O2".to_string());
+        let providers = ProviderRegistry::new(&towers, &names);
+        Graph::new(
+            vec![
+                NodeInfo::new(1, 52.0, 13.0, 0),
+                NodeInfo::new(2, 52.0, 13.01, 0),
+                NodeInfo::new(3, 52.0, 13.02, 0),
+            ],
+            edges,
+            &mut towers,
+            providers,
+            &Default::default(),
+        ).unwrap()
+    }
+}
+
+#[test]
+fn disabling_towers_updates_coverage() {
+    use provider::{Provider, ProviderSelection};
+    use std::collections::HashMap;
+
+    let mut towers = vec![
+        Tower::test(2, 51.99, 12.99, 100.0),
+        Tower::test(1, 52.001, 13.005, 1000.0),
+        Tower::test(2, 52.01, 13.02, 100.0),
+    ];
+    let providers = ProviderRegistry::new(&towers, &HashMap::new());
+    let mut g = Graph::new(
+        vec![
+            NodeInfo::new(1, 52.0, 13.0, 0),
+            NodeInfo::new(2, 52.002, 13.01, 0),
+        ],
+        vec![EdgeInfo::new(1, 2, 1.0, 1)],
+        &mut towers,
+        providers,
+        &Default::default(),
+    ).unwrap();
+    let selection = Some(ProviderSelection::Single(Provider::new(262, 1)));
+    let covered = |g: &Graph| g.coverage.get_all(selection, TechClass::Any).unwrap().get(0);
+    assert_eq!(1.0, covered(&g));
+
+    let index = towers.iter().position(|t| t.net == 1).unwrap();
+    towers[index].disabled = true;
+    assert_eq!(1, g.update_coverage(&towers, &[index], &Default::default()));
+    assert_eq!(0.0, covered(&g));
+}
+
+#[test]
+fn calibration_survives_coverage_updates() {
+    use provider::{Provider, ProviderSelection};
+    use std::collections::HashMap;
+
+    let mut towers = vec![
+        Tower::test(2, 51.99, 12.99, 100.0),
+        Tower::test(1, 52.001, 13.005, 1000.0),
+        Tower::test(2, 52.01, 13.02, 100.0),
+    ];
+    let providers = ProviderRegistry::new(&towers, &HashMap::new());
+    let mut g = Graph::new(
+        vec![
+            NodeInfo::new(1, 52.0, 13.0, 0),
+            NodeInfo::new(2, 52.002, 13.01, 0),
+        ],
+        vec![EdgeInfo::new(1, 2, 1.0, 1)],
+        &mut towers,
+        providers,
+        &Default::default(),
+    ).unwrap();
+    let covered = |g: &Graph, selection| {
+        g.coverage.get_all(Some(selection), TechClass::Any).unwrap().get(0)
+    };
+    let single = ProviderSelection::Single(Provider::new(262, 1));
+    assert_eq!(1.0, covered(&g, single));
+
+    // measured without signal, which overrides the model
+    g.add_calibration(vec![
+        Measurement {
+            provider: Provider::new(262, 1),
+            segment: 0,
+            measured: 0.0,
+            confidence: 1.0,
+        },
+    ]);
+    assert_eq!(0.0, covered(&g, single));
+    assert_eq!(0.0, covered(&g, ProviderSelection::AtLeast(1)));
+
+    let index = towers.iter().position(|t| t.net == 1).unwrap();
+    g.update_coverage(&towers, &[index], &Default::default());
+    assert_eq!(0.0, covered(&g, single));
+
+    let providers = ProviderRegistry::new(&towers, &HashMap::new());
+    let coverage = g.tower_coverage(&mut towers, providers, &Default::default()).unwrap();
+    g.set_tower_coverage(coverage);
+    assert_eq!(0.0, covered(&g, single));
+    assert_eq!(0.0, covered(&g, ProviderSelection::AtLeast(1)));
+}
+
+#[test]
+fn edges_to_missing_nodes_are_skipped() {
+    let mut towers = Vec::new();
+    let g = Graph::new(
+        vec![
+            NodeInfo::new(1, 52.0, 13.0, 0),
+            NodeInfo::new(2, 52.001, 13.0, 0),
+        ],
+        vec![EdgeInfo::new(1, 2, 1.0, 50), EdgeInfo::new(2, 3, 1.0, 50)],
+        &mut towers,
+        Default::default(),
+        &Default::default(),
+    ).unwrap();
+    assert_eq!(1, g.edges.len());
+
+    let nodes = vec![NodeInfo::new(1, ::std::f64::NAN, 13.0, 0)];
+    let g = Graph::new(nodes, Vec::new(), &mut towers, Default::default(), &Default::default());
+    assert!(g.is_err());
+}
+
+#[test]
+fn flat_types_have_no_padding() {
+    use flat::layout_size;
+    use self::shape::{ShapePoint, ShapeEdges};
+    use std::mem::size_of;
+
+    assert_eq!(layout_size(NodeInfo::LAYOUT), size_of::<NodeInfo>());
+    assert_eq!(layout_size(HalfEdge::LAYOUT), size_of::<HalfEdge>());
+    assert_eq!(layout_size(NodeOffset::LAYOUT), size_of::<NodeOffset>());
+    assert_eq!(layout_size(SegmentRef::LAYOUT), size_of::<SegmentRef>());
+    assert_eq!(layout_size(ShapePoint::LAYOUT), size_of::<ShapePoint>());
+    assert_eq!(layout_size(ShapeEdges::LAYOUT), size_of::<ShapeEdges>());
+}
+
+#[test]
+fn snapping_to_the_middle_of_a_road() {
+    let mut towers = Vec::new();
+    let mut road = EdgeInfo::new(1, 2, 1.0, 50);
+    road.shape = vec![NodeInfo::new(10, 52.0, 13.05, 0)];
+    let mut back = EdgeInfo::new(2, 1, 1.0, 50);
+    back.shape = road.shape.clone();
+    let g = Graph::new(
+        vec![
+            NodeInfo::new(1, 52.0, 13.0, 0),
+            NodeInfo::new(2, 52.0, 13.1, 0),
+            NodeInfo::new(3, 52.003, 13.06, 0),
+        ],
+        vec![
+            road,
+            back,
+            EdgeInfo::new(1, 3, 1.0, 50),
+            EdgeInfo::new(3, 1, 1.0, 50),
+        ],
+        &mut towers,
+        Default::default(),
+        &Default::default(),
+    ).unwrap();
+    // node 3 is closer, but the long road passes right by
+    let node = g.next_node_to(52.0005, 13.06, &Movement::Car).unwrap();
+    assert_eq!(2, node.1.osm_id);
+}
+
+#[test]
+fn walking_over_a_hill_takes_longer() {
+    let mut towers = Vec::new();
+    // both roads connect nodes at the same height, but one of them
+    // crosses a hill
+    let mut hill = EdgeInfo::new(1, 2, 1.0, 5);
+    hill.shape = vec![NodeInfo::new(10, 52.0, 13.005, 50)];
+    let g = Graph::new(
+        vec![
+            NodeInfo::new(1, 52.0, 13.0, 0),
+            NodeInfo::new(2, 52.0, 13.01, 0),
+            NodeInfo::new(3, 52.01, 13.0, 0),
+            NodeInfo::new(4, 52.01, 13.01, 0),
+        ],
+        vec![hill, EdgeInfo::new(3, 4, 1.0, 5)],
+        &mut towers,
+        Default::default(),
+        &Default::default(),
+    ).unwrap();
+    for n in 0..g.edges.len() {
+        let over_hill = g.shapes.points(n).len() == 3;
+        assert_eq!(over_hill, g.walking_factor(n) < 1.0);
+    }
 }
@@ -0,0 +1,96 @@
+/// Class of a road after the `highway` tag of its way. Links count
+/// as the class of the road they belong to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, HeapSizeOf)]
+pub enum RoadClass {
+    Motorway,
+    Trunk,
+    Primary,
+    Secondary,
+    Tertiary,
+    Unclassified,
+    Residential,
+    LivingStreet,
+    Service,
+    Track,
+    /// Footways, cycleways and other paths
+    Path,
+    Other,
+}
+
+const CLASSES: [RoadClass; 12] = [
+    RoadClass::Motorway,
+    RoadClass::Trunk,
+    RoadClass::Primary,
+    RoadClass::Secondary,
+    RoadClass::Tertiary,
+    RoadClass::Unclassified,
+    RoadClass::Residential,
+    RoadClass::LivingStreet,
+    RoadClass::Service,
+    RoadClass::Track,
+    RoadClass::Path,
+    RoadClass::Other,
+];
+
+impl RoadClass {
+    pub fn all() -> &'static [RoadClass; 12] {
+        &CLASSES
+    }
+
+    pub fn from_highway(tag: &str) -> RoadClass {
+        match tag.trim_right_matches("_link") {
+            "motorway" => RoadClass::Motorway,
+            "trunk" => RoadClass::Trunk,
+            "primary" => RoadClass::Primary,
+            "secondary" => RoadClass::Secondary,
+            "tertiary" => RoadClass::Tertiary,
+            "unclassified" => RoadClass::Unclassified,
+            "residential" => RoadClass::Residential,
+            "living_street" => RoadClass::LivingStreet,
+            "service" => RoadClass::Service,
+            "track" => RoadClass::Track,
+            "path" | "footway" | "cycleway" | "bridleway" | "steps" | "pedestrian" => {
+                RoadClass::Path
+            }
+            _ => RoadClass::Other,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            RoadClass::Motorway => "motorway",
+            RoadClass::Trunk => "trunk",
+            RoadClass::Primary => "primary",
+            RoadClass::Secondary => "secondary",
+            RoadClass::Tertiary => "tertiary",
+            RoadClass::Unclassified => "unclassified",
+            RoadClass::Residential => "residential",
+            RoadClass::LivingStreet => "living_street",
+            RoadClass::Service => "service",
+            RoadClass::Track => "track",
+            RoadClass::Path => "path",
+            RoadClass::Other => "other",
+        }
+    }
+
+    /// Position in `all`, as stored in the edges of the graph
+    pub fn index(&self) -> u8 {
+        CLASSES.iter().position(|c| c == self).unwrap() as u8
+    }
+
+    /// Reverses `index`, unknown indices become `Other`
+    pub fn from_index(index: u8) -> RoadClass {
+        CLASSES.get(index as usize).cloned().unwrap_or(RoadClass::Other)
+    }
+}
+
+#[test]
+fn road_classes_from_highway_tags() {
+    assert_eq!(RoadClass::Primary, RoadClass::from_highway("primary_link"));
+    assert_eq!(RoadClass::Path, RoadClass::from_highway("cycleway"));
+    assert_eq!(RoadClass::Other, RoadClass::from_highway("raceway"));
+    for class in RoadClass::all() {
+        assert_eq!(*class, RoadClass::from_index(class.index()));
+    }
+    assert_eq!(RoadClass::Other, RoadClass::from_index(200));
+}
@@ -0,0 +1,400 @@
+use super::{EdgeInfo, NodeId, NodeInfo};
+use grid::Grid;
+use error::Error;
+use flat::{Flat, Store};
+use geom::{Coord, haversine_distance, closest_on_segment};
+
+use std::collections::HashMap;
+use std::f64;
+use std::ops::Range;
+use std::usize;
+
+/// Coordinates of shape points are stored in 1e-7 degrees like in OSM
+const SCALE: f64 = 10_000_000.0;
+/// Marks a direction of a shape without edge
+const NO_EDGE: usize = usize::MAX;
+
+/// A point of a shape, stored in a quarter of the size of a `NodeInfo`
+#[derive(Debug, Clone, Copy, PartialEq, HeapSizeOf, Serialize, Deserialize)]
+#[repr(C)]
+pub struct ShapePoint {
+    lat: i32,
+    long: i32,
+    height: i16,
+    #[ignore_heap_size_of = "padding"]
+    #[serde(skip_serializing, skip_deserializing)]
+    _padding: [u8; 2],
+}
+
+unsafe impl Flat for ShapePoint {
+    const LAYOUT: &'static str = "i32,i32,i16,[u8;2]";
+}
+
+impl ShapePoint {
+    fn new(n: &NodeInfo) -> Result<ShapePoint, Error> {
+        if !(n.lat.abs() <= 90.0 && n.long.abs() <= 180.0) {
+            return Err(Error::InvalidCoordinate(n.lat, n.long));
+        }
+        Ok(ShapePoint {
+            lat: (n.lat * SCALE).round() as i32,
+            long: (n.long * SCALE).round() as i32,
+            height: n.height,
+            _padding: [0; 2],
+        })
+    }
+
+    /// The point as node without OSM id
+    pub fn node(&self) -> NodeInfo {
+        NodeInfo::new(
+            0,
+            f64::from(self.lat) / SCALE,
+            f64::from(self.long) / SCALE,
+            self.height,
+        )
+    }
+}
+
+/// The edges running along a shape in and against its direction
+#[derive(Debug, Clone, Copy, PartialEq, HeapSizeOf, Serialize, Deserialize)]
+#[repr(C)]
+pub struct ShapeEdges {
+    forward: usize,
+    backward: usize,
+}
+
+unsafe impl Flat for ShapeEdges {
+    const LAYOUT: &'static str = "usize,usize";
+}
+
+/// A straight part of a shape between two of its points
+#[derive(Debug, Clone, Copy, PartialEq, HeapSizeOf, Serialize, Deserialize)]
+#[repr(C)]
+pub struct SegmentRef {
+    pub shape: usize,
+    /// Index of the subsegment among the subsegments of all shapes
+    pub segment: usize,
+}
+
+unsafe impl Flat for SegmentRef {
+    const LAYOUT: &'static str = "usize,usize";
+}
+
+struct SegmentStart {
+    lat: f64,
+    long: f64,
+    segment: SegmentRef,
+}
+
+impl Coord for SegmentStart {
+    #[inline]
+    fn lat(&self) -> f64 {
+        self.lat
+    }
+    #[inline]
+    fn lon(&self) -> f64 {
+        self.long
+    }
+}
+
+/// Geometry of contracted edges.
+///
+/// Every edge runs along a polyline from its source to its target node
+/// with the nodes of the contracted chain in between. Both directions
+/// of a road share the same shape, edge `e` runs along shape
+/// `edge_shapes[e] >> 1`, against its direction if the lowest bit is
+/// set. Shape `s` consists of `points[offsets[s]..offsets[s + 1]]`, its
+/// subsegments are numbered consecutively over all shapes.
+#[derive(HeapSizeOf, Serialize, Deserialize)]
+pub struct EdgeShapes {
+    edge_shapes: Store<usize>,
+    shape_edges: Store<ShapeEdges>,
+    offsets: Store<usize>,
+    points: Store<ShapePoint>,
+    /// Spatial index over the start points of all subsegments
+    grid: Grid,
+    segments: Store<SegmentRef>,
+    max_segment_length: f64,
+}
+
+impl EdgeShapes {
+    /// Collects the shapes of `edges` whose end points are already
+    /// renamed to indices into `nodes`. An edge shares the shape of
+    /// the edge in the opposite direction if it has the same points.
+    pub fn new(nodes: &[NodeInfo], edges: &[EdgeInfo]) -> Result<EdgeShapes, Error> {
+        let mut edge_shapes = Vec::with_capacity(edges.len());
+        let mut shape_edges: Vec<ShapeEdges> = Vec::new();
+        let mut offsets = vec![0];
+        let mut points = Vec::new();
+        // shapes by their first and last node
+        let mut shapes: HashMap<(NodeId, NodeId), usize> = HashMap::new();
+        for (edge, e) in edges.iter().enumerate() {
+            let mut polyline = Vec::with_capacity(e.shape.len() + 2);
+            polyline.push(ShapePoint::new(&nodes[e.source])?);
+            for n in &e.shape {
+                polyline.push(ShapePoint::new(n)?);
+            }
+            polyline.push(ShapePoint::new(&nodes[e.dest])?);
+
+            let reverse = match shapes.get(&(e.dest, e.source)) {
+                Some(&shape) if shape_edges[shape].backward == NO_EDGE => {
+                    let stored = &points[offsets[shape]..offsets[shape + 1]];
+                    if stored.iter().rev().eq(polyline.iter()) {
+                        Some(shape)
+                    } else {
+                        None
+                    }
+                }
+                _ => None,
+            };
+            match reverse {
+                Some(shape) => {
+                    shape_edges[shape].backward = edge;
+                    edge_shapes.push(shape << 1 | 1);
+                }
+                None => {
+                    let shape = shape_edges.len();
+                    shapes.insert((e.source, e.dest), shape);
+                    shape_edges.push(ShapeEdges {
+                        forward: edge,
+                        backward: NO_EDGE,
+                    });
+                    edge_shapes.push(shape << 1);
+                    points.extend(polyline);
+                    offsets.push(points.len());
+                }
+            }
+        }
+
+        let mut starts = Vec::with_capacity(points.len() - shape_edges.len());
+        let mut max_segment_length: f64 = 0.0;
+        for shape in 0..shape_edges.len() {
+            let polyline = &points[offsets[shape]..offsets[shape + 1]];
+            for (i, pair) in polyline.windows(2).enumerate() {
+                let (s, t) = (pair[0].node(), pair[1].node());
+                max_segment_length = max_segment_length.max(haversine_distance(&s, &t));
+                starts.push(SegmentStart {
+                    lat: s.lat,
+                    long: s.long,
+                    segment: SegmentRef {
+                        shape,
+                        segment: offsets[shape] - shape + i,
+                    },
+                });
+            }
+        }
+        let grid = Grid::new(&mut starts, 100)?;
+
+        Ok(EdgeShapes {
+            edge_shapes: edge_shapes.into(),
+            shape_edges: shape_edges.into(),
+            offsets: offsets.into(),
+            points: points.into(),
+            grid,
+            segments: starts.into_iter().map(|s| s.segment).collect::<Vec<_>>().into(),
+            max_segment_length,
+        })
+    }
+
+    /// Whether the shapes belong to `edge_count` edges and every
+    /// subsegment is indexed
+    pub fn is_valid_for(&self, edge_count: usize) -> bool {
+        let shapes = self.shape_edges.len();
+        let offsets = &self.offsets;
+        if self.edge_shapes.len() != edge_count || offsets.len() != shapes + 1 ||
+            offsets.first() != Some(&0) || offsets.windows(2).any(|w| w[0] + 2 > w[1]) ||
+            offsets.last() != Some(&self.points.len())
+        {
+            return false;
+        }
+        self.edge_shapes.iter().all(|&s| s >> 1 < shapes) &&
+            self.shape_edges.iter().all(|e| {
+                e.forward < edge_count && (e.backward < edge_count || e.backward == NO_EDGE)
+            }) && self.segments.len() == self.segment_count() &&
+            self.segments.iter().all(|r| {
+                r.shape < shapes && {
+                    let range = self.shape_segments(r.shape);
+                    range.start <= r.segment && r.segment < range.end
+                }
+            }) && self.grid.is_valid_for(self.segments.len())
+    }
+
+    pub fn edge_count(&self) -> usize {
+        self.edge_shapes.len()
+    }
+
+    pub fn shape_count(&self) -> usize {
+        self.shape_edges.len()
+    }
+
+    pub fn segment_count(&self) -> usize {
+        self.points.len() - self.shape_count()
+    }
+
+    /// The shape of `edge` and whether the edge runs against its
+    /// direction
+    #[inline]
+    pub fn shape(&self, edge: usize) -> (usize, bool) {
+        let value = self.edge_shapes[edge];
+        (value >> 1, value & 1 == 1)
+    }
+
+    /// The edges along `shape`
+    pub fn shape_edges(&self, shape: usize) -> Vec<usize> {
+        let edges = &self.shape_edges[shape];
+        let mut result = vec![edges.forward];
+        if edges.backward != NO_EDGE {
+            result.push(edges.backward);
+        }
+        result
+    }
+
+    /// All points of `shape` in its direction
+    pub fn shape_points(&self, shape: usize) -> Vec<NodeInfo> {
+        self.points[self.offsets[shape]..self.offsets[shape + 1]]
+            .iter()
+            .map(ShapePoint::node)
+            .collect()
+    }
+
+    /// Indices of the subsegments of `shape`
+    #[inline]
+    pub fn shape_segments(&self, shape: usize) -> Range<usize> {
+        self.offsets[shape] - shape..self.offsets[shape + 1] - shape - 1
+    }
+
+    /// All points of `edge` from its source to its target node
+    pub fn points(&self, edge: usize) -> Vec<NodeInfo> {
+        let (shape, reversed) = self.shape(edge);
+        let mut points = self.shape_points(shape);
+        if reversed {
+            points.reverse();
+        }
+        points
+    }
+
+    /// Indices of the subsegments of `edge` from its source on
+    pub fn segments(&self, edge: usize) -> Vec<usize> {
+        let (shape, reversed) = self.shape(edge);
+        let segments = self.shape_segments(shape);
+        if reversed {
+            segments.rev().collect()
+        } else {
+            segments.collect()
+        }
+    }
+
+    /// The shape subsegment `n` belongs to
+    pub fn shape_of_segment(&self, n: usize) -> usize {
+        // the first subsegment of shape `s` is `offsets[s] - s`
+        let mut low = 0;
+        let mut high = self.shape_count();
+        while high - low > 1 {
+            let middle = (low + high) / 2;
+            if self.offsets[middle] - middle <= n {
+                low = middle;
+            } else {
+                high = middle;
+            }
+        }
+        low
+    }
+
+    /// End points of subsegment `n` in the direction of its shape
+    pub fn segment_points(&self, n: usize) -> (NodeInfo, NodeInfo) {
+        let point = n + self.shape_of_segment(n);
+        (self.points[point].node(), self.points[point + 1].node())
+    }
+
+    /// Subsegments of `edge` with their length as weight. Subsegments
+    /// without length get a tiny weight so every edge has a total
+    /// weight above zero.
+    pub fn segment_weights(&self, edge: usize) -> Vec<(usize, f64)> {
+        let points = self.points(edge);
+        self.segments(edge)
+            .into_iter()
+            .zip(points.windows(2))
+            .map(|(n, pair)| {
+                (n, haversine_distance(&pair[0], &pair[1]).max(f64::EPSILON))
+            })
+            .collect()
+    }
+
+    /// All subsegments that pass within `max_dist` meters of `coord`
+    /// with the fraction of the subsegment in the direction of its
+    /// shape closest to `coord` and its distance
+    pub fn segments_near<C: Coord>(
+        &self,
+        coord: &C,
+        max_dist: f64,
+    ) -> Vec<(SegmentRef, f64, f64)> {
+        let mut result = Vec::new();
+        for cell in self.grid.cells_within(coord, max_dist + self.max_segment_length) {
+            for r in &self.segments[cell] {
+                let point = r.segment + r.shape;
+                let (s, t) = (self.points[point].node(), self.points[point + 1].node());
+                let (fraction, distance) = closest_on_segment(coord, &s, &t);
+                if distance <= max_dist {
+                    result.push((*r, fraction, distance));
+                }
+            }
+        }
+        result
+    }
+
+    /// Converts a position on a subsegment, as fraction in the
+    /// direction of `edge`, to a fraction of the length of the whole
+    /// edge
+    pub fn edge_fraction(&self, edge: usize, segment: usize, fraction: f64) -> f64 {
+        let mut before = 0.0;
+        let mut total = 0.0;
+        let mut reached = false;
+        for (n, length) in self.segment_weights(edge) {
+            if n == segment {
+                before += fraction * length;
+                reached = true;
+            } else if !reached {
+                before += length;
+            }
+            total += length;
+        }
+        before / total
+    }
+}
+
+#[test]
+fn shapes_of_contracted_edges() {
+    let nodes = vec![
+        NodeInfo::new(1, 52.0, 13.0, 0),
+        NodeInfo::new(2, 52.0, 13.01, 0),
+    ];
+    let mut edge = EdgeInfo::new(0, 1, 1.0, 50);
+    edge.shape = vec![
+        NodeInfo::new(3, 52.001, 13.003, 0),
+        NodeInfo::new(4, 52.001, 13.006, 0),
+    ];
+    let mut back = EdgeInfo::new(1, 0, 1.0, 50);
+    back.shape = edge.shape.iter().rev().cloned().collect();
+    let edges = [EdgeInfo::new(1, 0, 1.0, 50), edge, back];
+    let shapes = EdgeShapes::new(&nodes, &edges).unwrap();
+    assert_eq!(3, shapes.edge_count());
+    // the way back shares the shape
+    assert_eq!(2, shapes.shape_count());
+    assert_eq!((1, true), shapes.shape(2));
+    assert_eq!(vec![1, 2], shapes.shape_edges(1));
+    assert_eq!(4, shapes.segment_count());
+    assert_eq!(vec![0], shapes.segments(0));
+    assert_eq!(vec![1, 2, 3], shapes.segments(1));
+    assert_eq!(vec![3, 2, 1], shapes.segments(2));
+    assert_eq!(4, shapes.points(1).len());
+    assert_eq!(52.001, shapes.points(2)[1].lat);
+    assert_eq!(13.006, shapes.points(2)[1].long);
+    assert_eq!(13.003, shapes.segment_points(2).0.long);
+    assert_eq!(1, shapes.shape_of_segment(3));
+
+    let near = shapes.segments_near(&(52.0012, 13.0045), 50.0);
+    assert_eq!(1, near.len());
+    assert_eq!(2, near[0].0.segment);
+    assert!((shapes.edge_fraction(1, 2, near[0].1) - 0.5).abs() < 0.1);
+    assert!((shapes.edge_fraction(2, 2, 1.0 - near[0].1) - 0.5).abs() < 0.1);
+    assert!(shapes.is_valid_for(3));
+}
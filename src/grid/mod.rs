@@ -1,6 +1,10 @@
 use graph::NodeInfo;
 use geom::{Coord, haversine_distance};
 use towers::Tower;
+use error::Error;
+use flat::Store;
+
+use std::ops::Range;
 
 
 mod radius;
@@ -48,14 +52,14 @@ impl BoundingBox {
 pub struct Grid {
     pub b_box: BoundingBox,
     side_length: usize,
-    offset_array: Vec<usize>,
+    offset_array: Store<usize>,
 }
 
 impl Grid {
     /// Creates Grid of size `size` with the data in `coords`. The
     /// values in `coords` will be sorted by their cell index inside
-    /// the grid.
-    pub fn new<C: Coord>(coords: &mut Vec<C>, size: usize) -> Grid {
+    /// the grid. Fails for coordinates that are not numbers.
+    pub fn new<C: Coord>(coords: &mut Vec<C>, size: usize) -> Result<Grid, Error> {
         let mut b_box = BoundingBox::new();
 
         //dereference and reborrow needed (ugly...)
@@ -65,15 +69,14 @@ impl Grid {
         let mut g = Grid {
             b_box: b_box,
             side_length: size,
-            offset_array: vec![0; size * size + 1],
+            offset_array: vec![0; size * size + 1].into(),
         };
 
         coords.sort_by_key(|n| g.coord_to_index(n.lat(), n.lon()));
         let mut current = 0;
         for (i, n) in coords.iter().enumerate() {
-            let new_index = g.coord_to_index(n.lat(), n.lon()).expect(
-                "Coordinate not in grid area. Something is really wrong",
-            );
+            let new_index = g.coord_to_index(n.lat(), n.lon())
+                .map_err(|_| Error::InvalidCoordinate(n.lat(), n.lon()))?;
             if new_index != current {
                 for offset in &mut g.offset_array[current + 1..new_index + 1] {
                     *offset = i;
@@ -87,7 +90,16 @@ impl Grid {
         let last_offset = g.offset_array.len() - 1;
         g.offset_array[last_offset] = coords.len();
 
-        g
+        Ok(g)
+    }
+
+    /// Whether the cell offsets are ordered and end at `count`, the
+    /// number of values the grid was built from
+    pub fn is_valid_for(&self, count: usize) -> bool {
+        let cells = self.side_length.checked_mul(self.side_length);
+        cells.map_or(false, |c| c + 1 == self.offset_array.len()) &&
+            self.offset_array.windows(2).all(|w| w[0] <= w[1]) &&
+            self.offset_array.last() == Some(&count)
     }
 
     /// Converts coordinates to a index inside the grid.
@@ -100,8 +112,17 @@ impl Grid {
         let cell_height = (self.b_box.long_max - self.b_box.long_min) / self.side_length as f64;
         let lat_dif = lat - self.b_box.lat_min;
         let long_dif = long - self.b_box.long_min;
-        let mut x = (lat_dif / cell_width) as usize;
-        let mut y = (long_dif / cell_height) as usize;
+        // grids without height or width have a single row or column
+        let mut x = if cell_width > 0.0 {
+            (lat_dif / cell_width) as usize
+        } else {
+            0
+        };
+        let mut y = if cell_height > 0.0 {
+            (long_dif / cell_height) as usize
+        } else {
+            0
+        };
         if x == self.side_length {
             x -= 1;
         }
@@ -119,6 +140,21 @@ impl Grid {
         long: f64,
         nodes: &'a [NodeInfo],
     ) -> Result<NodeInfoWithIndex, ()> {
+        self.nearest_neighbor_where(lat, long, nodes, |_| true)
+    }
+
+    /// The node closest to the given coordinate among the nodes whose
+    /// index is accepted by `filter`
+    pub fn nearest_neighbor_where<'a, F>(
+        &self,
+        lat: f64,
+        long: f64,
+        nodes: &'a [NodeInfo],
+        filter: F,
+    ) -> Result<NodeInfoWithIndex, ()>
+    where
+        F: Fn(usize) -> bool,
+    {
         use std::{f64, usize};
 
         let cell_width = haversine_distance(&(self.b_box.lat_max, self.b_box.long_max), &(
@@ -141,7 +177,8 @@ impl Grid {
         let mut min_index = usize::MAX;
         loop {
             let max_min_dist = (radius as f64 - 1.0) * cell_measure;
-            if max_min_dist > min_dist {
+            // stop once the whole grid was searched
+            if max_min_dist > min_dist || radius > self.side_length as isize {
                 break;
             }
             let radius_iter =
@@ -153,7 +190,7 @@ impl Grid {
 
                 for (i, n) in nodes[start..end].iter().enumerate() {
                     let dist = haversine_distance(&(lat, long), n);
-                    if dist < min_dist {
+                    if dist < min_dist && filter(start + i) {
                         min_dist = dist;
                         min_index = start + i;
                     }
@@ -174,6 +211,28 @@ impl Grid {
         max_dist: f64,
         towers: &'a [Tower],
     ) -> Result<Vec<::std::slice::Iter<'a, Tower>>, ()> {
+        let index = self.coord_to_index(coords.lat(), coords.lon())?;
+        Ok(
+            self.cells_around(index, max_dist)
+                .into_iter()
+                .map(|cell| towers[cell].iter())
+                .collect(),
+        )
+    }
+
+    /// Ranges of the sorted coordinates in all cells that may contain
+    /// coordinates within `max_dist` meters of `coords`. Coordinates
+    /// outside of the grid are moved onto its border first.
+    pub fn cells_within<C: Coord>(&self, coords: &C, max_dist: f64) -> Vec<Range<usize>> {
+        let lat = coords.lat().max(self.b_box.lat_min).min(self.b_box.lat_max);
+        let long = coords.lon().max(self.b_box.long_min).min(self.b_box.long_max);
+        match self.coord_to_index(lat, long) {
+            Ok(index) => self.cells_around(index, max_dist),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    fn cells_around(&self, index: usize, max_dist: f64) -> Vec<Range<usize>> {
         let cell_width = haversine_distance(&(self.b_box.lat_max, self.b_box.long_max), &(
             self.b_box
                 .lat_min,
@@ -189,24 +248,22 @@ impl Grid {
         let cell_measure = cell_width.min(cell_height);
         let mut radius = 0;
 
-        let index = self.coord_to_index(coords.lat(), coords.lon())?;
         let mut result = Vec::new();
         loop {
             let max_min_dist = (radius as f64 - 1.0) * cell_measure;
-            if max_min_dist > max_dist {
+            // cells have no size in grids without height or width, so
+            // also stop once the whole grid was searched
+            if max_min_dist > max_dist || radius > self.side_length as isize {
                 break;
             }
             let radius_iter =
                 radius::RadiusIter::new(index as isize, self.side_length as isize, radius);
             radius += 1;
             for index in radius_iter {
-                let start = self.offset_array[index];
-                let end = self.offset_array[index + 1];
-                let iter = towers[start..end].iter();
-                result.push(iter);
+                result.push(self.offset_array[index]..self.offset_array[index + 1]);
             }
         }
-        Ok(result)
+        result
     }
 }
 
@@ -217,18 +274,10 @@ pub struct NodeInfoWithIndex(pub usize, pub NodeInfo);
 #[test]
 fn converting_coord_to_index() {
     let mut nodes = vec![
-        NodeInfo {
-            lat: 3.4,
-            long: 5.1,
-            ..Default::default()
-        },
-        NodeInfo {
-            lat: 4.4,
-            long: 6.1,
-            ..Default::default()
-        },
+        NodeInfo::new(0, 3.4, 5.1, 0),
+        NodeInfo::new(0, 4.4, 6.1, 0),
     ];
-    let g = Grid::new(&mut nodes, 10);
+    let g = Grid::new(&mut nodes, 10).unwrap();
 
     let index = g.coord_to_index(4.12, 5.73);
     assert_eq!(index.unwrap(), 67)
@@ -237,18 +286,10 @@ fn converting_coord_to_index() {
 #[test]
 fn converting_coord_to_index2() {
     let mut nodes = vec![
-        NodeInfo {
-            lat: 3.4,
-            long: 5.1,
-            ..Default::default()
-        },
-        NodeInfo {
-            lat: 4.4,
-            long: 5.6,
-            ..Default::default()
-        },
+        NodeInfo::new(0, 3.4, 5.1, 0),
+        NodeInfo::new(0, 4.4, 5.6, 0),
     ];
-    let g = Grid::new(&mut nodes, 10);
+    let g = Grid::new(&mut nodes, 10).unwrap();
 
     let index = g.coord_to_index(4.12, 5.38);
     assert_eq!(index.unwrap(), 57)
@@ -257,18 +298,10 @@ fn converting_coord_to_index2() {
 #[test]
 fn converting_coord_to_index_edge_points() {
     let mut nodes = vec![
-        NodeInfo {
-            lat: 3.4,
-            long: 5.1,
-            ..Default::default()
-        },
-        NodeInfo {
-            lat: 4.4,
-            long: 5.6,
-            ..Default::default()
-        },
+        NodeInfo::new(0, 3.4, 5.1, 0),
+        NodeInfo::new(0, 4.4, 5.6, 0),
     ];
-    let g = Grid::new(&mut nodes, 10);
+    let g = Grid::new(&mut nodes, 10).unwrap();
 
     let index = g.coord_to_index(4.4, 5.6);
     assert_eq!(index.unwrap(), 99)
@@ -280,7 +313,7 @@ fn nearest_neighbor_2_points() {
         NodeInfo::new(0, 10.2, 30.4, 0),
         NodeInfo::new(1, 20.5, 40.1, 0),
     ];
-    let g = Grid::new(&mut nodes, 10);
+    let g = Grid::new(&mut nodes, 10).unwrap();
     let n = g.nearest_neighbor(10.3, 30.5, &nodes).unwrap();
     assert_eq!(0, n.0);
 }
@@ -291,7 +324,7 @@ fn nearest_neighbor_2_points_other_point() {
         NodeInfo::new(0, 10.2, 30.4, 0),
         NodeInfo::new(1, 20.5, 40.1, 0),
     ];
-    let g = Grid::new(&mut nodes, 10);
+    let g = Grid::new(&mut nodes, 10).unwrap();
     let n = g.nearest_neighbor(20.5, 40.1, &nodes).unwrap();
     assert_eq!(1, n.0);
 }
@@ -302,7 +335,22 @@ fn nearest_neighbor_2_points_different_cell() {
         NodeInfo::new(0, 10.2, 30.4, 0),
         NodeInfo::new(1, 20.5, 40.1, 0),
     ];
-    let g = Grid::new(&mut nodes, 10);
+    let g = Grid::new(&mut nodes, 10).unwrap();
     let n = g.nearest_neighbor(19.0, 38.0, &nodes).unwrap();
     assert_eq!(1, n.0);
 }
+
+#[test]
+fn cells_of_a_grid_without_height() {
+    let mut nodes = vec![
+        NodeInfo::new(0, 52.0, 13.0, 0),
+        NodeInfo::new(1, 52.0, 13.02, 0),
+    ];
+    let g = Grid::new(&mut nodes, 10).unwrap();
+    let cells = g.cells_within(&(52.0, 13.0), 1000.0);
+    assert_eq!(2, cells.iter().map(|c| c.len()).sum::<usize>());
+
+    let mut single = vec![NodeInfo::new(0, 52.0, 13.0, 0)];
+    let g = Grid::new(&mut single, 10).unwrap();
+    assert_eq!(1, g.cells_within(&(52.0, 13.0), 1000.0).iter().map(|c| c.len()).sum::<usize>());
+}
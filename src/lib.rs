@@ -16,20 +16,91 @@ extern crate serde_derive;
 extern crate serde_json;
 extern crate rayon;
 extern crate bincode;
+extern crate xml;
+extern crate memmap;
+extern crate flate2;
+extern crate zstd;
 
 mod graph;
 mod pbf;
 mod grid;
 mod geom;
 mod towers;
+mod coverage;
+mod elevation;
+mod propagation;
+mod provider;
+mod gpx;
+mod raster;
+mod drivetest;
+mod matching;
+mod clip;
+mod error;
+mod preprocessed;
+mod flat;
+mod download;
+mod batch;
+mod stats;
 pub mod web;
 pub use pbf::load_graph;
-pub use graph::load_preprocessed_graph;
-pub use towers::load_towers;
+pub use preprocessed::{load_preprocessed_graph, write_preprocessed, save_preprocessed,
+                       fingerprint, SourceFingerprint};
+pub use towers::{load_towers, load_towers_with_report, TowerReport, LineOfSight,
+                 CoverageOptions, CoverageSettings, CoverageSpec};
+pub use propagation::PropagationModel;
+pub use provider::{load_provider_names, ProviderRegistry, ProviderSelection};
+pub use elevation::Dem;
+pub use raster::{RasterSource, RasterLayer};
+pub use drivetest::{load_samples, calibrate, CalibrationReport};
+pub use matching::{match_gpx, MatchReport};
+pub use clip::ClipArea;
+pub use batch::{read_queries, route_query, route_batch, write_summaries, RouteOptions,
+                RouteQuery, RouteSummary};
+pub use graph::{RoutingGoal, Movement, Penalty, RoadClass};
+pub use stats::{coverage_stats, CoverageStats};
+pub use grid::BoundingBox;
+pub use towers::TechClass;
+pub use error::Error;
 
+use std::sync::{Arc, RwLock};
 
-#[derive(Deserialize, HeapSizeOf)]
+/// State of a running server that is shared between request handlers
+/// and background jobs
+pub type SharedState = Arc<RwLock<ApplicationState>>;
+
+#[derive(Serialize, Deserialize, HeapSizeOf)]
 pub struct ApplicationState {
     pub graph: graph::Graph,
     pub towers: Vec<towers::Tower>,
+    /// Settings the coverage was calculated with, changes of the towers
+    /// have to use them as well
+    pub coverage: towers::CoverageSpec,
+    /// Files the graph was built from, stored in the header of
+    /// preprocessed graphs
+    #[serde(skip_serializing, skip_deserializing)]
+    pub sources: Vec<preprocessed::SourceFingerprint>,
+}
+
+impl ApplicationState {
+    /// Disables or enables the towers with the indices `ids` and
+    /// updates the coverage of all edges near them. Returns the number
+    /// of changed towers and of updated edges.
+    pub fn set_towers_disabled(
+        &mut self,
+        ids: &[usize],
+        disabled: bool,
+        options: &CoverageOptions,
+    ) -> (usize, usize) {
+        let mut changed: Vec<usize> = ids.iter()
+            .cloned()
+            .filter(|&i| self.towers[i].disabled != disabled)
+            .collect();
+        changed.sort();
+        changed.dedup();
+        for &i in &changed {
+            self.towers[i].disabled = disabled;
+        }
+        let edges = self.graph.update_coverage(&self.towers, &changed, options);
+        (changed.len(), edges)
+    }
 }
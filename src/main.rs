@@ -6,57 +6,257 @@ extern crate dzr;
 extern crate heapsize;
 extern crate rocket;
 extern crate clap;
-use clap::{App, Arg};
+extern crate serde_json;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 
 use heapsize::HeapSizeOf;
 
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
 fn main() {
     let matches = App::new("Dead-Zone-aware Routing")
         .author("Florian Barth <florianbarth@gmx.de>")
-        .arg(
-            Arg::with_name("graph-file")
-                .short("f")
-                .value_name("FILE")
-                .takes_value(true)
-                .required(true),
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("Starts the web server")
+                .args(&graph_args())
+                .arg(
+                    Arg::with_name("preprocessed")
+                        .short("p")
+                        .takes_value(false)
+                        .help("determines if graph-file is preprocessed"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("preprocess")
+                .about("Imports pbf files and towers and writes a preprocessed graph")
+                .args(&graph_args())
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .required(true)
+                        .help("File to write the preprocessed graph to"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("validate-towers")
+                .about("Checks a tower file and reports all invalid rows")
+                .arg(
+                    Arg::with_name("tower-file")
+                        .short("t")
+                        .value_name("file")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("strict")
+                        .long("strict")
+                        .takes_value(false)
+                        .help("Stop at the first invalid row"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("match")
+                .about("Matches a GPX track onto a preprocessed graph and reports its coverage")
+                .arg(
+                    Arg::with_name("graph-file")
+                        .short("f")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(Arg::with_name("track").value_name("GPX").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("route")
+                .about("Calculates a single route on a preprocessed graph")
+                .arg(
+                    Arg::with_name("graph-file")
+                        .short("f")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .value_name("LAT,LON")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .value_name("LAT,LON")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .args(&routing_args()),
         )
-        .arg(
-            Arg::with_name("preprocessed")
-                .short("p")
-                .takes_value(false)
-                .help("determines if graph-file is preprocessed"),
+        .subcommand(
+            SubCommand::with_name("batch-route")
+                .about("Calculates the routes of a CSV file on a preprocessed graph")
+                .arg(
+                    Arg::with_name("graph-file")
+                        .short("f")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .required(true)
+                        .help("CSV file to write the results to"),
+                )
+                .arg(
+                    Arg::with_name("queries")
+                        .value_name("CSV")
+                        .required(true)
+                        .help("Columns from_lat, from_lon, to_lat, to_lon, [id], [provider]"),
+                )
+                .args(&routing_args()),
         )
-        .arg(
-            Arg::with_name("tower-file")
-                .short("t")
-                .value_name("file")
-                .takes_value(true)
-                .help("Tower file is needed for not preprocessed graphs"),
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Reports the road length and its coverage of a preprocessed graph")
+                .arg(
+                    Arg::with_name("graph-file")
+                        .short("f")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("bbox")
+                        .long("bbox")
+                        .value_name("LAT_MIN,LON_MIN,LAT_MAX,LON_MAX")
+                        .takes_value(true)
+                        .help("Only count roads inside of this bounding box"),
+                )
+                .arg(
+                    Arg::with_name("tech")
+                        .long("tech")
+                        .takes_value(true)
+                        .possible_values(&["any", "umts", "lte"])
+                        .default_value("any"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["json", "csv"])
+                        .default_value("json"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .help("File to write the report to instead of stdout"),
+                ),
         )
         .get_matches();
 
-    let path = matches.value_of("graph-file").expect("No Graph-file given");
-    let preprocessed = matches.is_present("preprocessed");
-    let g = if preprocessed {
-        dzr::load_preprocessed_graph(path)
-    } else {
-        let tower_path = matches.value_of("tower-file").expect(
-            "for pbf files a tower file is needed",
-        );
-        let mut towers = dzr::load_towers(tower_path).expect("Could not load towers file");
-        let graph = dzr::load_graph(path, &mut towers);
-        dzr::ApplicationState { graph, towers }
-    };
+    match matches.subcommand() {
+        ("serve", Some(matches)) => serve(matches),
+        ("preprocess", Some(matches)) => preprocess(matches),
+        ("validate-towers", Some(matches)) => validate_towers(matches),
+        ("match", Some(matches)) => match_track(matches),
+        ("route", Some(matches)) => route(matches),
+        ("batch-route", Some(matches)) => batch_route(matches),
+        ("stats", Some(matches)) => stats(matches),
+        _ => unreachable!(),
+    }
+}
 
+/// Arguments to build a graph with, shared by `serve` and `preprocess`
+fn graph_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("graph-file")
+            .short("f")
+            .value_name("FILE")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .required(true)
+            .help("Graph file, pbf files of neighboring regions can be given repeatedly"),
+        Arg::with_name("tower-file")
+            .short("t")
+            .value_name("file")
+            .takes_value(true)
+            .help("Tower file is needed for not preprocessed graphs without coverage rasters"),
+        Arg::with_name("strict")
+            .long("strict")
+            .takes_value(false)
+            .help("Fail on the first invalid row of the tower file"),
+        Arg::with_name("provider-names")
+            .short("n")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("CSV file with the columns mcc, mnc and name to name providers"),
+        Arg::with_name("elevation")
+            .short("e")
+            .value_name("DIR")
+            .takes_value(true)
+            .help("Directory with SRTM .hgt tiles to set node heights from"),
+        Arg::with_name("line-of-sight")
+            .long("line-of-sight")
+            .takes_value(false)
+            .requires("elevation")
+            .help("Only count edges as covered if towers are not hidden by terrain"),
+        Arg::with_name("antenna-height")
+            .long("antenna-height")
+            .value_name("METERS")
+            .takes_value(true)
+            .default_value("30")
+            .help("Height of tower antennas above ground for the line of sight check"),
+        Arg::with_name("signal-model")
+            .long("signal-model")
+            .value_name("MODEL")
+            .takes_value(true)
+            .possible_values(&["free-space", "hata"])
+            .help("Estimate signal levels along all edges with this propagation model"),
+        Arg::with_name("coverage-raster")
+            .long("coverage-raster")
+            .value_name("MCC-MNC:RADIO:FILE[:MIN]")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("ESRI ASCII grid coverage map to combine with the towers"),
+        Arg::with_name("drive-test")
+            .long("drive-test")
+            .value_name("FILE")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Calibrate coverage with a drive test log (CSV or GPX)"),
+        Arg::with_name("measurement-weight")
+            .long("measurement-weight")
+            .value_name("WEIGHT")
+            .takes_value(true)
+            .default_value("0.5")
+            .help("Weight from 0 to 1 of drive test measurements, 1 overrides the model"),
+        Arg::with_name("clip")
+            .long("clip")
+            .value_name("AREA")
+            .takes_value(true)
+            .help("Only import lat_min,lon_min,lat_max,lon_max or the polygon of a .poly file"),
+    ]
+}
 
-    println!(
-        "Size of graph: {} MB",
-        g.heap_size_of_children() / 1_048_576
-    );
-    println!(
-        "Size of Edges: {} MB",
-        g.graph.edges.heap_size_of_children() / 1_048_576
-    );
+fn serve(matches: &ArgMatches) {
+    let (g, settings) = build_state(matches);
+    print_size(&g);
 
     rocket::ignite()
         .mount(
@@ -69,10 +269,372 @@ fn main() {
                 dzr::web::download,
                 dzr::web::map_boundary,
                 dzr::web::low_coverage,
+                dzr::web::providers,
+                dzr::web::stats,
+                dzr::web::stats_all,
+                dzr::web::disable_towers,
+                dzr::web::enable_towers,
+                dzr::web::reload_towers,
+                dzr::web::match_track,
                 dzr::web::redirect_to_index,
             ],
         )
-        .manage(g.graph)
-        .manage(g.towers)
+        .manage(Arc::new(RwLock::new(g)))
+        .manage(Arc::new(settings))
         .launch();
 }
+
+fn preprocess(matches: &ArgMatches) {
+    let output = matches.value_of("output").expect("No output file given");
+    let start = Instant::now();
+    let (g, _) = build_state(matches);
+    print_size(&g);
+
+    let start_writing = Instant::now();
+    or_exit(dzr::save_preprocessed(output, &g));
+    let end = Instant::now();
+    println!("writing time: {:?}", end.duration_since(start_writing));
+    println!("total   time: {:?}", end.duration_since(start));
+}
+
+fn coverage_spec(matches: &ArgMatches) -> dzr::CoverageSpec {
+    dzr::CoverageSpec {
+        elevation: matches.value_of("elevation").map(String::from),
+        line_of_sight: matches.is_present("line-of-sight"),
+        antenna_height: matches
+            .value_of("antenna-height")
+            .and_then(|h| h.parse().ok())
+            .expect("antenna height has to be a number"),
+        signal_model: matches.value_of("signal-model").map(|m| {
+            m.parse::<dzr::PropagationModel>().expect(
+                "Unknown propagation model",
+            )
+        }),
+        rasters: matches
+            .values_of("coverage-raster")
+            .map(|specs| specs.map(String::from).collect())
+            .unwrap_or_default(),
+    }
+}
+
+fn load_coverage_settings(spec: &dzr::CoverageSpec) -> dzr::CoverageSettings {
+    match dzr::CoverageSettings::load(spec) {
+        Ok(settings) => settings,
+        Err(e) => {
+            println!("could not load elevation data or coverage maps: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Loads a preprocessed graph or imports the given pbf files and
+/// calibrates the result with the drive tests. Preprocessed graphs keep
+/// the coverage settings they were built with.
+fn build_state(matches: &ArgMatches) -> (dzr::ApplicationState, dzr::CoverageSettings) {
+    let paths: Vec<&str> = matches
+        .values_of("graph-file")
+        .expect("No Graph-file given")
+        .collect();
+    let preprocessed = matches.is_present("preprocessed");
+    if preprocessed && paths.len() > 1 {
+        panic!("only pbf files can be merged, give a single preprocessed graph");
+    }
+    let spec = coverage_spec(matches);
+    let (mut g, settings) = if preprocessed {
+        let g = or_exit(dzr::load_preprocessed_graph(paths[0]));
+        if g.coverage != spec {
+            println!(
+                "Warning: using the coverage settings of the preprocessed graph {:?}",
+                g.coverage
+            );
+        }
+        let settings = load_coverage_settings(&g.coverage);
+        (g, settings)
+    } else {
+        let settings = load_coverage_settings(&spec);
+        let mut towers = match matches.value_of("tower-file") {
+            Some(tower_path) if matches.is_present("strict") => {
+                or_exit(dzr::load_towers_with_report(tower_path, true)).0
+            }
+            Some(tower_path) => or_exit(dzr::load_towers(tower_path)),
+            None if settings.raster.is_some() => Vec::new(),
+            None => panic!("for pbf files a tower file or coverage raster is needed"),
+        };
+        let names = match matches.value_of("provider-names") {
+            Some(names) => dzr::load_provider_names(names).expect("Could not load provider names"),
+            None => Default::default(),
+        };
+        let area = matches.value_of("clip").map(|spec| {
+            dzr::ClipArea::from_spec(spec).expect("Could not load clip area")
+        });
+        if let Some(ref area) = area {
+            let removed = area.retain_towers(&mut towers);
+            println!("Removed {} towers outside of the clip area", removed);
+        }
+        let providers = settings.provider_registry(&towers, &names);
+        let graph = or_exit(dzr::load_graph(
+            &paths,
+            &mut towers,
+            providers,
+            settings.dem.as_ref(),
+            &settings.options(),
+            area.as_ref(),
+        ));
+        let mut sources = Vec::new();
+        for path in paths.iter().chain(matches.value_of("tower-file").as_ref()) {
+            sources.push(or_exit(dzr::fingerprint(path)));
+        }
+        let g = dzr::ApplicationState {
+            graph,
+            towers,
+            coverage: spec,
+            sources,
+        };
+        (g, settings)
+    };
+
+    if let Some(files) = matches.values_of("drive-test") {
+        let weight: f64 = matches
+            .value_of("measurement-weight")
+            .and_then(|w| w.parse().ok())
+            .expect("measurement weight has to be a number");
+        if !(0.0 <= weight && weight <= 1.0) {
+            println!("measurement weight has to be between 0 and 1");
+            std::process::exit(1);
+        }
+        if !g.graph.calibration.is_empty() {
+            println!("graph is already calibrated, preprocess it again with all drive tests");
+            std::process::exit(1);
+        }
+        let mut samples = Vec::new();
+        for file in files {
+            samples.extend(dzr::load_samples(file).expect("Could not load drive test"));
+        }
+        print!("{}", dzr::calibrate(&mut g.graph, &samples, weight));
+    }
+    (g, settings)
+}
+
+fn print_size(g: &dzr::ApplicationState) {
+    println!(
+        "Size of graph: {} MB",
+        g.heap_size_of_children() / 1_048_576
+    );
+    println!(
+        "Size of Edges: {} MB",
+        g.graph.edges.heap_size_of_children() / 1_048_576
+    );
+    println!(
+        "Size of edge shapes: {} MB",
+        g.graph.shapes.heap_size_of_children() / 1_048_576
+    );
+}
+
+fn validate_towers(matches: &ArgMatches) {
+    let path = matches.value_of("tower-file").expect("No tower file given");
+    match dzr::load_towers_with_report(path, matches.is_present("strict")) {
+        Ok((_, report)) => {
+            print!("{}", report);
+            if !report.dropped.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn match_track(matches: &ArgMatches) {
+    let path = matches.value_of("graph-file").expect("No Graph-file given");
+    let track = matches.value_of("track").expect("No track given");
+    let state = or_exit(dzr::load_preprocessed_graph(path));
+    let file = File::open(track).expect("Could not open track");
+    match dzr::match_gpx(&state.graph, BufReader::new(file)) {
+        Ok(report) => println!("{}", serde_json::to_string_pretty(&report).unwrap()),
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Options of `route` and `batch-route`
+fn routing_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("provider")
+            .long("provider")
+            .value_name("PROVIDER")
+            .takes_value(true)
+            .help("Provider name, mcc-mnc, any, all or min-<k> whose coverage counts"),
+        Arg::with_name("movement")
+            .long("movement")
+            .takes_value(true)
+            .possible_values(&["car", "foot"])
+            .default_value("car"),
+        Arg::with_name("goal")
+            .long("goal")
+            .takes_value(true)
+            .possible_values(&["length", "speed"])
+            .default_value("length"),
+        Arg::with_name("tech")
+            .long("tech")
+            .takes_value(true)
+            .possible_values(&["any", "umts", "lte"])
+            .default_value("any"),
+        Arg::with_name("penalty")
+            .long("penalty")
+            .takes_value(true)
+            .possible_values(&["coverage", "signal"])
+            .default_value("coverage"),
+    ]
+}
+
+fn route_options(matches: &ArgMatches) -> dzr::RouteOptions {
+    dzr::RouteOptions {
+        goal: matches.value_of("goal").unwrap().parse().unwrap(),
+        movement: matches.value_of("movement").unwrap().parse().unwrap(),
+        tech: matches.value_of("tech").unwrap().parse().unwrap(),
+        penalty: matches.value_of("penalty").unwrap().parse().unwrap(),
+    }
+}
+
+fn provider_selection(
+    matches: &ArgMatches,
+    state: &dzr::ApplicationState,
+) -> Option<dzr::ProviderSelection> {
+    matches.value_of("provider").map(|name| {
+        state.graph.providers.parse_selection(name).unwrap_or_else(|| {
+            println!("unknown provider {}", name);
+            std::process::exit(1);
+        })
+    })
+}
+
+fn coordinate(matches: &ArgMatches, name: &str) -> (f64, f64) {
+    let value = matches.value_of(name).expect("No coordinate given");
+    let mut parts = value.splitn(2, ',').map(|p| p.trim().parse::<f64>());
+    match (parts.next(), parts.next()) {
+        (Some(Ok(lat)), Some(Ok(lon))) => (lat, lon),
+        _ => {
+            println!("{} has to be given as lat,lon", name);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn route(matches: &ArgMatches) {
+    let path = matches.value_of("graph-file").expect("No Graph-file given");
+    let state = or_exit(dzr::load_preprocessed_graph(path));
+    let query = dzr::RouteQuery {
+        id: String::new(),
+        from: coordinate(matches, "from"),
+        to: coordinate(matches, "to"),
+        provider: provider_selection(matches, &state),
+    };
+    let mut dijkstra = state.graph.dijkstra();
+    match dzr::route_query(&state.graph, &mut dijkstra, &query, &route_options(matches)) {
+        Some(summary) => println!("{}", serde_json::to_string_pretty(&summary).unwrap()),
+        None => {
+            println!("no route between the given coordinates");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn batch_route(matches: &ArgMatches) {
+    let path = matches.value_of("graph-file").expect("No Graph-file given");
+    let queries = matches.value_of("queries").expect("No queries given");
+    let state = or_exit(dzr::load_preprocessed_graph(path));
+    let provider = provider_selection(matches, &state);
+    let file = File::open(queries).expect("Could not open queries");
+    let queries = match dzr::read_queries(BufReader::new(file), &state.graph, provider) {
+        Ok(queries) => queries,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let start = Instant::now();
+    let summaries = dzr::route_batch(&state.graph, &queries, &route_options(matches));
+    let end = Instant::now();
+    let found = summaries.iter().filter(|s| s.is_some()).count();
+    println!(
+        "routed {} of {} queries in {:?}",
+        found,
+        queries.len(),
+        end.duration_since(start)
+    );
+
+    let output = matches.value_of("output").expect("No output file given");
+    let file = File::create(output).expect("Could not create output file");
+    if let Err(e) = dzr::write_summaries(BufWriter::new(file), &queries, &summaries) {
+        println!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn stats(matches: &ArgMatches) {
+    let path = matches.value_of("graph-file").expect("No Graph-file given");
+    let bbox = matches.value_of("bbox").map(|spec| {
+        let values = spec.split(',').map(|v| v.trim().parse()).collect::<Result<Vec<f64>, _>>();
+        let values = match values {
+            Ok(values) => values,
+            Err(e) => {
+                println!("invalid bounding box {}: {}", spec, e);
+                std::process::exit(1);
+            }
+        };
+        if values.len() != 4 {
+            println!("the bounding box has to be given as lat_min,lon_min,lat_max,lon_max");
+            std::process::exit(1);
+        }
+        let mut bbox = dzr::BoundingBox::new();
+        bbox.add_coord(&(values[0], values[1]));
+        bbox.add_coord(&(values[2], values[3]));
+        bbox
+    });
+    let state = or_exit(dzr::load_preprocessed_graph(path));
+    let tech = matches.value_of("tech").unwrap().parse().unwrap();
+    let stats = dzr::coverage_stats(&state.graph, bbox.as_ref(), tech);
+
+    let mut out: Box<Write> = match matches.value_of("output") {
+        Some(output) => {
+            let file = File::create(output).expect("Could not create output file");
+            Box::new(BufWriter::new(file))
+        }
+        None => Box::new(std::io::stdout()),
+    };
+    if let Err(e) = write_stats(&stats, matches.value_of("format"), &mut *out) {
+        println!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn write_stats(
+    stats: &dzr::CoverageStats,
+    format: Option<&str>,
+    out: &mut Write,
+) -> Result<(), Box<Error>> {
+    match format {
+        Some("csv") => stats.write_csv(out),
+        _ => {
+            serde_json::to_writer_pretty(&mut *out, stats)?;
+            out.write_all(b"\n")?;
+            Ok(())
+        }
+    }
+}
+
+/// Unwraps `result` or ends the process after printing the error
+fn or_exit<T>(result: Result<T, dzr::Error>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,443 @@
+use graph::{Graph, NodeId, EdgePosition, Movement};
+use gpx::{read_track, TrackPoint};
+use geom::haversine_distance;
+use provider::ProviderSelection;
+use towers::TechClass;
+
+use std::error::Error;
+use std::f64;
+use std::io::Read;
+
+/// Standard deviation of the GPS noise in meters
+const SIGMA_Z: f64 = 4.07;
+/// Scale in meters of the difference between route length and
+/// great circle distance of consecutive points
+const BETA: f64 = 5.0;
+/// Edges farther away from a point are no candidates for it
+const SEARCH_RADIUS: f64 = 50.0;
+/// Only the closest candidates of every point are considered
+const MAX_CANDIDATES: usize = 8;
+/// Routes between candidates may be at most this much longer than
+/// the great circle distance of their points
+const MAX_DETOUR: f64 = 2000.0;
+/// Edges with at least this coverage count as covered
+const COVERED: f64 = 0.5;
+
+/// A candidate during the Viterbi search
+struct State {
+    position: EdgePosition,
+    score: f64,
+    /// Index of the best candidate of the previous point
+    prev: Option<usize>,
+    /// Edges driven from the previous candidate up to this one
+    edges: Vec<usize>,
+}
+
+/// Path of a track through the graph
+#[derive(Debug, Default)]
+pub struct MatchedTrack {
+    /// Traversed edges as pairs of source node and edge index
+    pub edges: Vec<(NodeId, usize)>,
+    /// Track points that could not be matched
+    pub unmatched: usize,
+    /// Position of every track point on the matched path, `None` for
+    /// the unmatched ones
+    pub positions: Vec<Option<EdgePosition>>,
+}
+
+/// Matches a track onto the graph with the hidden Markov model of
+/// Newson and Krumm. If consecutive points can not be connected the
+/// matching starts over at the second point.
+pub fn match_track(graph: &Graph, points: &[TrackPoint], movement: &Movement) -> MatchedTrack {
+    let mut result = MatchedTrack {
+        positions: vec![None; points.len()],
+        ..Default::default()
+    };
+    let sources = source_nodes(graph);
+    let mut dijkstra = graph.dijkstra();
+    let mut steps: Vec<Vec<State>> = Vec::new();
+    // index of the track point of every step
+    let mut step_points: Vec<usize> = Vec::new();
+    let mut last_point: Option<&TrackPoint> = None;
+
+    for (index, point) in points.iter().enumerate() {
+        let candidates: Vec<EdgePosition> = graph
+            .edges_near(point.lat, point.lon, SEARCH_RADIUS)
+            .into_iter()
+            .filter(|c| !graph.edges[c.edge].is_not_for(movement))
+            .take(MAX_CANDIDATES)
+            .collect();
+        if candidates.is_empty() {
+            result.unmatched += 1;
+            continue;
+        }
+        let emission = |c: &EdgePosition| -0.5 * (c.distance / SIGMA_Z).powi(2);
+
+        let mut states: Vec<State> = candidates
+            .iter()
+            .map(|c| {
+                State {
+                    position: *c,
+                    score: f64::NEG_INFINITY,
+                    prev: None,
+                    edges: Vec::new(),
+                }
+            })
+            .collect();
+        if let (Some(last), Some(previous)) = (last_point, steps.last()) {
+            let great_circle = haversine_distance(last, point);
+            let targets: Vec<NodeId> = candidates.iter().map(|c| c.source).collect();
+            for (i, from) in previous.iter().enumerate() {
+                let from_edge = &graph.edges[from.position.edge];
+                let rest = (1.0 - from.position.fraction) * from_edge.length;
+                let paths = dijkstra.paths_to(
+                    from_edge.endpoint,
+                    &targets,
+                    great_circle + MAX_DETOUR,
+                    movement,
+                );
+                let transitions = candidates.iter().zip(states.iter_mut()).zip(paths);
+                for ((to, state), path) in transitions {
+                    let to_edge = &graph.edges[to.edge];
+                    let (route, edges) = if to.edge == from.position.edge &&
+                        to.fraction >= from.position.fraction
+                    {
+                        ((to.fraction - from.position.fraction) * to_edge.length, Vec::new())
+                    } else {
+                        match path {
+                            Some((dist, mut edges)) => {
+                                edges.push(to.edge);
+                                (rest + dist + to.fraction * to_edge.length, edges)
+                            }
+                            None => continue,
+                        }
+                    };
+                    let score = from.score - (route - great_circle).abs() / BETA + emission(to);
+                    if score > state.score {
+                        state.score = score;
+                        state.prev = Some(i);
+                        state.edges = edges;
+                    }
+                }
+            }
+        }
+        if states.iter().all(|s| s.prev.is_none()) {
+            // no connection to the previous point, start a new match
+            finish(&sources, &steps, &step_points, &mut result);
+            steps.clear();
+            step_points.clear();
+            for state in &mut states {
+                state.score = emission(&state.position);
+            }
+        }
+        steps.push(states);
+        step_points.push(index);
+        last_point = Some(point);
+    }
+    finish(&sources, &steps, &step_points, &mut result);
+    result
+}
+
+/// Follows the best states back and appends their edges to `result`.
+/// `step_points` holds the index of the track point of every step.
+fn finish(
+    sources: &[NodeId],
+    steps: &[Vec<State>],
+    step_points: &[usize],
+    result: &mut MatchedTrack,
+) {
+    let last = match steps.last() {
+        Some(last) => last,
+        None => return,
+    };
+    let mut best = (0..last.len())
+        .max_by(|&a, &b| last[a].score.partial_cmp(&last[b].score).unwrap())
+        .unwrap();
+    let mut edges = Vec::new();
+    for (step, &point) in steps.iter().zip(step_points).rev() {
+        let state = &step[best];
+        result.positions[point] = Some(state.position);
+        match state.prev {
+            Some(prev) => {
+                edges.extend(state.edges.iter().rev().cloned());
+                best = prev;
+            }
+            None => edges.push(state.position.edge),
+        }
+    }
+    edges.reverse();
+    result.edges.extend(edges.into_iter().map(|e| (sources[e], e)));
+}
+
+fn source_nodes(graph: &Graph) -> Vec<NodeId> {
+    let mut sources = vec![0; graph.edges.len()];
+    for node in 0..graph.node_info.len() {
+        for (e, _) in graph.outgoing_edges_for(node) {
+            sources[e] = node;
+        }
+    }
+    sources
+}
+
+/// Part of a matched track that is either covered or not
+#[derive(Debug, Serialize)]
+pub struct Stretch {
+    pub covered: bool,
+    /// Length in meters
+    pub length: f64,
+    /// Index of the first coordinate of the stretch in the route
+    pub from: usize,
+    /// Index of the last coordinate of the stretch in the route
+    pub to: usize,
+}
+
+/// Coverage of a single provider along a matched track
+#[derive(Debug, Serialize)]
+pub struct ProviderStretches {
+    pub id: String,
+    pub name: String,
+    /// Covered length in meters
+    pub covered: f64,
+    /// Uncovered length in meters
+    pub uncovered: f64,
+    pub stretches: Vec<Stretch>,
+}
+
+/// Matched route of a track with the coverage along it
+#[derive(Debug, Serialize)]
+pub struct MatchReport {
+    /// Coordinates of the route as pairs of longitude and latitude
+    pub route: Vec<[f64; 2]>,
+    pub length: f64,
+    pub unmatched: usize,
+    pub providers: Vec<ProviderStretches>,
+}
+
+/// Splits the matched track into covered and uncovered stretches for
+/// every provider
+pub fn coverage_stretches(graph: &Graph, track: &MatchedTrack) -> MatchReport {
+    let mut route = Vec::new();
+    let mut length = 0.0;
+    // subsegments with their length and the index of their first
+    // coordinate in the route
+    let mut segments = Vec::new();
+    let mut last_node = None;
+    for &(source, e) in &track.edges {
+        let points = graph.shapes.points(e);
+        if last_node != Some(source) {
+            route.push([points[0].long, points[0].lat]);
+        }
+        let weights = graph.shapes.segment_weights(e);
+        for ((n, segment_length), t) in weights.into_iter().zip(&points[1..]) {
+            segments.push((n, segment_length, route.len() - 1));
+            route.push([t.long, t.lat]);
+        }
+        last_node = Some(graph.edges[e].endpoint);
+        length += graph.edges[e].length;
+    }
+
+    let mut providers = Vec::new();
+    for (i, p) in graph.providers.providers().iter().enumerate() {
+        let layer = match graph.segment_coverage.get_all(
+            Some(ProviderSelection::Single(*p)),
+            TechClass::Any,
+        ) {
+            Some(layer) => layer,
+            None => continue,
+        };
+        let mut entry = ProviderStretches {
+            id: p.to_string(),
+            name: graph.providers.name(i).to_string(),
+            covered: 0.0,
+            uncovered: 0.0,
+            stretches: Vec::new(),
+        };
+        for &(n, segment_length, from) in &segments {
+            let covered = layer.get(n) >= COVERED;
+            if covered {
+                entry.covered += segment_length;
+            } else {
+                entry.uncovered += segment_length;
+            }
+            if let Some(last) = entry.stretches.last_mut() {
+                if last.covered == covered && last.to == from {
+                    last.length += segment_length;
+                    last.to = from + 1;
+                    continue;
+                }
+            }
+            entry.stretches.push(Stretch {
+                covered,
+                length: segment_length,
+                from,
+                to: from + 1,
+            });
+        }
+        providers.push(entry);
+    }
+    MatchReport {
+        route,
+        length,
+        unmatched: track.unmatched,
+        providers,
+    }
+}
+
+/// Reads a GPX track, matches it onto `graph` and reports the coverage
+/// along the matched route
+pub fn match_gpx<R: Read>(graph: &Graph, r: R) -> Result<MatchReport, Box<Error>> {
+    let points = read_track(r)?;
+    let track = match_track(graph, &points, &Movement::Car);
+    Ok(coverage_stretches(graph, &track))
+}
+
+#[cfg(test)]
+fn test_graph(mut towers: Vec<::towers::Tower>) -> Graph {
+    use graph::{NodeInfo, EdgeInfo};
+    use provider::ProviderRegistry;
+    use std::collections::HashMap;
+
+    let two_way = |s, t, shape: Vec<NodeInfo>| {
+        let mut forward = EdgeInfo::new(s, t, 1.0, 50);
+        forward.shape = shape.clone();
+        let mut backward = EdgeInfo::new(t, s, 1.0, 50);
+        backward.shape = shape.into_iter().rev().collect();
+        vec![forward, backward]
+    };
+    // a main road with a footway beside its last part, a side road
+    // just north of it and a road far away, both not connected
+    let mut edges = Vec::new();
+    edges.extend(two_way(1, 2, Vec::new()));
+    edges.extend(two_way(2, 3, vec![NodeInfo::new(10, 52.0, 13.006, 0)]));
+    edges.extend(two_way(3, 4, Vec::new()));
+    edges.extend(two_way(5, 6, Vec::new()));
+    edges.extend(two_way(7, 8, Vec::new()));
+    let mut footway = EdgeInfo::new(3, 4, 1.0, 5);
+    footway.shape = vec![NodeInfo::new(11, 51.99995, 13.009, 0)];
+    footway.not_for_cars();
+    edges.push(footway);
+
+    let providers = ProviderRegistry::new(&towers, &HashMap::new());
+    Graph::new(
+        vec![
+            NodeInfo::new(1, 52.0, 13.0, 0),
+            NodeInfo::new(2, 52.0, 13.004, 0),
+            NodeInfo::new(3, 52.0, 13.008, 0),
+            NodeInfo::new(4, 52.0, 13.012, 0),
+            NodeInfo::new(5, 52.0003, 13.003, 0),
+            NodeInfo::new(6, 52.0003, 13.006, 0),
+            NodeInfo::new(7, 52.01, 13.0, 0),
+            NodeInfo::new(8, 52.01, 13.004, 0),
+        ],
+        edges,
+        &mut towers,
+        providers,
+        &Default::default(),
+    ).unwrap()
+}
+
+#[cfg(test)]
+fn track(coords: &[(f64, f64)]) -> Vec<TrackPoint> {
+    use std::collections::HashMap;
+
+    coords
+        .iter()
+        .map(|&(lat, lon)| {
+            TrackPoint {
+                lat,
+                lon,
+                time: None,
+                extensions: HashMap::new(),
+            }
+        })
+        .collect()
+}
+
+/// The matched edges as pairs of OSM ids of their end points
+#[cfg(test)]
+fn osm_edges(g: &Graph, track: &MatchedTrack) -> Vec<(usize, usize)> {
+    track
+        .edges
+        .iter()
+        .map(|&(source, e)| {
+            (g.node_info[source].osm_id, g.node_info[g.edges[e].endpoint].osm_id)
+        })
+        .collect()
+}
+
+#[test]
+fn candidates_are_usable_edges_near_a_point() {
+    let g = test_graph(Vec::new());
+    // the second point is too far from any road, the last one is on
+    // the footway next to the main road
+    let points = track(&[(52.0, 13.001), (52.005, 13.005), (51.99995, 13.009)]);
+
+    let car = match_track(&g, &points, &Movement::Car);
+    assert_eq!(vec![(1, 2), (2, 3), (3, 4)], osm_edges(&g, &car));
+    assert_eq!(1, car.unmatched);
+    assert!(car.positions[1].is_none());
+    assert!(car.edges.iter().all(|&(_, e)| !g.edges[e].is_not_for(&Movement::Car)));
+
+    let foot = match_track(&g, &points, &Movement::Foot);
+    assert_eq!(vec![(1, 2), (2, 3), (3, 4)], osm_edges(&g, &foot));
+    assert!(g.edges[foot.edges[2].1].is_not_for(&Movement::Car));
+}
+
+#[test]
+fn backtracking_prefers_connected_candidates() {
+    let g = test_graph(Vec::new());
+    // the middle point is closer to the side road, which can not be
+    // reached from the main road
+    let side = g.nearest_edge(52.0002, 13.005, SEARCH_RADIUS).unwrap();
+    let osm_id = g.node_info[side.source].osm_id;
+    assert!(osm_id == 5 || osm_id == 6);
+
+    let points = track(&[(52.0, 13.001), (52.0002, 13.005), (52.0, 13.009)]);
+    let matched = match_track(&g, &points, &Movement::Car);
+    assert_eq!(vec![(1, 2), (2, 3), (3, 4)], osm_edges(&g, &matched));
+    assert_eq!(0, matched.unmatched);
+    let middle = matched.positions[1].unwrap();
+    assert_eq!(matched.edges[1].1, middle.edge);
+}
+
+#[test]
+fn matching_restarts_after_a_gap() {
+    let g = test_graph(Vec::new());
+    let points = track(&[(52.0, 13.001), (52.0, 13.005), (52.01, 13.001), (52.01, 13.003)]);
+    let matched = match_track(&g, &points, &Movement::Car);
+    assert_eq!(vec![(1, 2), (2, 3), (7, 8)], osm_edges(&g, &matched));
+    assert_eq!(0, matched.unmatched);
+}
+
+#[test]
+fn stretches_of_equal_coverage_are_merged() {
+    use towers::Tower;
+
+    // the first provider covers the first edge and less than half of
+    // the first subsegment of the second one, the other covers all
+    let g = test_graph(vec![
+        Tower::test(1, 51.99, 12.99, 1.0),
+        Tower::test(1, 52.0, 13.002, 200.0),
+        Tower::test(2, 52.0, 13.006, 1000.0),
+        Tower::test(1, 52.02, 13.02, 1.0),
+    ]);
+    let points = track(&[(52.0, 13.001), (52.0, 13.005), (52.0, 13.011)]);
+    let matched = match_track(&g, &points, &Movement::Car);
+    assert_eq!(vec![(1, 2), (2, 3), (3, 4)], osm_edges(&g, &matched));
+
+    let report = coverage_stretches(&g, &matched);
+    assert_eq!(5, report.route.len());
+    let first_edge = g.edges[matched.edges[0].1].length;
+
+    let partial = &report.providers[0];
+    assert_eq!("262-01", partial.id);
+    let ranges: Vec<_> = partial.stretches.iter().map(|s| (s.covered, s.from, s.to)).collect();
+    assert_eq!(vec![(true, 0, 1), (false, 1, 4)], ranges);
+    assert!((partial.covered - first_edge).abs() < 1e-6);
+    assert!((partial.covered + partial.uncovered - report.length).abs() < 1e-6);
+
+    let full = &report.providers[1];
+    let ranges: Vec<_> = full.stretches.iter().map(|s| (s.covered, s.from, s.to)).collect();
+    assert_eq!(vec![(true, 0, 4)], ranges);
+    assert!((full.covered - report.length).abs() < 1e-6);
+}
@@ -1,80 +1,121 @@
 use osmpbfreader::{OsmObj, OsmPbfReader, Way};
 
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
 use std::fs::File;
 use std::time::Instant;
 
 use graph::*;
-use towers::Tower;
+use towers::{Tower, CoverageOptions};
+use provider::ProviderRegistry;
+use elevation::Dem;
+use clip::ClipArea;
+use error::Error;
 
-/// Loads the graph from a pbf file.
+use rayon::prelude::*;
+
+/// Loads the graph from one or more pbf files.
+///
+/// Nodes and ways that are contained in several files, e.g. at the
+/// borders of neighboring extracts, are only imported once. Of a way
+/// the copy with the most nodes found in the files is used.
 ///
 /// All edges and nodes that contain a highway tag and are accessible
 /// for either cars or pedestrians by the judgement of is_not_for_cars
-/// and is_not_for_pedestrians
-pub fn load_graph<P: AsRef<Path>>(p: P, towers: &mut Vec<Tower>) -> Graph {
-    let fs = File::open(p).unwrap();
-    let mut reader = OsmPbfReader::new(fs);
+/// and is_not_for_pedestrians. If a `dem` is given the height of
+/// every node is interpolated from it. `options` select the model
+/// used for the coverage calculation. Nodes that only connect two
+/// edges of the same way are contracted into shape points. If an
+/// `area` is given all ways are cut at its border. Ways are split at
+/// nodes missing in the files.
+pub fn load_graph<P: AsRef<Path>>(
+    paths: &[P],
+    towers: &mut Vec<Tower>,
+    providers: ProviderRegistry,
+    dem: Option<&Dem>,
+    options: &CoverageOptions,
+    area: Option<&ClipArea>,
+) -> Result<Graph, Error> {
     let start_loading = Instant::now();
-    let obj_map = reader
-        .get_objs_and_deps(|obj| obj.tags().contains_key("highway"))
-        .unwrap();
-
     let mut nodes = Vec::new();
-    let mut edges = Vec::new();
-    for (_, obj) in obj_map {
-        match obj {
-            OsmObj::Node(node) => {
-                nodes.push(NodeInfo::new(
-                    node.id.0 as usize,
-                    (node.decimicro_lat as f64) / 10_000_000.0,
-                    (node.decimicro_lon as f64) / 10_000_000.0,
-                    0,
-                ));
-            }
-            OsmObj::Way(w) => {
-                let speed = determine_speed(&w);
-                let is_one_way = is_one_way(&w);
-                let no_cars = is_not_for_cars(&w);
-                let no_pedestrians = is_not_for_pedestrians(&w);
-                for (index, node) in w.nodes[0..(w.nodes.len() - 1)].iter().enumerate() {
-                    let mut edge = EdgeInfo::new(
-                        node.0 as NodeId,
-                        w.nodes[index + 1].0 as NodeId,
-                        1.1, // calculating length happens inside the graph
-                        speed,
+    let mut copies = Vec::new();
+    let mut seen_nodes = HashSet::new();
+    for p in paths {
+        let fs = File::open(p)?;
+        let mut reader = OsmPbfReader::new(fs);
+        let obj_map = reader.get_objs_and_deps(|obj| obj.tags().contains_key("highway"))?;
+
+        for (_, obj) in obj_map {
+            match obj {
+                OsmObj::Node(node) => {
+                    let node = NodeInfo::new(
+                        node.id.0 as usize,
+                        (node.decimicro_lat as f64) / 10_000_000.0,
+                        (node.decimicro_lon as f64) / 10_000_000.0,
+                        0,
                     );
-                    if no_cars && no_pedestrians {
-                        continue;
+                    if seen_nodes.insert(node.osm_id) {
+                        nodes.push(node);
                     }
-                    if no_cars {
-                        edge.not_for_cars();
-                    } else if no_pedestrians {
-                        edge.not_for_pedestrians();
-                    }
-                    edges.push(edge);
-                    if !is_one_way {
-                        let mut edge = EdgeInfo::new(
-                            w.nodes[index + 1].0 as NodeId,
-                            node.0 as NodeId,
-                            1.1, // calculating length happens inside the graph
-                            speed,
-                        );
-                        if no_cars {
-                            edge.not_for_cars();
-                        } else if no_pedestrians {
-                            edge.not_for_pedestrians();
-                        }
-                        edges.push(edge);
+                }
+                OsmObj::Way(w) => {
+                    let no_cars = is_not_for_cars(&w);
+                    let no_pedestrians = is_not_for_pedestrians(&w);
+                    if (no_cars && no_pedestrians) || w.nodes.len() < 2 {
+                        continue;
                     }
+                    copies.push((w.id.0, WayInfo {
+                        nodes: w.nodes.iter().map(|n| n.0 as OsmNodeId).collect(),
+                        speed: determine_speed(&w),
+                        one_way: is_one_way(&w),
+                        no_cars,
+                        no_pedestrians,
+                        road_class: w.tags
+                            .get("highway")
+                            .map_or(RoadClass::Other, |h| RoadClass::from_highway(h)),
+                    }));
                 }
+                _ => (),
             }
-            _ => (),
         }
     }
-    println!("Amount of Edges {}", edges.len());
+    let mut ways = dedup_ways(&seen_nodes, copies);
+    if paths.len() > 1 {
+        println!(
+            "Merged {} files into {} nodes and {} ways",
+            paths.len(),
+            nodes.len(),
+            ways.len()
+        );
+    }
+    let (split_ways, missing) = split_at_missing_nodes(&seen_nodes, ways);
+    if missing > 0 {
+        println!(
+            "Warning: {} node references of ways are missing, split the ways at them",
+            missing
+        );
+    }
+    ways = split_ways;
+    if let Some(area) = area {
+        let count = ways.len();
+        let (clipped_nodes, clipped_ways) = clip_to_area(nodes, ways, area);
+        println!("Clipped {} ways to {} parts inside of the area", count, clipped_ways.len());
+        nodes = clipped_nodes;
+        ways = clipped_ways;
+    }
+    if let Some(dem) = dem {
+        set_heights(&mut nodes, dem);
+    }
+    let node_count = nodes.len();
+    let (nodes, edges) = contract_ways(nodes, &ways);
+    println!(
+        "Amount of Edges {}, {} of {} nodes contracted into edges",
+        edges.len(),
+        node_count - nodes.len(),
+        node_count
+    );
     let start_graph = Instant::now();
-    let g = Graph::new(nodes, edges, towers);
+    let g = Graph::new(nodes, edges, towers, providers, options)?;
     let end_graph = Instant::now();
 
     println!(
@@ -82,8 +123,214 @@ pub fn load_graph<P: AsRef<Path>>(p: P, towers: &mut Vec<Tower>) -> Graph {
         start_graph.duration_since(start_loading)
     );
     println!("graph   time: {:?}", end_graph.duration_since(start_graph));
-    g
+    Ok(g)
+
+}
 
+/// The parts of a way that matter for routing
+struct WayInfo {
+    nodes: Vec<OsmNodeId>,
+    speed: Speed,
+    one_way: bool,
+    no_cars: bool,
+    no_pedestrians: bool,
+    road_class: RoadClass,
+}
+
+/// Keeps one copy of every way that is contained in several files, the
+/// one with the most nodes in `known`. Copies differ if one of the
+/// files is an extract that misses nodes at its border.
+fn dedup_ways(known: &HashSet<OsmNodeId>, ways: Vec<(i64, WayInfo)>) -> Vec<WayInfo> {
+    let resolvable = |way: &WayInfo| way.nodes.iter().filter(|id| known.contains(id)).count();
+    let mut index: HashMap<i64, usize> = HashMap::new();
+    let mut result: Vec<WayInfo> = Vec::with_capacity(ways.len());
+    for (id, way) in ways {
+        match index.get(&id).cloned() {
+            Some(i) => {
+                if resolvable(&way) > resolvable(&result[i]) {
+                    result[i] = way;
+                }
+            }
+            None => {
+                index.insert(id, result.len());
+                result.push(way);
+            }
+        }
+    }
+    result
+}
+
+/// Splits `ways` at the nodes that are not in `known`, as it happens
+/// with truncated extracts. Returns the remaining parts and the number
+/// of missing node references.
+fn split_at_missing_nodes(
+    known: &HashSet<OsmNodeId>,
+    ways: Vec<WayInfo>,
+) -> (Vec<WayInfo>, usize) {
+    let mut missing = 0;
+    let mut result = Vec::with_capacity(ways.len());
+    for way in ways {
+        let count = way.nodes.iter().filter(|id| !known.contains(id)).count();
+        if count == 0 {
+            result.push(way);
+            continue;
+        }
+        missing += count;
+        for part in way.nodes.split(|id| !known.contains(id)) {
+            if part.len() > 1 {
+                result.push(WayInfo {
+                    nodes: part.to_vec(),
+                    speed: way.speed,
+                    one_way: way.one_way,
+                    no_cars: way.no_cars,
+                    no_pedestrians: way.no_pedestrians,
+                    road_class: way.road_class,
+                });
+            }
+        }
+    }
+    (result, missing)
+}
+
+/// Cuts all ways at the border of `area` and drops their parts outside
+/// of it
+fn clip_to_area(
+    nodes: Vec<NodeInfo>,
+    ways: Vec<WayInfo>,
+    area: &ClipArea,
+) -> (Vec<NodeInfo>, Vec<WayInfo>) {
+    let mut next_id = nodes.iter().map(|n| n.osm_id).max().unwrap_or(0) + 1;
+    let mut nodes: HashMap<OsmNodeId, NodeInfo> =
+        nodes.into_iter().map(|n| (n.osm_id, n)).collect();
+    let mut clipped = Vec::new();
+    for way in ways {
+        for part in area.clip_way(&way.nodes, &mut nodes, &mut next_id) {
+            clipped.push(WayInfo {
+                nodes: part,
+                speed: way.speed,
+                one_way: way.one_way,
+                no_cars: way.no_cars,
+                no_pedestrians: way.no_pedestrians,
+                road_class: way.road_class,
+            });
+        }
+    }
+    (nodes.into_iter().map(|(_, n)| n).collect(), clipped)
+}
+
+/// Splits all ways at their end points and at the nodes they share
+/// with other ways and turns every part into a single edge. The nodes
+/// in between, which only connect two edges, become shape points of
+/// the edges and are not part of the returned nodes. Loops and parts
+/// between two nodes that are already connected are split once more,
+/// as the graph only keeps one edge between two nodes.
+fn contract_ways(nodes: Vec<NodeInfo>, ways: &[WayInfo]) -> (Vec<NodeInfo>, Vec<EdgeInfo>) {
+    let mut uses: HashMap<OsmNodeId, usize> = HashMap::new();
+    for way in ways {
+        for node in &way.nodes {
+            *uses.entry(*node).or_insert(0) += 1;
+        }
+        // end points are always kept
+        *uses.entry(way.nodes[0]).or_insert(0) += 1;
+        *uses.entry(way.nodes[way.nodes.len() - 1]).or_insert(0) += 1;
+    }
+    let mut junctions: HashSet<OsmNodeId> = uses
+        .into_iter()
+        .filter(|&(_, count)| count > 1)
+        .map(|(id, _)| id)
+        .collect();
+    let nodes: HashMap<OsmNodeId, NodeInfo> =
+        nodes.into_iter().map(|n| (n.osm_id, n)).collect();
+
+    let mut edges = Vec::new();
+    // source and target of all edges so far
+    let mut connected: HashSet<(OsmNodeId, OsmNodeId)> = HashSet::new();
+    for way in ways {
+        let mut start = 0;
+        for end in 1..way.nodes.len() {
+            if !junctions.contains(&way.nodes[end]) {
+                continue;
+            }
+            let (first, last) = (way.nodes[start], way.nodes[end]);
+            let parallel = connected.contains(&(first, last)) ||
+                (!way.one_way && connected.contains(&(last, first)));
+            let parts = if (first == last || parallel) && end - start > 1 {
+                // split loops so they don't become edges to themselves
+                // and parallel chains so they are not dropped
+                let middle = (start + end) / 2;
+                junctions.insert(way.nodes[middle]);
+                vec![(start, middle), (middle, end)]
+            } else {
+                vec![(start, end)]
+            };
+            for (from, to) in parts {
+                connected.insert((way.nodes[from], way.nodes[to]));
+                if !way.one_way {
+                    connected.insert((way.nodes[to], way.nodes[from]));
+                }
+                push_way_part(&mut edges, way, from, to, &nodes);
+            }
+            start = end;
+        }
+    }
+    let junctions = nodes
+        .into_iter()
+        .filter(|&(id, _)| junctions.contains(&id))
+        .map(|(_, n)| n)
+        .collect();
+    (junctions, edges)
+}
+
+/// Adds the edges for the nodes `start` to `end` of `way`
+fn push_way_part(
+    edges: &mut Vec<EdgeInfo>,
+    way: &WayInfo,
+    start: usize,
+    end: usize,
+    nodes: &HashMap<OsmNodeId, NodeInfo>,
+) {
+    let shape: Vec<NodeInfo> = way.nodes[start + 1..end]
+        .iter()
+        .map(|id| nodes[id].clone())
+        .collect();
+    let edge = |source, dest, shape| {
+        let mut edge = EdgeInfo::new(
+            source,
+            dest,
+            1.1, // calculating length happens inside the graph
+            way.speed,
+        );
+        if way.no_cars {
+            edge.not_for_cars();
+        } else if way.no_pedestrians {
+            edge.not_for_pedestrians();
+        }
+        edge.road_class = way.road_class;
+        edge.shape = shape;
+        edge
+    };
+    if !way.one_way {
+        let back = shape.iter().rev().cloned().collect();
+        edges.push(edge(way.nodes[end], way.nodes[start], back));
+    }
+    edges.push(edge(way.nodes[start], way.nodes[end], shape));
+}
+
+/// Sets the height of all `nodes` covered by `dem`
+fn set_heights(nodes: &mut [NodeInfo], dem: &Dem) {
+    let missing: usize = nodes
+        .par_iter_mut()
+        .map(|n| match dem.height_at(n.lat, n.long) {
+            Some(h) => {
+                n.height = h.round() as Height;
+                0
+            }
+            None => 1,
+        })
+        .sum();
+    if missing > 0 {
+        println!("No elevation data for {} of {} nodes", missing, nodes.len());
+    }
 }
 
 fn determine_speed(way: &Way) -> Speed {
@@ -149,3 +396,101 @@ fn is_not_for_pedestrians(way: &Way) -> bool {
     }
 
 }
+
+#[test]
+fn parallel_chains_are_split() {
+    let nodes = (1..7)
+        .map(|id| NodeInfo::new(id, 52.0 + id as f64 * 0.001, 13.0, 0))
+        .collect();
+    let way = |nodes| test_way(nodes, false);
+    // two roads from 1 to 2, one with a shape point, one with two
+    let ways = vec![way(vec![1, 3, 2]), way(vec![2, 4, 5, 1]), way(vec![1, 6, 1])];
+    let (nodes, edges) = contract_ways(nodes, &ways);
+
+    let mut ids: Vec<_> = nodes.iter().map(|n| n.osm_id).collect();
+    ids.sort();
+    // the second chain and the loop are split in the middle
+    assert_eq!(vec![1, 2, 4, 6], ids);
+    assert_eq!(10, edges.len());
+    let shapes: usize = edges.iter().map(|e| e.shape.len()).sum();
+    assert_eq!(4, shapes);
+}
+
+#[cfg(test)]
+fn test_way(nodes: Vec<OsmNodeId>, one_way: bool) -> WayInfo {
+    WayInfo {
+        nodes,
+        speed: 50,
+        one_way,
+        no_cars: false,
+        no_pedestrians: false,
+        road_class: RoadClass::Residential,
+    }
+}
+
+#[test]
+fn duplicate_ways_keep_the_most_complete_copy() {
+    let known: HashSet<OsmNodeId> = vec![1, 2, 3].into_iter().collect();
+    // the copies of way 10 from extracts that miss some of its nodes
+    // are replaced by the one whose nodes are all known
+    let ways = vec![
+        (10, test_way(vec![1, 9, 3], false)),
+        (11, test_way(vec![2, 3], false)),
+        (10, test_way(vec![1, 2], false)),
+        (10, test_way(vec![1, 2, 3], false)),
+    ];
+    let ways = dedup_ways(&known, ways);
+    let nodes: Vec<_> = ways.iter().map(|w| w.nodes.clone()).collect();
+    assert_eq!(vec![vec![1, 2, 3], vec![2, 3]], nodes);
+}
+
+#[test]
+fn ways_are_split_at_missing_nodes() {
+    let known: HashSet<OsmNodeId> = vec![1, 2, 3, 5, 6].into_iter().collect();
+    let ways = vec![
+        test_way(vec![1, 2, 4, 5, 6], true),
+        test_way(vec![3, 8], false),
+        test_way(vec![2, 3], false),
+    ];
+    let (ways, missing) = split_at_missing_nodes(&known, ways);
+    assert_eq!(2, missing);
+    let nodes: Vec<_> = ways.iter().map(|w| w.nodes.clone()).collect();
+    assert_eq!(vec![vec![1, 2], vec![5, 6], vec![2, 3]], nodes);
+    assert!(ways[0].one_way && ways[1].one_way && !ways[2].one_way);
+}
+
+#[test]
+fn chains_are_contracted_into_shape_points() {
+    let nodes = (1..7)
+        .map(|id| NodeInfo::new(id, 52.0 + id as f64 * 0.001, 13.0, 0))
+        .collect();
+    let ways = vec![
+        test_way(vec![1, 2, 3, 4], false),
+        test_way(vec![3, 5], false),
+        test_way(vec![4, 6], true),
+    ];
+    let (nodes, edges) = contract_ways(nodes, &ways);
+
+    let mut ids: Vec<_> = nodes.iter().map(|n| n.osm_id).collect();
+    ids.sort();
+    assert_eq!(vec![1, 3, 4, 5, 6], ids);
+    let mut pairs: Vec<_> = edges
+        .iter()
+        .map(|e| {
+            (e.source, e.dest, e.shape.iter().map(|n| n.osm_id).collect::<Vec<_>>())
+        })
+        .collect();
+    pairs.sort();
+    assert_eq!(
+        vec![
+            (1, 3, vec![2]),
+            (3, 1, vec![2]),
+            (3, 4, vec![]),
+            (3, 5, vec![]),
+            (4, 3, vec![]),
+            (4, 6, vec![]),
+            (5, 3, vec![]),
+        ],
+        pairs
+    );
+}
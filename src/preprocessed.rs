@@ -0,0 +1,450 @@
+use error::Error;
+use flat::{ALIGN, Backing, Section, serialize_flat, deserialize_flat};
+use ApplicationState;
+
+use bincode;
+use memmap::Mmap;
+
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// First bytes of every preprocessed graph file
+pub const MAGIC: &'static [u8; 8] = b"DZRGRAPH";
+/// Version of the container format. Version 1 stored the state as a
+/// single bincode blob, version 2 edges without road class, version 3
+/// sections without the layout of their elements, version 4 a shape
+/// for every edge, version 5 no drive test calibration, version 6 no
+/// coverage settings. Files without a header were written by releases
+/// before version 1 and are rejected.
+pub const FORMAT_VERSION: u32 = 7;
+
+/// Identifies a file a graph was built from
+#[derive(Debug, Clone, PartialEq, HeapSizeOf, Serialize, Deserialize)]
+pub struct SourceFingerprint {
+    pub path: String,
+    pub size: u64,
+    /// FNV-1a hash of the content
+    pub hash: u64,
+}
+
+/// Follows magic bytes and format version in front of the graph.
+///
+/// The arrays of the graph are stored in sections aligned to `ALIGN`
+/// bytes behind the rest of the state so they can be used in place.
+/// Only the rest is covered by the checksum, checking the sections
+/// would mean reading the whole file on startup. Instead the sections
+/// record the layout of their elements and the graph is validated
+/// after loading.
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    /// Commit of the build that wrote the file
+    build: String,
+    sources: Vec<SourceFingerprint>,
+    /// Size of `usize` in bytes on the writing machine
+    word_size: u8,
+    little_endian: bool,
+    /// Length of the serialized rest of the state in bytes
+    length: u64,
+    /// FNV-1a hash of the serialized rest
+    checksum: u64,
+    /// Offset from the start of the file, length and element layout
+    /// of every section
+    sections: Vec<Section>,
+}
+
+/// Commit the running binary was built from
+pub fn build_hash() -> &'static str {
+    match option_env!("DZR_BUILD_HASH") {
+        Some(hash) if !hash.is_empty() => hash,
+        _ => "unknown",
+    }
+}
+
+/// 64 bit FNV-1a, stable across builds unlike the default hasher
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Fnv {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= u64::from(b);
+            self.0 = self.0.wrapping_mul(0x100_0000_01b3);
+        }
+    }
+}
+
+pub fn checksum(bytes: &[u8]) -> u64 {
+    let mut hasher = Fnv::new();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+/// Fingerprints the file at `p` by its size and content
+pub fn fingerprint<P: AsRef<Path>>(p: P) -> Result<SourceFingerprint, Error> {
+    let mut reader = BufReader::new(File::open(&p)?);
+    let mut hasher = Fnv::new();
+    let mut size = 0;
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.write(&buffer[..read]);
+        size += read as u64;
+    }
+    Ok(SourceFingerprint {
+        path: p.as_ref().to_string_lossy().into_owned(),
+        size,
+        hash: hasher.finish(),
+    })
+}
+
+/// Writes `state` in the preprocessed graph format
+pub fn write_preprocessed<W: Write>(w: &mut W, state: &ApplicationState) -> Result<(), Error> {
+    Container::new(state)?.write_to(w)
+}
+
+/// Writes `state` to a temporary file next to `path` and renames it
+/// to `path` once it is complete. Processes that have the old file
+/// mapped keep using it, as it is replaced instead of overwritten.
+pub fn save_preprocessed<P: AsRef<Path>>(path: P, state: &ApplicationState) -> Result<(), Error> {
+    let path = path.as_ref();
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let result = write_file(&temp, state).and_then(|_| {
+        fs::rename(&temp, path).map_err(Error::from)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+fn write_file(path: &Path, state: &ApplicationState) -> Result<(), Error> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_preprocessed(&mut writer, state)?;
+    let file = writer.into_inner().map_err(io::Error::from)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// A state laid out in the preprocessed graph format
+pub struct Container<'a> {
+    header: Header,
+    /// Position of the first byte after the header
+    header_end: usize,
+    rest: Vec<u8>,
+    sections: Vec<&'a [u8]>,
+}
+
+impl<'a> Container<'a> {
+    pub fn new(state: &'a ApplicationState) -> Result<Container<'a>, Error> {
+        let (rest, flat_sections) = serialize_flat(state)?;
+        let (table, sections): (Vec<Section>, Vec<&'a [u8]>) = flat_sections.into_iter().unzip();
+        let mut header = Header {
+            build: build_hash().to_string(),
+            sources: state.sources.clone(),
+            word_size: mem::size_of::<usize>() as u8,
+            little_endian: cfg!(target_endian = "little"),
+            length: rest.len() as u64,
+            checksum: checksum(&rest),
+            sections: table,
+        };
+        // integers have a fixed size, the offsets don't change the size
+        // of the header
+        let header_end = MAGIC.len() + 4 + bincode::serialized_size(&header) as usize;
+        let mut position = header_end + rest.len();
+        for section in &mut header.sections {
+            position = (position + ALIGN - 1) / ALIGN * ALIGN;
+            section.offset = position;
+            position += section.length;
+        }
+        Ok(Container {
+            header,
+            header_end,
+            rest,
+            sections,
+        })
+    }
+
+    /// Size of the whole file in bytes
+    pub fn len(&self) -> u64 {
+        match self.header.sections.last() {
+            Some(section) => (section.offset + section.length) as u64,
+            None => (self.header_end + self.rest.len()) as u64,
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+        w.write_all(MAGIC)?;
+        w.write_all(&version_bytes(FORMAT_VERSION))?;
+        bincode::serialize_into(&mut *w, &self.header, bincode::Infinite)?;
+        w.write_all(&self.rest)?;
+        let mut position = self.header_end + self.rest.len();
+        for (section, content) in self.header.sections.iter().zip(&self.sections) {
+            w.write_all(&[0; ALIGN][..section.offset - position])?;
+            w.write_all(content)?;
+            position = section.offset + content.len();
+        }
+        Ok(())
+    }
+}
+
+fn version_bytes(version: u32) -> [u8; 4] {
+    [
+        version as u8,
+        (version >> 8) as u8,
+        (version >> 16) as u8,
+        (version >> 24) as u8,
+    ]
+}
+
+/// Reads a preprocessed graph. The arrays of the graph are copied
+/// into memory, use `load_preprocessed_graph` to map them instead.
+pub fn read_preprocessed<R: Read>(mut r: R) -> Result<ApplicationState, Error> {
+    let mut bytes = Vec::new();
+    r.read_to_end(&mut bytes)?;
+    read_backing(Arc::new(Backing::from_bytes(&bytes)))
+}
+
+/// Reads a preprocessed graph of the current format version that was
+/// written by the running build
+fn read_backing(backing: Arc<Backing>) -> Result<ApplicationState, Error> {
+    let bytes = backing.bytes();
+    if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(Error::InvalidGraphFile(
+            "no header, either not a graph or written by an older release, preprocess it again"
+                .to_string(),
+        ));
+    }
+    let header_start = MAGIC.len() + 4;
+    if bytes.len() < header_start {
+        return Err(Error::InvalidGraphFile("truncated header".to_string()));
+    }
+    let version = bytes[MAGIC.len()..header_start]
+        .iter()
+        .rev()
+        .fold(0, |v, &b| (v << 8) | u32::from(b));
+    let mut cursor = Cursor::new(&bytes[header_start..]);
+    let truncated = |_: bincode::Error| Error::InvalidGraphFile("truncated header".to_string());
+    match version {
+        FORMAT_VERSION => {
+            let header: Header = bincode::deserialize_from(&mut cursor, bincode::Infinite)
+                .map_err(&truncated)?;
+            if header.word_size as usize != mem::size_of::<usize>() ||
+                header.little_endian != cfg!(target_endian = "little")
+            {
+                return Err(Error::InvalidGraphFile(
+                    "written on a machine with a different word size or byte order".to_string(),
+                ));
+            }
+            let start = header_start + cursor.position() as usize;
+            let rest = checked_part(bytes, start, header.length, header.checksum)?;
+            let sections = header.sections;
+            if sections.iter().any(|s| s.offset + s.length > bytes.len()) {
+                return Err(Error::InvalidGraphFile("truncated sections".to_string()));
+            }
+            // the sections are used in place, so the types in them have
+            // to be exactly the ones of the running build
+            if header.build != build_hash() {
+                return Err(Error::InvalidGraphFile(format!(
+                    "written by build {}, this is build {}, preprocess the graph again",
+                    header.build,
+                    build_hash()
+                )));
+            }
+            let mut state = deserialize_flat(rest, backing.clone(), sections)?;
+            state.graph.validate(state.towers.len())?;
+            state.sources = header.sources;
+            Ok(state)
+        }
+        v => Err(Error::UnsupportedVersion(v, FORMAT_VERSION)),
+    }
+}
+
+/// `length` bytes of `bytes` from `start` on if they match `checksum`
+fn checked_part(bytes: &[u8], start: usize, length: u64, expected: u64) -> Result<&[u8], Error> {
+    let end = start + length as usize;
+    if end > bytes.len() {
+        return Err(Error::InvalidGraphFile(format!(
+            "truncated, {} of {} bytes",
+            bytes.len().saturating_sub(start),
+            length
+        )));
+    }
+    if checksum(&bytes[start..end]) != expected {
+        return Err(Error::InvalidGraphFile("checksum mismatch".to_string()));
+    }
+    Ok(&bytes[start..end])
+}
+
+/// Maps the preprocessed graph at `path` into memory. Its arrays are
+/// used in place, so several processes share the same pages. The
+/// file must not be changed while it is mapped.
+pub fn load_preprocessed_graph<P: AsRef<Path>>(path: P) -> Result<ApplicationState, Error> {
+    let start = Instant::now();
+    let file = File::open(path)?;
+    let map = unsafe { Mmap::map(&file)? };
+    let state = read_backing(Arc::new(Backing::Map(map)))?;
+    let end = Instant::now();
+    println!(
+        "loaded preprocessed graph in {:?}",
+        end.duration_since(start)
+    );
+    for source in &state.sources {
+        println!("  built from {} ({} bytes)", source.path, source.size);
+    }
+    Ok(state)
+}
+
+#[test]
+fn preprocessed_roundtrip() {
+    use graph::{Graph, NodeInfo, EdgeInfo, HalfEdge};
+    use towers::CoverageSpec;
+    use flat::{Flat, Store};
+
+    let mut towers = Vec::new();
+    let graph = Graph::new(
+        vec![
+            NodeInfo::new(1, 52.0, 13.0, 0),
+            NodeInfo::new(2, 52.001, 13.0, 0),
+        ],
+        vec![EdgeInfo::new(1, 2, 1.0, 50)],
+        &mut towers,
+        Default::default(),
+        &Default::default(),
+    ).unwrap();
+    let source = SourceFingerprint {
+        path: "test.pbf".to_string(),
+        size: 3,
+        hash: checksum(b"pbf"),
+    };
+    let state = ApplicationState {
+        graph,
+        towers,
+        coverage: CoverageSpec {
+            line_of_sight: true,
+            antenna_height: 30.0,
+            rasters: vec!["262-01:LTE:lte.asc".to_string()],
+            ..Default::default()
+        },
+        sources: vec![source.clone()],
+    };
+    let mut bytes = Vec::new();
+    write_preprocessed(&mut bytes, &state).unwrap();
+    let read = read_preprocessed(&bytes[..]).unwrap();
+    assert_eq!(vec![source], read.sources);
+    assert_eq!(state.coverage, read.coverage);
+    assert_eq!(1, read.graph.edges.len());
+
+    assert_eq!(&state.graph.node_info[..], &read.graph.node_info[..]);
+    match read.graph.edges {
+        Store::Mapped { .. } => (),
+        Store::Owned(_) => panic!("arrays have to be used in place"),
+    }
+
+    let rest = {
+        let mut cursor = Cursor::new(&bytes[MAGIC.len() + 4..]);
+        let _: Header = bincode::deserialize_from(&mut cursor, bincode::Infinite).unwrap();
+        MAGIC.len() + 4 + cursor.position() as usize
+    };
+    bytes[rest] ^= 1;
+    assert!(read_preprocessed(&bytes[..]).is_err());
+    bytes[rest] ^= 1;
+    assert!(read_preprocessed(&bytes[..bytes.len() / 2]).is_err());
+    bytes[MAGIC.len()] = 8;
+    match read_preprocessed(&bytes[..]) {
+        Err(Error::UnsupportedVersion(8, FORMAT_VERSION)) => (),
+        _ => panic!("newer versions have to be rejected"),
+    }
+    // version 6 has no coverage settings
+    bytes[MAGIC.len()] = 6;
+    match read_preprocessed(&bytes[..]) {
+        Err(Error::UnsupportedVersion(6, FORMAT_VERSION)) => (),
+        _ => panic!("older versions have to be rejected"),
+    }
+    bytes[MAGIC.len()] = FORMAT_VERSION as u8;
+    match read_preprocessed(&bytes[MAGIC.len()..]) {
+        Err(Error::InvalidGraphFile(_)) => (),
+        _ => panic!("files without header have to be rejected"),
+    }
+    // the build follows the length of its name
+    let build = MAGIC.len() + 4 + 8;
+    bytes[build] ^= 0x20;
+    match read_preprocessed(&bytes[..]) {
+        Err(Error::InvalidGraphFile(_)) => (),
+        _ => panic!("files of other builds have to be rejected"),
+    }
+    bytes[build] ^= 0x20;
+
+    // flags of mapped edges have to be 0 or 1
+    let header: Header = bincode::deserialize_from(
+        &mut Cursor::new(&bytes[MAGIC.len() + 4..]),
+        bincode::Infinite,
+    ).unwrap();
+    let edges = header
+        .sections
+        .iter()
+        .find(|s| s.layout == HalfEdge::LAYOUT)
+        .unwrap();
+    let for_cars = edges.offset + mem::size_of::<usize>() + 16;
+    bytes[for_cars] = 2;
+    match read_preprocessed(&bytes[..]) {
+        Err(Error::InvalidGraphFile(_)) => (),
+        _ => panic!("invalid flags have to be rejected"),
+    }
+}
+
+#[test]
+fn saving_replaces_the_file() {
+    use graph::{Graph, NodeInfo, EdgeInfo};
+    use std::env;
+
+    let mut towers = Vec::new();
+    let graph = Graph::new(
+        vec![
+            NodeInfo::new(1, 52.0, 13.0, 0),
+            NodeInfo::new(2, 52.001, 13.0, 0),
+        ],
+        vec![EdgeInfo::new(1, 2, 1.0, 50)],
+        &mut towers,
+        Default::default(),
+        &Default::default(),
+    ).unwrap();
+    let mut state = ApplicationState {
+        graph,
+        towers,
+        coverage: Default::default(),
+        sources: Vec::new(),
+    };
+    let path = env::temp_dir().join("dzr-saving-replaces-the-file.graph");
+    save_preprocessed(&path, &state).unwrap();
+    let mapped = load_preprocessed_graph(&path).unwrap();
+
+    state.graph.node_info[0].height = 10;
+    save_preprocessed(&path, &state).unwrap();
+    // the mapped file is replaced, not changed
+    assert_eq!(0, mapped.graph.node_info[0].height);
+    let read = load_preprocessed_graph(&path).unwrap();
+    assert_eq!(10, read.graph.node_info[0].height);
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    assert!(!PathBuf::from(temp).exists());
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,272 @@
+use graph::NodeInfo;
+use geom::haversine_distance;
+use towers::{Tower, TowerType};
+use provider::{Provider, ProviderRegistry};
+
+use std::collections::HashMap;
+use std::{f32, f64};
+use std::str::FromStr;
+
+use rayon::prelude::*;
+
+/// Received signal level in dBm that is treated as no signal at all
+pub const NO_SIGNAL: f64 = -140.0;
+/// Signal level in dBm at which an edge counts as not usable for routing
+const WORST_USABLE: f64 = -120.0;
+/// Signal level in dBm from which on an edge counts as perfectly covered
+const BEST_USABLE: f64 = -70.0;
+/// Height of the base station antenna in meters
+const BASE_HEIGHT: f64 = 30.0;
+/// Height of the mobile antenna in meters
+const MOBILE_HEIGHT: f64 = 1.5;
+/// Distance in meters between the points sampled along an edge
+const SAMPLE_STEP: f64 = 25.0;
+
+/// Models to estimate the path loss between a tower and a receiver
+#[derive(Debug, Clone, Copy, PartialEq, HeapSizeOf, Serialize, Deserialize)]
+pub enum PropagationModel {
+    /// Free-space path loss, an optimistic upper bound
+    FreeSpace,
+    /// Okumura-Hata below 1500 MHz and its COST-231 extension above
+    Hata,
+}
+
+impl TowerType {
+    /// Carrier frequency in MHz usually used for this radio technology
+    fn frequency(&self) -> f64 {
+        match *self {
+            TowerType::CDMA => 850.0,
+            TowerType::GSM => 900.0,
+            TowerType::UMTS => 2100.0,
+            TowerType::LTE => 1800.0,
+            TowerType::NR => 3500.0,
+        }
+    }
+
+    /// Typical effective isotropic radiated power in dBm
+    fn eirp(&self) -> f64 {
+        match *self {
+            TowerType::GSM | TowerType::CDMA => 57.0,
+            TowerType::UMTS => 58.0,
+            TowerType::LTE => 61.0,
+            TowerType::NR => 64.0,
+        }
+    }
+}
+
+impl PropagationModel {
+    /// Path loss in dB of a signal sent by a tower of type `radio`
+    /// over `distance` meters
+    pub fn path_loss(&self, radio: &TowerType, distance: f64) -> f64 {
+        let d = (distance / 1000.0).max(0.01).log10();
+        let f = radio.frequency().log10();
+        match *self {
+            PropagationModel::FreeSpace => 20.0 * d + 20.0 * f + 32.44,
+            PropagationModel::Hata => {
+                let hb = BASE_HEIGHT.log10();
+                let a_hm = (1.1 * f - 0.7) * MOBILE_HEIGHT - (1.56 * f - 0.8);
+                let base = match *radio {
+                    TowerType::GSM | TowerType::CDMA => 69.55 + 26.16 * f,
+                    TowerType::UMTS | TowerType::LTE | TowerType::NR => 46.3 + 33.9 * f,
+                };
+                base - 13.82 * hb - a_hm + (44.9 - 6.55 * hb) * d
+            }
+        }
+    }
+
+    /// Estimated signal level in dBm received `distance` meters away from `tower`
+    pub fn received_level(&self, tower: &Tower, distance: f64) -> f64 {
+        tower.radio.eirp() - self.path_loss(&tower.radio, distance)
+    }
+}
+
+impl FromStr for PropagationModel {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "free-space" => Ok(PropagationModel::FreeSpace),
+            "hata" => Ok(PropagationModel::Hata),
+            _ => Err(format!("unknown propagation model {}", s)),
+        }
+    }
+}
+
+/// Minimum and mean signal level in dBm along an edge
+#[derive(Debug, Clone, Copy, PartialEq, HeapSizeOf, Serialize, Deserialize)]
+pub struct EdgeSignal {
+    pub min: f32,
+    pub mean: f32,
+}
+
+impl EdgeSignal {
+    /// Maps the signal level to a quality between 0.0 (unusable) and
+    /// 1.0 (perfect). Weak spots count as much as the average level.
+    pub fn quality(&self) -> f64 {
+        let scale = |dbm: f32| {
+            ((dbm as f64 - WORST_USABLE) / (BEST_USABLE - WORST_USABLE))
+                .max(0.0)
+                .min(1.0)
+        };
+        (scale(self.min) + scale(self.mean)) / 2.0
+    }
+}
+
+/// Estimated signal levels for all edge provider combinations
+#[derive(HeapSizeOf, Serialize, Deserialize)]
+pub struct SignalLevels(HashMap<Provider, Vec<EdgeSignal>>);
+
+impl SignalLevels {
+    /// Estimates the signal levels of `edge_count` edges in parallel.
+    /// `edge_levels` is called with the index of every edge.
+    pub fn calculate<F>(
+        edge_count: usize,
+        providers: &ProviderRegistry,
+        edge_levels: F,
+    ) -> SignalLevels
+    where
+        F: Fn(usize) -> Vec<EdgeSignal> + Sync,
+    {
+        let per_edge: Vec<Vec<EdgeSignal>> = (0..edge_count)
+            .into_par_iter()
+            .map(&edge_levels)
+            .collect();
+
+        let map = providers
+            .providers()
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (*p, per_edge.iter().map(|e| e[i]).collect()))
+            .collect();
+        SignalLevels(map)
+    }
+
+    pub fn get_all(&self, p: Option<Provider>) -> Option<&Vec<EdgeSignal>> {
+        p.and_then(|p| self.0.get(&p))
+    }
+
+    /// Overwrites the signal levels of edge `n` with the per provider
+    /// levels `signal` as returned by `edge_signal`
+    pub fn set_edge(&mut self, providers: &ProviderRegistry, n: usize, signal: &[EdgeSignal]) {
+        for (p, level) in providers.providers().iter().zip(signal) {
+            if let Some(levels) = self.0.get_mut(p) {
+                levels[n] = *level;
+            }
+        }
+    }
+}
+
+/// Samples points along the edge between `s` and `t` and calculates
+/// the best signal level of each provider at every point
+pub fn edge_signal(
+    s: &NodeInfo,
+    t: &NodeInfo,
+    towers: &[&Tower],
+    providers: &ProviderRegistry,
+    model: PropagationModel,
+) -> Vec<EdgeSignal> {
+    let length = haversine_distance(s, t);
+    let samples = ((length / SAMPLE_STEP).ceil() as usize + 1).max(2);
+
+    let mut min = vec![f64::MAX; providers.len()];
+    let mut sum = vec![0.0; providers.len()];
+    for i in 0..samples {
+        let f = i as f64 / (samples - 1) as f64;
+        let point = (s.lat + f * (t.lat - s.lat), s.long + f * (t.long - s.long));
+        let mut best = vec![NO_SIGNAL; providers.len()];
+        for tower in towers {
+            let dist = haversine_distance(*tower, &point);
+            if dist > tower.range || !tower.faces(&point) {
+                continue;
+            }
+            if let Some(index) = providers.index_of(&tower.provider()) {
+                best[index] = best[index].max(model.received_level(tower, dist));
+            }
+        }
+        for p in 0..providers.len() {
+            min[p] = min[p].min(best[p]);
+            sum[p] += best[p];
+        }
+    }
+    min.into_iter()
+        .zip(sum)
+        .map(|(min, sum)| {
+            EdgeSignal {
+                min: min as f32,
+                mean: (sum / samples as f64) as f32,
+            }
+        })
+        .collect()
+}
+
+/// Signal levels along an edge running through `points`. Every
+/// subsegment is sampled with `edge_signal`, the means are weighted by
+/// the lengths of the subsegments. `adjacent` has to yield all towers
+/// which might reach a point.
+pub fn polyline_signal<'a, F>(
+    points: &[NodeInfo],
+    adjacent: F,
+    providers: &ProviderRegistry,
+    model: PropagationModel,
+) -> Vec<EdgeSignal>
+where
+    F: Fn(&NodeInfo) -> Vec<&'a Tower>,
+{
+    let mut min = vec![f32::MAX; providers.len()];
+    let mut sum = vec![0.0; providers.len()];
+    let mut total = 0.0;
+    for pair in points.windows(2) {
+        let length = haversine_distance(&pair[0], &pair[1]).max(f64::EPSILON);
+        let levels = edge_signal(&pair[0], &pair[1], &adjacent(&pair[0]), providers, model);
+        for (p, level) in levels.into_iter().enumerate() {
+            min[p] = min[p].min(level.min);
+            sum[p] += level.mean as f64 * length;
+        }
+        total += length;
+    }
+    min.into_iter()
+        .zip(sum)
+        .map(|(min, sum)| {
+            EdgeSignal {
+                min,
+                mean: (sum / total) as f32,
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn signal_decreases_with_distance() {
+    for model in &[PropagationModel::FreeSpace, PropagationModel::Hata] {
+        for radio in &[
+            TowerType::CDMA,
+            TowerType::GSM,
+            TowerType::UMTS,
+            TowerType::LTE,
+            TowerType::NR,
+        ]
+        {
+            assert!(model.path_loss(radio, 100.0) < model.path_loss(radio, 1000.0));
+        }
+    }
+}
+
+#[test]
+fn hata_is_more_pessimistic_than_free_space() {
+    let free = PropagationModel::FreeSpace.path_loss(&TowerType::LTE, 2000.0);
+    let hata = PropagationModel::Hata.path_loss(&TowerType::LTE, 2000.0);
+    assert!(free < hata);
+}
+
+#[test]
+fn quality_of_signal() {
+    let good = EdgeSignal {
+        min: -60.0,
+        mean: -50.0,
+    };
+    let none = EdgeSignal {
+        min: NO_SIGNAL as f32,
+        mean: NO_SIGNAL as f32,
+    };
+    assert_eq!(1.0, good.quality());
+    assert_eq!(0.0, none.quality());
+}
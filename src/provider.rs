@@ -0,0 +1,152 @@
+use towers::Tower;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+
+use csv::Reader;
+
+/// Mobile network identified by its mobile country code (MCC) and
+/// mobile network code (MNC)
+#[derive(Debug, HeapSizeOf, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Serialize,
+         Deserialize)]
+pub struct Provider {
+    pub mcc: u16,
+    pub mnc: u16,
+}
+
+impl Provider {
+    pub fn new(mcc: u16, mnc: u16) -> Provider {
+        Provider { mcc, mnc }
+    }
+}
+
+impl fmt::Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{:02}", self.mcc, self.mnc)
+    }
+}
+
+/// Selects whose towers count for the coverage of an edge
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, HeapSizeOf, Serialize, Deserialize)]
+pub enum ProviderSelection {
+    /// Only towers of this provider
+    Single(Provider),
+    /// Covered by any provider, e.g. for roaming or multi-SIM routers
+    Any,
+    /// Covered by every provider at the same time
+    All,
+    /// Covered by at least this many providers at the same time
+    AtLeast(usize),
+}
+
+impl ProviderSelection {
+    /// Expresses `Any` and `All` as the equivalent `AtLeast` for
+    /// `provider_count` providers
+    pub fn normalize(&self, provider_count: usize) -> ProviderSelection {
+        match *self {
+            ProviderSelection::Any => ProviderSelection::AtLeast(1),
+            ProviderSelection::All => ProviderSelection::AtLeast(provider_count),
+            other => other,
+        }
+    }
+
+    /// The provider if only a single one is selected
+    pub fn single(&self) -> Option<Provider> {
+        match *self {
+            ProviderSelection::Single(p) => Some(p),
+            _ => None,
+        }
+    }
+}
+
+/// All providers that have towers in the loaded data set. Per
+/// provider values are stored in the order of this registry.
+#[derive(Debug, Default, HeapSizeOf, Serialize, Deserialize)]
+pub struct ProviderRegistry {
+    providers: Vec<Provider>,
+    names: Vec<String>,
+}
+
+impl ProviderRegistry {
+    /// Collects the providers of all `towers`. Providers without an
+    /// entry in `names` are named after their MCC and MNC.
+    pub fn new(towers: &[Tower], names: &HashMap<Provider, String>) -> ProviderRegistry {
+        ProviderRegistry::from_providers(towers.iter().map(Tower::provider), names)
+    }
+
+    /// Like `new` but takes the providers directly, which may contain
+    /// duplicates
+    pub fn from_providers<I>(providers: I, names: &HashMap<Provider, String>) -> ProviderRegistry
+    where
+        I: IntoIterator<Item = Provider>,
+    {
+        let mut providers: Vec<Provider> = providers.into_iter().collect();
+        providers.sort();
+        providers.dedup();
+        let names = providers
+            .iter()
+            .map(|p| names.get(p).cloned().unwrap_or_else(|| p.to_string()))
+            .collect();
+        ProviderRegistry { providers, names }
+    }
+
+    pub fn len(&self) -> usize {
+        self.providers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.providers.is_empty()
+    }
+
+    /// Position of `p` in per provider values
+    #[inline]
+    pub fn index_of(&self, p: &Provider) -> Option<usize> {
+        self.providers.binary_search(p).ok()
+    }
+
+    pub fn providers(&self) -> &[Provider] {
+        &self.providers
+    }
+
+    pub fn name(&self, index: usize) -> &str {
+        &self.names[index]
+    }
+
+    /// Parses a provider selection like the `provider` parameter of
+    /// routes. Single providers may also be given by their name.
+    pub fn parse_selection(&self, s: &str) -> Option<ProviderSelection> {
+        if let Ok(selection) = s.parse() {
+            return Some(selection);
+        }
+        self.names
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(s))
+            .map(|i| ProviderSelection::Single(self.providers[i]))
+    }
+
+    /// Names of all providers to carry them over to a new registry
+    pub fn name_map(&self) -> HashMap<Provider, String> {
+        self.providers.iter().cloned().zip(self.names.iter().cloned()).collect()
+    }
+}
+
+#[derive(Deserialize)]
+struct NameRecord {
+    mcc: u16,
+    mnc: u16,
+    name: String,
+}
+
+/// Loads a CSV file with the columns `mcc`, `mnc` and `name` that
+/// assigns readable names to providers
+pub fn load_provider_names<P: AsRef<Path>>(p: P) -> Result<HashMap<Provider, String>, Box<Error>> {
+    let mut reader = Reader::from_path(p)?;
+    let mut names = HashMap::new();
+    for res in reader.deserialize() {
+        let record: NameRecord = res?;
+        names.insert(Provider::new(record.mcc, record.mnc), record.name);
+    }
+    Ok(names)
+}
@@ -0,0 +1,305 @@
+use graph::NodeInfo;
+use coverage::CoverageSource;
+use provider::{Provider, ProviderRegistry};
+use towers::{TechClass, TowerType};
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+/// A raster in the ESRI ASCII grid format with WGS84 coordinates
+#[derive(Debug)]
+pub struct AsciiGrid {
+    ncols: usize,
+    nrows: usize,
+    /// Longitude of the lower left corner
+    xll: f64,
+    /// Latitude of the lower left corner
+    yll: f64,
+    cellsize: f64,
+    nodata: Option<f64>,
+    /// Values row by row starting with the northernmost row
+    values: Vec<f32>,
+}
+
+impl AsciiGrid {
+    pub fn from_path<P: AsRef<Path>>(p: P) -> Result<AsciiGrid, Box<Error>> {
+        AsciiGrid::from_reader(File::open(p)?)
+    }
+
+    pub fn from_reader<R: Read>(r: R) -> Result<AsciiGrid, Box<Error>> {
+        let mut ncols = None;
+        let mut nrows = None;
+        let mut xll = None;
+        let mut yll = None;
+        let mut center = false;
+        let mut cellsize = None;
+        let mut nodata = None;
+        let mut values = Vec::new();
+        for line in BufReader::new(r).lines() {
+            let line = line?;
+            let mut fields = line.split_whitespace().peekable();
+            let header = match fields.peek() {
+                Some(field) => field.chars().next().map_or(false, char::is_alphabetic),
+                None => continue,
+            };
+            if !header {
+                for field in fields {
+                    values.push(field.parse()?);
+                }
+                continue;
+            }
+            let key = fields.next().unwrap().to_lowercase();
+            let value = fields.next().ok_or_else(|| format!("no value for {}", key))?;
+            match key.as_str() {
+                "ncols" => ncols = Some(value.parse()?),
+                "nrows" => nrows = Some(value.parse()?),
+                "xllcorner" => xll = Some(value.parse()?),
+                "yllcorner" => yll = Some(value.parse()?),
+                "xllcenter" => {
+                    xll = Some(value.parse()?);
+                    center = true;
+                }
+                "yllcenter" => {
+                    yll = Some(value.parse()?);
+                    center = true;
+                }
+                "cellsize" => cellsize = Some(value.parse()?),
+                "nodata_value" => nodata = Some(value.parse()?),
+                _ => return Err(format!("unknown header {}", key).into()),
+            }
+        }
+        let missing = |name: &str| format!("header {} missing", name);
+        let ncols: usize = ncols.ok_or_else(|| missing("ncols"))?;
+        let nrows: usize = nrows.ok_or_else(|| missing("nrows"))?;
+        let cellsize: f64 = cellsize.ok_or_else(|| missing("cellsize"))?;
+        let mut xll: f64 = xll.ok_or_else(|| missing("xllcorner"))?;
+        let mut yll: f64 = yll.ok_or_else(|| missing("yllcorner"))?;
+        if center {
+            xll -= cellsize / 2.0;
+            yll -= cellsize / 2.0;
+        }
+        if values.len() != ncols * nrows {
+            return Err(
+                format!("expected {} values but found {}", ncols * nrows, values.len()).into(),
+            );
+        }
+        Ok(AsciiGrid {
+            ncols,
+            nrows,
+            xll,
+            yll,
+            cellsize,
+            nodata,
+            values,
+        })
+    }
+
+    /// Value of the cell containing the coordinate. `None` outside of
+    /// the raster and for cells without data.
+    pub fn value_at(&self, lat: f64, lon: f64) -> Option<f64> {
+        let col = ((lon - self.xll) / self.cellsize).floor();
+        let row_from_bottom = ((lat - self.yll) / self.cellsize).floor();
+        if col < 0.0 || row_from_bottom < 0.0 || col >= self.ncols as f64 ||
+            row_from_bottom >= self.nrows as f64
+        {
+            return None;
+        }
+        let row = self.nrows - 1 - row_from_bottom as usize;
+        let value = self.values[row * self.ncols + col as usize] as f64;
+        match self.nodata {
+            Some(nodata) if nodata == value => None,
+            _ => Some(value),
+        }
+    }
+}
+
+/// Coverage map of one provider and radio technology
+#[derive(Debug)]
+pub struct RasterLayer {
+    pub provider: Provider,
+    pub radio: TowerType,
+    /// Cells with at least this value count as covered
+    pub min_value: f64,
+    pub grid: AsciiGrid,
+}
+
+impl RasterLayer {
+    /// Loads a layer described as `<mcc>-<mnc>:<radio>:<file>` with an
+    /// optional `:<min value>`, which defaults to 1 for maps that mark
+    /// covered cells with 1 and uncovered ones with 0
+    pub fn from_spec(spec: &str) -> Result<RasterLayer, Box<Error>> {
+        let parts: Vec<&str> = spec.split(':').collect();
+        if parts.len() < 3 || parts.len() > 4 {
+            return Err(format!("invalid raster layer {}", spec).into());
+        }
+        let provider: Vec<&str> = parts[0].splitn(2, '-').collect();
+        if provider.len() != 2 {
+            return Err(format!("invalid provider {}", parts[0]).into());
+        }
+        Ok(RasterLayer {
+            provider: Provider::new(provider[0].parse()?, provider[1].parse()?),
+            radio: parts[1].parse()?,
+            min_value: match parts.get(3) {
+                Some(v) => v.parse()?,
+                None => 1.0,
+            },
+            grid: AsciiGrid::from_path(parts[2])?,
+        })
+    }
+}
+
+/// Coverage taken from coverage maps by sampling them along each edge
+#[derive(Debug, Default)]
+pub struct RasterSource {
+    pub layers: Vec<RasterLayer>,
+}
+
+impl RasterSource {
+    pub fn providers(&self) -> Vec<Provider> {
+        self.layers.iter().map(|l| l.provider).collect()
+    }
+}
+
+impl CoverageSource for RasterSource {
+    fn covered_points(
+        &self,
+        s: &NodeInfo,
+        t: &NodeInfo,
+        fractions: &[f64],
+        providers: &ProviderRegistry,
+    ) -> Vec<Vec<[bool; 3]>> {
+        fractions
+            .iter()
+            .map(|&f| {
+                let lat = s.lat + f * (t.lat - s.lat);
+                let lon = s.long + f * (t.long - s.long);
+                let mut found = vec![[false; 3]; providers.len()];
+                for layer in &self.layers {
+                    let index = match providers.index_of(&layer.provider) {
+                        Some(index) => index,
+                        None => continue,
+                    };
+                    if layer.grid.value_at(lat, lon).map_or(
+                        false,
+                        |v| v >= layer.min_value,
+                    )
+                    {
+                        for class in TechClass::all().iter().filter(|c| c.includes(&layer.radio)) {
+                            found[index][class.index()] = true;
+                        }
+                    }
+                }
+                found
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn reading_ascii_grid() {
+    let asc = "ncols 3\n\
+               nrows 2\n\
+               xllcorner 13.0\n\
+               yllcorner 52.0\n\
+               cellsize 0.1\n\
+               NODATA_value -9999\n\
+               1 0 -9999\n\
+               0 1 1\n";
+    let grid = AsciiGrid::from_reader(asc.as_bytes()).unwrap();
+    assert_eq!(Some(1.0), grid.value_at(52.15, 13.05));
+    assert_eq!(Some(0.0), grid.value_at(52.05, 13.05));
+    assert_eq!(None, grid.value_at(52.15, 13.25));
+    assert_eq!(Some(1.0), grid.value_at(52.05, 13.25));
+    assert_eq!(None, grid.value_at(51.95, 13.05));
+}
+
+#[cfg(test)]
+fn test_source() -> RasterSource {
+    let layer = |provider, radio, values: &str| {
+        let asc = format!(
+            "ncols 2\nnrows 1\nxllcorner 13.0\nyllcorner 52.0\ncellsize 0.01\n{}\n",
+            values
+        );
+        RasterLayer {
+            provider,
+            radio,
+            min_value: 1.0,
+            grid: AsciiGrid::from_reader(asc.as_bytes()).unwrap(),
+        }
+    };
+    // the first provider covers the western half with LTE, the second
+    // everything with UMTS
+    RasterSource {
+        layers: vec![
+            layer(Provider::new(262, 1), TowerType::LTE, "1 0"),
+            layer(Provider::new(262, 2), TowerType::UMTS, "1 1"),
+        ],
+    }
+}
+
+#[test]
+fn coverage_of_an_edge_from_rasters() {
+    use std::collections::HashMap;
+
+    let source = test_source();
+    let providers = ProviderRegistry::from_providers(source.providers(), &HashMap::new());
+    let s = NodeInfo::new(1, 52.005, 13.0, 0);
+    let t = NodeInfo::new(2, 52.005, 13.02, 0);
+    let coverage = source.edge_coverage(&s, &t, &providers);
+
+    let close = |a: f64, b: f64| (a - b).abs() < 0.05;
+    assert!(coverage.providers[0].iter().all(|&v| close(0.5, v)));
+    assert_eq!([1.0, 1.0, 0.0], coverage.providers[1]);
+    assert_eq!([1.0, 1.0], coverage.at_least[0][..2]);
+    assert!(close(0.5, coverage.at_least[0][2]));
+    assert!(close(0.5, coverage.at_least[1][0]) && close(0.5, coverage.at_least[1][1]));
+    assert_eq!(0.0, coverage.at_least[1][2]);
+}
+
+#[test]
+fn combining_rasters_and_towers() {
+    use coverage::Combined;
+    use grid::Grid;
+    use towers::{Tower, TowerSource};
+    use std::collections::HashMap;
+
+    // a GSM tower covering the whole edge and two more spanning the grid
+    let mut towers = vec![
+        Tower::test(1, 51.99, 12.99, 1.0),
+        Tower {
+            radio: TowerType::GSM,
+            ..Tower::test(1, 52.005, 13.01, 2000.0)
+        },
+        Tower::test(1, 52.02, 13.03, 1.0),
+    ];
+    let grid = Grid::new(&mut towers, 10).unwrap();
+    let towers = TowerSource {
+        towers: &towers,
+        grid: &grid,
+        los: None,
+    };
+    let raster = test_source();
+    let providers = ProviderRegistry::from_providers(raster.providers(), &HashMap::new());
+    let s = NodeInfo::new(1, 52.005, 13.0, 0);
+    let t = NodeInfo::new(2, 52.005, 13.02, 0);
+
+    let only_towers = towers.edge_coverage(&s, &t, &providers);
+    assert_eq!([1.0, 0.0, 0.0], only_towers.providers[0]);
+    assert_eq!([0.0, 0.0, 0.0], only_towers.providers[1]);
+
+    // a point is covered if one of the sources covers it
+    let combined = Combined(&towers, &raster).edge_coverage(&s, &t, &providers);
+    let close = |a: f64, b: f64| (a - b).abs() < 0.05;
+    assert_eq!(1.0, combined.providers[0][0]);
+    assert!(close(0.5, combined.providers[0][1]) && close(0.5, combined.providers[0][2]));
+    assert_eq!([1.0, 1.0, 0.0], combined.providers[1]);
+    assert_eq!([1.0, 1.0], combined.at_least[0][..2]);
+    assert!(close(0.5, combined.at_least[0][2]));
+    // the tower of the first provider and the raster of the second
+    // one cover the whole edge together
+    assert_eq!(1.0, combined.at_least[1][0]);
+    assert!(close(0.5, combined.at_least[1][1]));
+    assert_eq!(0.0, combined.at_least[1][2]);
+}
@@ -0,0 +1,222 @@
+use graph::{Graph, Movement, RoadClass};
+use grid::BoundingBox;
+use provider::ProviderSelection;
+use towers::TechClass;
+
+use std::error::Error;
+use std::io::Write;
+
+use csv::Writer;
+
+/// A provider as it appears in the report
+#[derive(Debug, Serialize)]
+pub struct ProviderName {
+    pub id: String,
+    pub name: String,
+}
+
+/// Road length of a part of the network and how much of it is covered
+#[derive(Debug, Serialize)]
+pub struct StatsRow {
+    /// `total`, `movement` or `road_class`
+    pub group: &'static str,
+    pub key: String,
+    /// Length in km
+    pub length: f64,
+    /// Covered km of every provider in the order of the report
+    pub covered: Vec<f64>,
+    /// Km covered by at least one provider
+    pub covered_any: f64,
+}
+
+impl StatsRow {
+    fn new(group: &'static str, key: &str, provider_count: usize) -> StatsRow {
+        StatsRow {
+            group,
+            key: key.to_string(),
+            length: 0.0,
+            covered: vec![0.0; provider_count],
+            covered_any: 0.0,
+        }
+    }
+
+    fn add(&mut self, length: f64, covered: &[f64], covered_any: f64) {
+        self.length += length;
+        for (sum, value) in self.covered.iter_mut().zip(covered) {
+            *sum += value;
+        }
+        self.covered_any += covered_any;
+    }
+}
+
+/// Coverage of the road network
+#[derive(Debug, Serialize)]
+pub struct CoverageStats {
+    pub providers: Vec<ProviderName>,
+    /// The whole network followed by the roads usable for every
+    /// `Movement` and by every `RoadClass`
+    pub rows: Vec<StatsRow>,
+}
+
+impl CoverageStats {
+    /// Writes one CSV row per row of the report with a column of
+    /// covered km for every provider
+    pub fn write_csv<W: Write>(&self, w: W) -> Result<(), Box<Error>> {
+        let mut writer = Writer::from_writer(w);
+        let mut header = vec![
+            "group".to_string(),
+            "key".to_string(),
+            "length_km".to_string(),
+        ];
+        header.extend(self.providers.iter().map(|p| format!("{}_km", p.name)));
+        header.push("any_provider_km".to_string());
+        writer.write_record(&header)?;
+        for row in &self.rows {
+            let mut record = vec![
+                row.group.to_string(),
+                row.key.clone(),
+                format!("{:.3}", row.length),
+            ];
+            record.extend(row.covered.iter().map(|km| format!("{:.3}", km)));
+            record.push(format!("{:.3}", row.covered_any));
+            writer.write_record(&record)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Sums up the length of all roads and their coverage, counting only
+/// towers of technology class `tech`. Both directions of a road share
+/// its shape and are counted once. With `bbox` only subsegments whose
+/// middle lies inside of it are counted.
+pub fn coverage_stats(
+    graph: &Graph,
+    bbox: Option<&BoundingBox>,
+    tech: TechClass,
+) -> CoverageStats {
+    let providers = graph.providers.providers();
+    let layers: Vec<_> = providers
+        .iter()
+        .map(|p| {
+            graph.segment_coverage.get_all(
+                Some(ProviderSelection::Single(*p)),
+                tech,
+            )
+        })
+        .collect();
+    let any = graph.segment_coverage.get_all(Some(ProviderSelection::Any), tech);
+
+    let count = providers.len();
+    let mut total = StatsRow::new("total", "all", count);
+    let mut car = StatsRow::new("movement", "car", count);
+    let mut foot = StatsRow::new("movement", "foot", count);
+    let mut classes: Vec<StatsRow> = RoadClass::all()
+        .iter()
+        .map(|c| StatsRow::new("road_class", c.name(), count))
+        .collect();
+
+    let mut covered = vec![0.0; count];
+    for shape in 0..graph.shapes.shape_count() {
+        // both directions of a road share its shape, so every road is
+        // counted once with the road class of its first edge
+        let edges = graph.shapes.shape_edges(shape);
+        let usable = |movement: &Movement| {
+            edges.iter().any(|&e| !graph.edges[e].is_not_for(movement))
+        };
+        let (for_car, for_foot) = (usable(&Movement::Car), usable(&Movement::Foot));
+        let class = graph.edges[edges[0]].road_class().index() as usize;
+        let points = graph.shapes.points(edges[0]);
+        let weights = graph.shapes.segment_weights(edges[0]);
+        for (i, (n, length)) in weights.into_iter().enumerate() {
+            if let Some(bbox) = bbox {
+                let lat = (points[i].lat + points[i + 1].lat) / 2.0;
+                let long = (points[i].long + points[i + 1].long) / 2.0;
+                if !bbox.contains_point(lat, long) {
+                    continue;
+                }
+            }
+            let km = length / 1000.0;
+            for (value, layer) in covered.iter_mut().zip(&layers) {
+                *value = layer.map_or(0.0, |l| l.get(n) * km);
+            }
+            let covered_any = any.map_or(0.0, |l| l.get(n) * km);
+
+            total.add(km, &covered, covered_any);
+            if for_car {
+                car.add(km, &covered, covered_any);
+            }
+            if for_foot {
+                foot.add(km, &covered, covered_any);
+            }
+            classes[class].add(km, &covered, covered_any);
+        }
+    }
+
+    let mut rows = vec![total, car, foot];
+    rows.extend(classes);
+    CoverageStats {
+        providers: providers
+            .iter()
+            .enumerate()
+            .map(|(i, p)| {
+                ProviderName {
+                    id: p.to_string(),
+                    name: graph.providers.name(i).to_string(),
+                }
+            })
+            .collect(),
+        rows,
+    }
+}
+
+#[test]
+fn coverage_of_the_network() {
+    use graph::EdgeInfo;
+
+    let mut footway = EdgeInfo::new(2, 3, 1.0, 50);
+    footway.not_for_cars();
+    footway.road_class = RoadClass::Path;
+    let mut primary = EdgeInfo::new(1, 2, 1.0, 50);
+    primary.road_class = RoadClass::Primary;
+    let mut back = EdgeInfo::new(2, 1, 1.0, 50);
+    back.road_class = RoadClass::Primary;
+    let graph = Graph::test(vec![primary, back, footway]);
+
+    let stats = coverage_stats(&graph, None, TechClass::Any);
+    let row = |key: &str| stats.rows.iter().find(|r| r.key == key).unwrap();
+    // both directions of the primary road count once
+    assert!((row("all").length - 1.37).abs() < 0.01);
+    assert!((row("car").length - 0.685).abs() < 0.01);
+    assert_eq!(row("all").length, row("foot").length);
+    assert_eq!(row("car").length, row("primary").length);
+    assert_eq!(row("path").length + row("primary").length, row("all").length);
+    assert!((row("primary").covered[0] - 0.685).abs() < 0.01);
+    assert!(row("path").covered[0] > 0.0 && row("path").covered[0] < 0.685);
+    assert_eq!(row("all").covered[0], row("all").covered_any);
+
+    let mut bbox = BoundingBox::new();
+    bbox.add_coord(&(51.9, 12.9));
+    bbox.add_coord(&(52.1, 13.009));
+    let stats = coverage_stats(&graph, Some(&bbox), TechClass::Any);
+    assert_eq!(0.0, stats.rows.iter().find(|r| r.key == "path").unwrap().length);
+
+    let mut csv = Vec::new();
+    stats.write_csv(&mut csv).unwrap();
+    let csv = String::from_utf8(csv).unwrap();
+    assert!(csv.starts_with("group,key,length_km,O2_km,any_provider_km\n"));
+}
+
+#[test]
+fn separate_directions_are_counted_twice() {
+    use graph::{NodeInfo, EdgeInfo};
+
+    // the carriageways of a dual carriageway road run along different
+    // shapes between the same nodes
+    let mut backward = EdgeInfo::new(2, 1, 1.0, 50);
+    backward.shape = vec![NodeInfo::new(4, 52.0001, 13.005, 0)];
+    let graph = Graph::test(vec![EdgeInfo::new(1, 2, 1.0, 50), backward]);
+
+    let stats = coverage_stats(&graph, None, TechClass::Any);
+    assert!((stats.rows[0].length - 1.37).abs() < 0.01);
+}
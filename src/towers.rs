@@ -1,84 +1,99 @@
 use graph::{Longitude, Latitude, NodeInfo};
-use geom::{project, intersect, Coord, SegmentSection};
+use geom::{project, intersect, intersect_sector, in_sector, haversine_distance, Coord,
+           SegmentSection};
+use elevation::Dem;
+use propagation::PropagationModel;
+
+use provider::{Provider, ProviderRegistry};
+use coverage::{Combined, CoverageSource, EdgeCoverage, SampledCoverage};
+use raster::{RasterLayer, RasterSource};
+use grid::Grid;
+use error::Error;
 
-use std::error::Error;
 use std::collections::HashMap;
 use std::fmt;
-use std::cell::UnsafeCell;
+use std::fs::File;
+use std::io;
 use std::path::Path;
+use std::str::FromStr;
 
 use csv::Reader;
-use serde::{Deserializer, Deserialize, Serialize, Serializer};
-use serde::de::{self, Visitor};
 use heapsize::HeapSizeOf;
 
 
-/// Holds coverage values for all edge provider combination.
-/// Allows for coverage values to be written and read in
-/// parallel. This will cause race conditions if the same edge provider combination is
-/// written (and read) by more than one thread. Be careful!
-#[derive(Serialize, Deserialize)]
-pub struct Coverage(HashMap<Provider, UnsafeVec>, usize);
-impl Coverage {
-    pub fn new(size: usize) -> Coverage {
-        let mut map = HashMap::new();
-        map.insert(
-            Provider::Telekom,
-            UnsafeVec(UnsafeCell::new(vec![0.0; size])),
-        );
-        map.insert(
-            Provider::Vodafone,
-            UnsafeVec(UnsafeCell::new(vec![0.0; size])),
-        );
-        map.insert(Provider::O2, UnsafeVec(UnsafeCell::new(vec![0.0; size])));
-        Coverage(map, size)
-    }
+/// A single cell in the format of OpenCellID exports. Files without
+/// a `mcc` column are treated as German (MCC 262) towers.
+#[derive(Debug, Deserialize, Serialize, HeapSizeOf)]
+pub struct Tower {
+    pub radio: TowerType,
+    #[serde(default = "default_mcc")]
+    pub mcc: u16,
+    pub net: u16,
+    pub lat: Latitude,
+    pub lon: Longitude,
+    pub range: f64,
+    /// Direction of a sector antenna in degrees clockwise from north
+    #[serde(default)]
+    pub azimuth: Option<f64>,
+    /// Opening angle of a sector antenna in degrees
+    #[serde(default)]
+    pub beamwidth: Option<f64>,
+    /// Disabled towers are ignored for coverage, e.g. to simulate outages
+    #[serde(default)]
+    pub disabled: bool,
+}
 
+fn default_mcc() -> u16 {
+    262
+}
 
-    pub fn set(&self, p: &Provider, n: usize, value: f64) {
-        assert!(self.1 > n, format!("Index of {} is to high", n));
-        assert!(
-            0.0 <= value && 1.0 >= value,
-            format!("Value {} out of range [0.0,1.0]", value)
-        );
-        let cell = &self.0[p];
-        unsafe {
-            (*cell.0.get())[n] = value;
-        }
+impl Tower {
+    pub fn provider(&self) -> Provider {
+        Provider::new(self.mcc, self.net)
     }
 
-    pub fn get_all(&self, p: Option<Provider>) -> Option<&Vec<f64>> {
-        match p {
-            Some(p) => {
-                let cell = &self.0[&p];
-                unsafe { Some(&*cell.0.get()) }
-            }
-            None => None,
+    /// Direction and opening angle if the tower is a sector antenna
+    pub fn sector(&self) -> Option<(f64, f64)> {
+        match (self.azimuth, self.beamwidth) {
+            (Some(azimuth), Some(beamwidth)) => Some((azimuth, beamwidth)),
+            _ => None,
         }
-
     }
-}
-unsafe impl Sync for Coverage {}
 
-impl HeapSizeOf for Coverage {
-    fn heap_size_of_children(&self) -> usize {
-        let mut size = self.1.heap_size_of_children();
-        for (k, v) in &self.0 {
-            unsafe {
-                size += (*v.0.get()).heap_size_of_children() + k.heap_size_of_children();
+    /// Checks if `point` lies in the direction the tower's antenna
+    /// points to. Towers without sector information send in all
+    /// directions.
+    pub fn faces<C: Coord>(&self, point: &C) -> bool {
+        match self.sector() {
+            Some((azimuth, beamwidth)) => {
+                in_sector(
+                    &project(point, self.lat.to_radians()),
+                    &project(self, self.lat.to_radians()),
+                    azimuth,
+                    beamwidth,
+                )
             }
+            None => true,
         }
-        size
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, HeapSizeOf)]
-pub struct Tower {
-    pub radio: TowerType,
-    pub net: Provider,
-    pub lat: Latitude,
-    pub lon: Longitude,
-    pub range: f64,
+#[cfg(test)]
+impl Tower {
+    /// An LTE tower of provider 262-`net` sending in all directions
+    pub fn test(net: u16, lat: Latitude, lon: Longitude, range: f64) -> Tower {
+        Tower {
+            radio: TowerType::LTE,
+            mcc: 262,
+            net,
+            lat,
+            lon,
+            range,
+            azimuth: None,
+            beamwidth: None,
+            disabled: false,
+        }
+    }
 }
 
 
@@ -89,77 +104,340 @@ pub fn edge_coverage<'a, I: Iterator<Item = &'a Tower>>(
     s: &NodeInfo,
     t: &NodeInfo,
     towers: Vec<I>,
-) -> (f64, f64, f64) {
-    let mut o2_sections = Vec::new();
-    let mut telekom_sections = Vec::new();
-    let mut vodafone_sections = Vec::new();
-    let mut tele_full = false;
-    let mut voda_full = false;
-    let mut o2_full = false;
-    let _: Vec<()> = towers
-        .into_iter()
-        .flat_map(|iter| iter)
-        .filter_map(|tower| {
-            // really ugly hack that gives a great performance gain
-            // because most edges are covered completely by only one
-            // tower
-            match tower.net {
-                Provider::Telekom => {
-                    if tele_full {
-                        return None;
-                    }
-                }
-                Provider::Vodafone => {
-                    if voda_full {
-                        return None;
-                    }
-                }
-                Provider::O2 => {
-                    if o2_full {
-                        return None;
-                    }
+    providers: &ProviderRegistry,
+) -> EdgeCoverage {
+    let sections = edge_sections(s, t, towers, providers);
+    let mut edge = EdgeCoverage::new(providers.len());
+    for tech in TechClass::all() {
+        let c = tech.index();
+        let per_provider: Vec<&Vec<SegmentSection>> = sections.iter().map(|s| &s[c]).collect();
+        for (values, merged) in edge.providers.iter_mut().zip(&per_provider) {
+            values[c] = merged.iter().fold(0.0, |acc, sec| acc + sec.length());
+        }
+        for (values, depth) in edge.at_least.iter_mut().zip(coverage_depths(&per_provider)) {
+            values[c] = depth;
+        }
+    }
+    edge
+}
+
+/// The merged sections of the edge between `s` and `t` in range of
+/// `towers` for every provider and technology class
+fn edge_sections<'a, I: Iterator<Item = &'a Tower>>(
+    s: &NodeInfo,
+    t: &NodeInfo,
+    towers: Vec<I>,
+    providers: &ProviderRegistry,
+) -> Vec<Vec<Vec<SegmentSection>>> {
+    let mut sections: Vec<Vec<Vec<SegmentSection>>> = vec![vec![Vec::new(); 3]; providers.len()];
+    let mut full = vec![[false; 3]; providers.len()];
+    for tower in towers.into_iter().flat_map(|iter| iter) {
+        let index = match providers.index_of(&tower.provider()) {
+            Some(index) => index,
+            None => continue,
+        };
+        let classes: Vec<usize> = TechClass::all()
+            .iter()
+            .filter(|c| c.includes(&tower.radio))
+            .map(TechClass::index)
+            .collect();
+        // really ugly hack that gives a great performance gain
+        // because most edges are covered completely by only one
+        // tower
+        if classes.iter().all(|&c| full[index][c]) {
+            continue;
+        }
+        let lat0 = tower.lat.to_radians();
+        let s = project(s, lat0);
+        let t = project(t, lat0);
+        let tower_point = project(tower, lat0);
+        let tower_sections = match tower.sector() {
+            Some((azimuth, beamwidth)) => {
+                intersect_sector(&s, &t, &tower_point, tower.range, azimuth, beamwidth)
+            }
+            None => vec![intersect(&s, &t, &tower_point, tower.range)],
+        };
+        for sec in tower_sections.into_iter().filter(|sec| !sec.is_empty()) {
+            for &c in &classes {
+                if sec.is_full() {
+                    full[index][c] = true;
                 }
+                sections[index][c].push(sec.clone());
             }
-            let s = project(s, tower.lat);
-            let t = project(t, tower.lat);
-            let tower_point = project(tower, tower.lat);
-            let sec = intersect(&s, &t, &tower_point, tower.range);
-            if !sec.is_empty() {
-                match tower.net {
-                    Provider::Telekom => {
-                        if sec.is_full() {
-                            tele_full = true
-                        }
-                        telekom_sections.push(sec);
-                    }
-                    Provider::Vodafone => {
-                        if sec.is_full() {
-                            voda_full = true
-                        }
-                        vodafone_sections.push(sec);
-                    }
-                    Provider::O2 => {
-                        if sec.is_full() {
-                            o2_full = true
+        }
+    }
+    sections
+        .into_iter()
+        .map(|per_class| per_class.into_iter().map(merge_sections).collect())
+        .collect()
+}
+
+/// Coverage of the towers near an edge as modeled by
+/// `edge_coverage` or `los_edge_coverage` if `los` is given
+pub struct TowerSource<'a> {
+    pub towers: &'a [Tower],
+    /// Has to index `towers`
+    pub grid: &'a Grid,
+    pub los: Option<&'a LineOfSight<'a>>,
+}
+
+impl<'a> TowerSource<'a> {
+    /// All towers near `node` that are not disabled
+    pub fn adjacent(&self, node: &NodeInfo) -> Vec<&'a Tower> {
+        self.grid
+            .adjacent_towers(node, 15000.0, self.towers)
+            .unwrap_or_default()
+            .into_iter()
+            .flat_map(|iter| iter)
+            .filter(|t| !t.disabled)
+            .collect()
+    }
+}
+
+impl<'a> CoverageSource for TowerSource<'a> {
+    fn covered_points(
+        &self,
+        s: &NodeInfo,
+        t: &NodeInfo,
+        fractions: &[f64],
+        providers: &ProviderRegistry,
+    ) -> Vec<Vec<[bool; 3]>> {
+        let adjacent = self.adjacent(s);
+        if let Some(los) = self.los {
+            return fractions
+                .iter()
+                .map(|&f| {
+                    let point = (s.lat + f * (t.lat - s.lat), s.long + f * (t.long - s.long));
+                    los_coverage_at(&point, &adjacent, providers, los)
+                })
+                .collect();
+        }
+        let sections = edge_sections(s, t, vec![adjacent.into_iter()], providers);
+        fractions
+            .iter()
+            .map(|&f| {
+                sections
+                    .iter()
+                    .map(|per_class| {
+                        let mut found = [false; 3];
+                        for c in 0..3 {
+                            found[c] = per_class[c].iter().any(|sec| sec.contains(f));
                         }
-                        o2_sections.push(sec);
-                    }
-                };
-                return Some(());
-            }
+                        found
+                    })
+                    .collect()
+            })
+            .collect()
+    }
 
-            None
+    fn edge_coverage(
+        &self,
+        s: &NodeInfo,
+        t: &NodeInfo,
+        providers: &ProviderRegistry,
+    ) -> EdgeCoverage {
+        let adjacent = vec![self.adjacent(s).into_iter()];
+        match self.los {
+            Some(los) => los_edge_coverage(s, t, adjacent, providers, los),
+            None => edge_coverage(s, t, adjacent, providers),
+        }
+    }
+}
+
+/// Distance in meters between the points sampled along an edge for
+/// the line of sight coverage and coverage maps
+pub const EDGE_SAMPLE_STEP: f64 = 25.0;
+/// Height in meters of the receiving antenna above ground
+const RECEIVER_HEIGHT: f64 = 1.5;
+
+/// Settings for the terrain aware coverage calculation
+pub struct LineOfSight<'a> {
+    pub dem: &'a Dem,
+    /// Height of the tower antennas above ground in meters
+    pub antenna_height: f64,
+}
+
+/// Selects how coverage is calculated while building the graph
+#[derive(Default)]
+pub struct CoverageOptions<'a> {
+    /// Check the terrain between towers and edges instead of using disks
+    pub line_of_sight: Option<LineOfSight<'a>>,
+    /// Additionally estimate signal levels with this model
+    pub signal_model: Option<PropagationModel>,
+    /// Combine the coverage of the towers with these coverage maps
+    pub raster: Option<&'a RasterSource>,
+}
+
+impl<'a> CoverageOptions<'a> {
+    /// The coverage of `towers` combined with the coverage maps if
+    /// there are any. `grid` has to index `towers`.
+    pub fn source<'b>(
+        &'b self,
+        towers: &'b [Tower],
+        grid: &'b Grid,
+    ) -> Box<CoverageSource + 'b> {
+        let towers = TowerSource {
+            towers,
+            grid,
+            los: self.line_of_sight.as_ref(),
+        };
+        match self.raster {
+            Some(raster) => Box::new(Combined(towers, raster)),
+            None => Box::new(towers),
+        }
+    }
+}
+
+/// Coverage settings as given when the graph was built. They are
+/// stored with the graph, so its coverage is updated the same way it
+/// was calculated.
+#[derive(Debug, Clone, Default, PartialEq, HeapSizeOf, Serialize, Deserialize)]
+pub struct CoverageSpec {
+    /// Directory with the elevation data
+    pub elevation: Option<String>,
+    pub line_of_sight: bool,
+    pub antenna_height: f64,
+    pub signal_model: Option<PropagationModel>,
+    /// Coverage maps as `MCC-MNC:RADIO:FILE[:MIN]`
+    pub rasters: Vec<String>,
+}
+
+/// Owned coverage settings a running server keeps to recalculate
+/// coverage after the towers changed, loaded from the `CoverageSpec`
+/// of its graph
+pub struct CoverageSettings {
+    pub dem: Option<Dem>,
+    /// Use the line of sight check if `dem` is given
+    pub line_of_sight: bool,
+    pub antenna_height: f64,
+    pub signal_model: Option<PropagationModel>,
+    pub raster: Option<RasterSource>,
+}
+
+impl CoverageSettings {
+    /// Loads the elevation data and coverage maps of `spec`
+    pub fn load(spec: &CoverageSpec) -> Result<CoverageSettings, Box<::std::error::Error>> {
+        let dem = match spec.elevation {
+            Some(ref dir) => Some(Dem::from_dir(dir)?),
+            None => None,
+        };
+        let mut layers = Vec::new();
+        for layer in &spec.rasters {
+            layers.push(RasterLayer::from_spec(layer)?);
+        }
+        Ok(CoverageSettings {
+            dem,
+            line_of_sight: spec.line_of_sight,
+            antenna_height: spec.antenna_height,
+            signal_model: spec.signal_model,
+            raster: if layers.is_empty() {
+                None
+            } else {
+                Some(RasterSource { layers })
+            },
         })
-        .collect();
-    (
-        accumulate_sections(telekom_sections),
-        accumulate_sections(vodafone_sections),
-        accumulate_sections(o2_sections),
-    )
+    }
+
+    pub fn options(&self) -> CoverageOptions {
+        let line_of_sight = match self.dem {
+            Some(ref dem) if self.line_of_sight => Some(LineOfSight {
+                dem,
+                antenna_height: self.antenna_height,
+            }),
+            _ => None,
+        };
+        CoverageOptions {
+            line_of_sight,
+            signal_model: self.signal_model,
+            raster: self.raster.as_ref(),
+        }
+    }
+
+    /// Registry of the providers of `towers` and of all coverage maps
+    pub fn provider_registry(
+        &self,
+        towers: &[Tower],
+        names: &HashMap<Provider, String>,
+    ) -> ProviderRegistry {
+        let raster = self.raster.as_ref().map(RasterSource::providers);
+        let providers = towers
+            .iter()
+            .map(Tower::provider)
+            .chain(raster.unwrap_or_default());
+        ProviderRegistry::from_providers(providers, names)
+    }
+}
+
+/// Calculate coverage of the edge between `s` and `t` by sampling
+/// points along the edge. A point is covered by a provider if one of
+/// its `towers` is in range and the line of sight between tower and
+/// point clears the terrain.
+pub fn los_edge_coverage<'a, I: Iterator<Item = &'a Tower>>(
+    s: &NodeInfo,
+    t: &NodeInfo,
+    towers: Vec<I>,
+    providers: &ProviderRegistry,
+    los: &LineOfSight,
+) -> EdgeCoverage {
+    let towers: Vec<&Tower> = towers.into_iter().flat_map(|iter| iter).collect();
+    let mut sampled = SampledCoverage::new(providers.len());
+    for f in sample_fractions(s, t) {
+        let point = (s.lat + f * (t.lat - s.lat), s.long + f * (t.long - s.long));
+        sampled.add(&los_coverage_at(&point, &towers, providers, los));
+    }
+    sampled.finish()
+}
+
+/// Which providers cover `point` with which technology class, if the
+/// line of sight to one of their `towers` clears the terrain
+fn los_coverage_at(
+    point: &(f64, f64),
+    towers: &[&Tower],
+    providers: &ProviderRegistry,
+    los: &LineOfSight,
+) -> Vec<[bool; 3]> {
+    let mut found = vec![[false; 3]; providers.len()];
+    for tower in towers {
+        let index = match providers.index_of(&tower.provider()) {
+            Some(index) => index,
+            None => continue,
+        };
+        let classes: Vec<usize> = TechClass::all()
+            .iter()
+            .filter(|c| c.includes(&tower.radio))
+            .map(TechClass::index)
+            .collect();
+        if classes.iter().all(|&c| found[index][c]) ||
+            haversine_distance(*tower, point) > tower.range || !tower.faces(point)
+        {
+            continue;
+        }
+        if los.dem.line_of_sight(
+            *tower,
+            los.antenna_height,
+            point,
+            RECEIVER_HEIGHT,
+        )
+        {
+            for c in classes {
+                found[index][c] = true;
+            }
+        }
+    }
+    found
+}
 
+/// Positions of the points sampled every `EDGE_SAMPLE_STEP` meters
+/// along the edge between `s` and `t` as fractions of its length,
+/// including both ends
+pub fn sample_fractions(s: &NodeInfo, t: &NodeInfo) -> Vec<f64> {
+    let length = haversine_distance(s, t);
+    let samples = ((length / EDGE_SAMPLE_STEP).ceil() as usize + 1).max(2);
+    (0..samples).map(|i| i as f64 / (samples - 1) as f64).collect()
 }
 
-fn accumulate_sections(mut sections: Vec<SegmentSection>) -> f64 {
+/// Merges overlapping `sections` into a sorted list of disjoint sections
+fn merge_sections(mut sections: Vec<SegmentSection>) -> Vec<SegmentSection> {
     sections.sort();
     sections = sections.iter().fold(Vec::new(), |mut acc, sec| {
         if acc.is_empty() {
@@ -185,17 +463,174 @@ fn accumulate_sections(mut sections: Vec<SegmentSection>) -> f64 {
         res <= 1.0 && res >= 0.0,
         format!("calculated illegal tower coverage of {}", res)
     );
-    res
+    sections
 }
 
-pub fn load_towers<P: AsRef<Path>>(p: P) -> Result<Vec<Tower>, Box<Error>> {
-    let mut reader = Reader::from_path(p)?;
+/// Calculates from the merged sections of every provider which share
+/// of the edge is covered by at least `k` providers at once. The
+/// result holds this share for `k` at index `k - 1`.
+fn coverage_depths(per_provider: &[&Vec<SegmentSection>]) -> Vec<f64> {
+    let mut events: Vec<(f64, isize)> = per_provider
+        .iter()
+        .flat_map(|sections| sections.iter())
+        .flat_map(|sec| vec![(sec.start(), 1), (sec.end(), -1)])
+        .collect();
+    // ends before starts at the same position so touching sections
+    // do not count as overlapping
+    events.sort_by(|a, b| {
+        a.0.partial_cmp(&b.0).unwrap().then(a.1.cmp(&b.1))
+    });
+
+    let mut depths = vec![0.0; per_provider.len()];
+    let mut depth = 0;
+    let mut last = 0.0;
+    for (pos, change) in events {
+        for d in &mut depths[..depth as usize] {
+            *d += pos - last;
+        }
+        depth += change;
+        last = pos;
+    }
+    depths
+}
+
+/// Largest tower range in meters that is accepted as plausible
+const MAX_RANGE: f64 = 100_000.0;
+
+/// A row of a tower file that could not be used
+#[derive(Debug)]
+pub struct RowError {
+    /// Line of the row inside of the file
+    pub row: u64,
+    pub reason: String,
+}
+
+impl fmt::Display for RowError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "row {}: {}", self.row, self.reason)
+    }
+}
+
+impl ::std::error::Error for RowError {
+    fn description(&self) -> &str {
+        &self.reason
+    }
+}
+
+/// Summary of loading a tower file
+#[derive(Debug, Default)]
+pub struct TowerReport {
+    /// Number of data rows in the file
+    pub rows: usize,
+    /// Rows that were dropped and why
+    pub dropped: Vec<RowError>,
+}
+
+impl TowerReport {
+    pub fn loaded(&self) -> usize {
+        self.rows - self.dropped.len()
+    }
+}
+
+impl fmt::Display for TowerReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} rows, {} towers loaded, {} rows dropped",
+            self.rows,
+            self.loaded(),
+            self.dropped.len()
+        )?;
+        for error in &self.dropped {
+            writeln!(f, "  {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl Tower {
+    /// Checks if the values of the tower are physically plausible
+    fn validate(&self) -> Result<(), String> {
+        if !(self.lat >= -90.0 && self.lat <= 90.0) {
+            return Err(format!("latitude {} out of range", self.lat));
+        }
+        if !(self.lon >= -180.0 && self.lon <= 180.0) {
+            return Err(format!("longitude {} out of range", self.lon));
+        }
+        if !(self.range > 0.0 && self.range <= MAX_RANGE) {
+            return Err(format!("range {} out of range (0, {}]", self.range, MAX_RANGE));
+        }
+        if let Some(azimuth) = self.azimuth {
+            if !(azimuth >= 0.0 && azimuth <= 360.0) {
+                return Err(format!("azimuth {} out of range [0, 360]", azimuth));
+            }
+        }
+        if let Some(beamwidth) = self.beamwidth {
+            if !(beamwidth > 0.0 && beamwidth <= 360.0) {
+                return Err(format!("beamwidth {} out of range (0, 360]", beamwidth));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Loads all valid towers of the file at `p` and reports every row
+/// that was dropped. In `strict` mode the first invalid row is
+/// returned as error instead.
+pub fn load_towers_with_report<P: AsRef<Path>>(
+    p: P,
+    strict: bool,
+) -> Result<(Vec<Tower>, TowerReport), Error> {
+    read_towers_with_report(File::open(p)?, strict)
+}
+
+/// Like `load_towers_with_report` but reads the tower CSV from `rdr`
+pub fn read_towers_with_report<R: io::Read>(
+    rdr: R,
+    strict: bool,
+) -> Result<(Vec<Tower>, TowerReport), Error> {
+    let mut reader = Reader::from_reader(rdr);
+    let headers = reader.headers()?.clone();
     let mut result = Vec::new();
-    for res in reader.deserialize().filter(|res| res.is_ok()) {
-        let tower: Tower = res?;
-        result.push(tower);
+    let mut report = TowerReport::default();
+    for (i, record) in reader.records().enumerate() {
+        report.rows += 1;
+        // the header occupies the first line
+        let mut row = i as u64 + 2;
+        let tower = record.map_err(|e| e.to_string()).and_then(|record| {
+            if let Some(pos) = record.position() {
+                row = pos.line();
+            }
+            record.deserialize::<Tower>(Some(&headers)).map_err(
+                |e| e.to_string(),
+            )
+        });
+        match tower.and_then(|t| t.validate().map(|_| t)) {
+            Ok(tower) => result.push(tower),
+            Err(reason) => {
+                let error = RowError { row, reason };
+                if strict {
+                    return Err(error.into());
+                }
+                report.dropped.push(error);
+            }
+        }
     }
-    Ok(result)
+    Ok((result, report))
+}
+
+/// Loads all valid towers of the file at `p`. Invalid rows are
+/// dropped and summarized on stdout.
+pub fn load_towers<P: AsRef<Path>>(p: P) -> Result<Vec<Tower>, Error> {
+    let (towers, report) = load_towers_with_report(p, false)?;
+    if !report.dropped.is_empty() {
+        println!(
+            "Dropped {} of {} tower rows, run validate-towers for details",
+            report.dropped.len(),
+            report.rows
+        );
+    }
+    Ok(towers)
 }
 
 
@@ -215,77 +650,111 @@ pub enum TowerType {
     LTE,
     UMTS,
     GSM,
+    CDMA,
+    NR,
 }
 
-#[derive(Debug, HeapSizeOf, PartialEq, Eq, Hash)]
-pub enum Provider {
-    Telekom,
-    Vodafone,
-    O2,
+impl FromStr for TowerType {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "LTE" => Ok(TowerType::LTE),
+            "UMTS" => Ok(TowerType::UMTS),
+            "GSM" => Ok(TowerType::GSM),
+            "CDMA" => Ok(TowerType::CDMA),
+            "NR" => Ok(TowerType::NR),
+            _ => Err(format!("unknown radio type {}", s)),
+        }
+    }
 }
 
-
-impl<'de> Deserialize<'de> for Provider {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        deserializer.deserialize_u64(ProviderVisitor)
-    }
+/// Groups of radio technologies a receiver is able to use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, HeapSizeOf, Serialize, Deserialize)]
+pub enum TechClass {
+    /// Every technology
+    Any,
+    /// UMTS or newer
+    UmtsPlus,
+    /// LTE or newer
+    Lte,
 }
 
-struct ProviderVisitor;
+impl TechClass {
+    pub fn all() -> &'static [TechClass; 3] {
+        static ALL: [TechClass; 3] = [TechClass::Any, TechClass::UmtsPlus, TechClass::Lte];
+        &ALL
+    }
 
-impl<'de> Visitor<'de> for ProviderVisitor {
-    type Value = Provider;
-    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("an integer between 1 and 3")
+    /// Position of the class in per class arrays
+    pub fn index(&self) -> usize {
+        match *self {
+            TechClass::Any => 0,
+            TechClass::UmtsPlus => 1,
+            TechClass::Lte => 2,
+        }
     }
 
-    fn visit_u64<E>(self, value: u64) -> Result<Provider, E>
-    where
-        E: de::Error,
-    {
-        match value {
-            1 => Ok(Provider::Telekom),
-            2 => Ok(Provider::Vodafone),
-            3 => Ok(Provider::O2),
-            _ => Err(E::custom(format!("value out of range 1-3: {}", value))),
+    /// Checks if towers of type `radio` are usable for this class
+    pub fn includes(&self, radio: &TowerType) -> bool {
+        match (*self, radio) {
+            (TechClass::Any, _) |
+            (TechClass::UmtsPlus, &TowerType::UMTS) |
+            (TechClass::UmtsPlus, &TowerType::LTE) |
+            (TechClass::UmtsPlus, &TowerType::NR) |
+            (TechClass::Lte, &TowerType::LTE) |
+            (TechClass::Lte, &TowerType::NR) => true,
+            _ => false,
         }
     }
 }
 
-impl Serialize for Provider {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        serializer.serialize_u64(match *self {
-            Provider::Telekom => 1,
-            Provider::Vodafone => 2,
-            Provider::O2 => 3,
-        })
+impl Default for TechClass {
+    fn default() -> TechClass {
+        TechClass::Any
     }
 }
 
-struct UnsafeVec(UnsafeCell<Vec<f64>>);
+#[test]
+fn depths_of_overlapping_providers() {
+    let a = vec![intersect(&(0.0, 0.0), &(1.0, 0.0), &(0.25, 0.0), 0.25)];
+    let b = vec![intersect(&(0.0, 0.0), &(1.0, 0.0), &(0.5, 0.0), 0.25)];
+    let c = vec![];
+    let depths = coverage_depths(&[&a, &b, &c]);
+    assert!((depths[0] - 0.75).abs() < 1e-9);
+    assert!((depths[1] - 0.25).abs() < 1e-9);
+    assert_eq!(0.0, depths[2]);
+}
 
-impl<'de> Deserialize<'de> for UnsafeVec {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        let vec: Vec<f64> = Vec::deserialize(deserializer)?;
-        Ok(UnsafeVec(UnsafeCell::new(vec)))
-    }
+#[test]
+fn sectors_point_in_their_azimuth() {
+    let towers = vec![
+        Tower {
+            azimuth: Some(90.0),
+            beamwidth: Some(60.0),
+            ..Tower::test(1, 52.0, 13.0, 2000.0)
+        },
+    ];
+    assert!(towers[0].faces(&(52.0, 13.01)));
+    assert!(!towers[0].faces(&(52.0, 12.99)));
+
+    let providers = ProviderRegistry::new(&towers, &HashMap::new());
+    let coverage = |from: f64, to: f64| {
+        let s = NodeInfo::new(1, 52.0, from, 0);
+        let t = NodeInfo::new(2, 52.0, to, 0);
+        edge_coverage(&s, &t, vec![towers.iter()], &providers)
+    };
+    assert_eq!([1.0, 1.0, 1.0], coverage(13.005, 13.02).providers[0]);
+    assert_eq!([0.0, 0.0, 0.0], coverage(12.98, 12.995).providers[0]);
 }
 
-impl Serialize for UnsafeVec {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        let vec = unsafe { &*self.0.get() };
-        vec.serialize(serializer)
-    }
+#[test]
+fn reading_towers_from_memory() {
+    let csv = "radio,mcc,net,lat,lon,range\n\
+               LTE,262,1,52.0,13.0,1000\n\
+               GSM,262,2,95.0,13.0,1000\n";
+    let (towers, report) = read_towers_with_report(csv.as_bytes(), false).unwrap();
+    assert_eq!(1, towers.len());
+    assert_eq!(1, report.loaded());
+    assert_eq!(3, report.dropped[0].row);
+    assert!(read_towers_with_report(csv.as_bytes(), true).is_err());
 }
@@ -1,18 +1,26 @@
-use graph::{NodeId, Graph, RoutingGoal, Movement, NodeInfo};
+use graph::{NodeId, RoutingGoal, Movement, Penalty, NodeInfo};
 use grid::{BoundingBox, NodeInfoWithIndex};
-use towers::{Provider, Tower};
-
-use rocket::State;
-use rocket::request::{FormItems, FromForm, Request, FromFormValue};
+use towers::{Tower, TechClass, CoverageSettings, read_towers_with_report};
+use provider::{Provider, ProviderSelection};
+use matching::match_gpx;
+use stats::coverage_stats;
+use download::{stream_graph, ByteRange, Download, Encoding};
+use {ApplicationState, SharedState};
+
+use rocket::{Data, Outcome, State};
+use rocket::request::{self, FormItems, FromForm, FromRequest, Request, FromFormValue};
 use rocket::response::{self, Response, Responder, NamedFile, Redirect};
-use rocket::response::content::Json;
-use rocket::http::RawStr;
+use rocket::response::content::{Content, Json};
+use rocket::http::{ContentType, RawStr, Status};
 use geojson::{Value, Geometry, Feature, GeoJson, FeatureCollection};
 use serde_json;
-use bincode;
 use rayon::prelude::*;
 
 use std::io::Cursor;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::thread;
+use std::time::Instant;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::error::Error;
@@ -20,33 +28,46 @@ use std::error::Error;
 
 #[allow(needless_pass_by_value)]
 #[get("/low_coverage?<query>")]
-pub fn low_coverage(query: TowerQuery, graph: State<Graph>) -> Json<String> {
+pub fn low_coverage(
+    query: TowerQuery,
+    state: State<SharedState>,
+) -> Option<Json<String>> {
+    let state = state.read().unwrap();
+    let graph = &state.graph;
     let mut bbox = BoundingBox::new();
     bbox.add_coord(&(query.lat_min, query.lon_min));
     bbox.add_coord(&(query.lat_max, query.lon_max));
-    let coverage = graph.coverage.get_all(Some(query.provider)).expect(
-        "No coverage data for given Provider",
-    );
 
-    let mut edges: Vec<(&NodeInfo, &NodeInfo)> = Vec::new();
-    for (n_i, n) in graph.node_info.iter().enumerate() {
-        if bbox.contains_point(n.lat, n.long) {
-            for (e_i, e) in graph.outgoing_edges_for(n_i) {
-                let cov = coverage[e_i];
-                if cov <= 0.5 {
-                    edges.push((n, &graph.node_info[e.endpoint]));
-                }
+    let coverage = graph.segment_coverage.get_all(
+        Some(query.provider),
+        query.tech.unwrap_or_default(),
+    )?;
+
+    // runs of consecutive subsegments without coverage, both
+    // directions of a road share their subsegments
+    let mut lines: Vec<Vec<NodeInfo>> = Vec::new();
+    for shape in 0..graph.shapes.shape_count() {
+        let points = graph.shapes.shape_points(shape);
+        let mut open = false;
+        for (i, n) in graph.shapes.shape_segments(shape).enumerate() {
+            let start = &points[i];
+            if !bbox.contains_point(start.lat, start.long) || coverage.get(n) > 0.5 {
+                open = false;
+                continue;
             }
-
+            if !open {
+                lines.push(vec![start.clone()]);
+                open = true;
+            }
+            lines.last_mut().unwrap().push(points[i + 1].clone());
         }
     }
 
     let mut features = Vec::new();
-    for edge in edges {
-        let geometry = Geometry::new(Value::LineString(vec![
-            vec![edge.0.long, edge.0.lat],
-            vec![edge.1.long, edge.1.lat],
-        ]));
+    for line in lines {
+        let geometry = Geometry::new(Value::LineString(
+            line.iter().map(|n| vec![n.long, n.lat]).collect(),
+        ));
         features.push(Feature {
             bbox: None,
             geometry: Some(geometry),
@@ -62,12 +83,93 @@ pub fn low_coverage(query: TowerQuery, graph: State<Graph>) -> Json<String> {
         foreign_members: None,
     });
 
-    Json(collection.to_string())
+    Some(Json(collection.to_string()))
+}
+
+#[derive(Serialize)]
+struct ProviderEntry<'a> {
+    id: String,
+    name: &'a str,
+}
+
+#[allow(needless_pass_by_value)]
+#[get("/providers")]
+pub fn providers(state: State<SharedState>) -> Result<Json<String>, Box<Error>> {
+    let state = state.read().unwrap();
+    let graph = &state.graph;
+    let providers: Vec<ProviderEntry> = graph
+        .providers
+        .providers()
+        .iter()
+        .enumerate()
+        .map(|(i, p)| {
+            ProviderEntry {
+                id: p.to_string(),
+                name: graph.providers.name(i),
+            }
+        })
+        .collect();
+    Ok(Json(serde_json::to_string(&providers)?))
+}
+
+/// Optional bounding box, technology class and format (`json` or
+/// `csv`) of the coverage statistics
+#[derive(Debug, FromForm)]
+pub struct StatsQuery {
+    lat_max: Option<f64>,
+    lat_min: Option<f64>,
+    lon_max: Option<f64>,
+    lon_min: Option<f64>,
+    tech: Option<TechClass>,
+    format: Option<String>,
+}
+
+/// Road length and its coverage per provider, movement and road class
+/// inside of a bounding box
+#[allow(needless_pass_by_value)]
+#[get("/stats?<query>")]
+pub fn stats(query: StatsQuery, state: State<SharedState>) -> Result<Content<String>, Box<Error>> {
+    let bbox = match (query.lat_min, query.lat_max, query.lon_min, query.lon_max) {
+        (Some(lat_min), Some(lat_max), Some(lon_min), Some(lon_max)) => {
+            let mut bbox = BoundingBox::new();
+            bbox.add_coord(&(lat_min, lon_min));
+            bbox.add_coord(&(lat_max, lon_max));
+            Some(bbox)
+        }
+        (None, None, None, None) => None,
+        _ => return Err("the bounding box is incomplete".into()),
+    };
+    let state = state.read().unwrap();
+    let stats = coverage_stats(
+        &state.graph,
+        bbox.as_ref(),
+        query.tech.unwrap_or_default(),
+    );
+    match query.format.as_ref().map(String::as_str) {
+        Some("csv") => {
+            let mut csv = Vec::new();
+            stats.write_csv(&mut csv)?;
+            Ok(Content(ContentType::CSV, String::from_utf8(csv)?))
+        }
+        Some("json") | None => Ok(Content(ContentType::JSON, serde_json::to_string(&stats)?)),
+        Some(format) => Err(format!("unknown format {}", format).into()),
+    }
+}
+
+/// Statistics of the whole graph as JSON
+#[allow(needless_pass_by_value)]
+#[get("/stats", rank = 2)]
+pub fn stats_all(state: State<SharedState>) -> Result<Json<String>, Box<Error>> {
+    let state = state.read().unwrap();
+    let stats = coverage_stats(&state.graph, None, TechClass::Any);
+    Ok(Json(serde_json::to_string(&stats)?))
 }
 
 #[allow(needless_pass_by_value)]
 #[get("/map_coords")]
-pub fn map_boundary(graph: State<Graph>) -> Json<String> {
+pub fn map_boundary(state: State<SharedState>) -> Json<String> {
+    let state = state.read().unwrap();
+    let graph = &state.graph;
     let bbox = &graph.grid.b_box;
 
     Json(format!(
@@ -82,18 +184,32 @@ pub fn map_boundary(graph: State<Graph>) -> Json<String> {
 
 #[allow(needless_pass_by_value)]
 #[get("/towers?<query>")]
-pub fn towers(query: TowerQuery, towers: State<Vec<Tower>>) -> Result<Json<String>, Box<Error>> {
+pub fn towers(
+    query: TowerQuery,
+    state: State<SharedState>,
+) -> Result<Json<String>, Box<Error>> {
+    let state = state.read().unwrap();
 
     let mut bbox = BoundingBox::new();
     bbox.add_coord(&(query.lat_max, query.lon_max));
     bbox.add_coord(&(query.lat_min, query.lon_min));
-    let towers: Vec<&Tower> = towers
+    let tech = query.tech.unwrap_or_default();
+    let towers: Vec<(usize, &Tower)> = state
+        .towers
         .par_iter()
-        .filter(|t| {
-            t.net == query.provider && bbox.contains_point(t.lat, t.lon)
+        .enumerate()
+        .filter(|&(_, t)| {
+            query.provider.single().map_or(true, |p| t.provider() == p) &&
+                tech.includes(&t.radio) && bbox.contains_point(t.lat, t.lon)
         })
         .collect();
-    Ok(Json(serde_json::to_string(&towers)?))
+    let mut json = Vec::with_capacity(towers.len());
+    for (id, tower) in towers {
+        let mut value = serde_json::to_value(tower)?;
+        value["id"] = id.into();
+        json.push(value);
+    }
+    Ok(Json(serde_json::to_string(&json)?))
 }
 
 #[derive(Debug, FromForm)]
@@ -102,31 +218,200 @@ pub struct TowerQuery {
     lat_min: f64,
     lon_max: f64,
     lon_min: f64,
-    provider: Provider,
+    provider: ProviderSelection,
+    tech: Option<TechClass>,
+}
+
+
+/// Selects towers either by a comma separated list of ids or by a
+/// bounding box
+#[derive(Debug, FromForm)]
+pub struct OutageQuery {
+    ids: Option<String>,
+    lat_max: Option<f64>,
+    lat_min: Option<f64>,
+    lon_max: Option<f64>,
+    lon_min: Option<f64>,
+}
+
+impl OutageQuery {
+    fn tower_ids(&self, towers: &[Tower]) -> Result<Vec<usize>, Box<Error>> {
+        if let Some(ref ids) = self.ids {
+            let mut result = Vec::new();
+            for id in ids.split(',').filter(|id| !id.is_empty()) {
+                let id: usize = id.trim().parse()?;
+                if id >= towers.len() {
+                    return Err(format!("no tower with id {}", id).into());
+                }
+                result.push(id);
+            }
+            return Ok(result);
+        }
+        match (self.lat_min, self.lat_max, self.lon_min, self.lon_max) {
+            (Some(lat_min), Some(lat_max), Some(lon_min), Some(lon_max)) => {
+                let mut bbox = BoundingBox::new();
+                bbox.add_coord(&(lat_min, lon_min));
+                bbox.add_coord(&(lat_max, lon_max));
+                Ok(
+                    towers
+                        .iter()
+                        .enumerate()
+                        .filter(|&(_, t)| bbox.contains_point(t.lat, t.lon))
+                        .map(|(i, _)| i)
+                        .collect(),
+                )
+            }
+            _ => Err("either ids or a complete bounding box are needed".into()),
+        }
+    }
+}
+
+#[allow(needless_pass_by_value)]
+#[post("/towers/disable?<query>")]
+pub fn disable_towers(
+    query: OutageQuery,
+    state: State<SharedState>,
+    settings: State<Arc<CoverageSettings>>,
+) -> Result<Json<String>, Box<Error>> {
+    set_towers_disabled(&query, true, &state, &settings)
+}
+
+#[allow(needless_pass_by_value)]
+#[post("/towers/enable?<query>")]
+pub fn enable_towers(
+    query: OutageQuery,
+    state: State<SharedState>,
+    settings: State<Arc<CoverageSettings>>,
+) -> Result<Json<String>, Box<Error>> {
+    set_towers_disabled(&query, false, &state, &settings)
+}
+
+fn set_towers_disabled(
+    query: &OutageQuery,
+    disabled: bool,
+    state: &RwLock<ApplicationState>,
+    settings: &CoverageSettings,
+) -> Result<Json<String>, Box<Error>> {
+    if RELOADING.load(Ordering::SeqCst) {
+        return Err("towers are being reloaded".into());
+    }
+    let mut state = state.write().unwrap();
+    let ids = query.tower_ids(&state.towers)?;
+    let (towers, edges) = state.set_towers_disabled(&ids, disabled, &settings.options());
+    Ok(Json(
+        format!("{{\"towers\": {}, \"edges\": {}}}", towers, edges),
+    ))
+}
+
+/// Set while a new tower file is processed in the background
+static RELOADING: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Clears `RELOADING` when dropped, also if the reload panics
+struct ReloadGuard;
+
+impl Drop for ReloadGuard {
+    fn drop(&mut self) {
+        RELOADING.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Replaces all towers with the tower CSV in the request body. The
+/// coverage is calculated in the background and swapped in once it is
+/// done, requests are answered with the old towers until then.
+#[allow(needless_pass_by_value)]
+#[post("/towers/reload", data = "<data>")]
+pub fn reload_towers(
+    data: Data,
+    state: State<SharedState>,
+    settings: State<Arc<CoverageSettings>>,
+) -> Result<Json<String>, Box<Error>> {
+    let (towers, report) = read_towers_with_report(data.open(), false)?;
+    if RELOADING
+        .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        return Err("towers are already being reloaded".into());
+    }
+    let guard = ReloadGuard;
+    let state = state.inner().clone();
+    let settings = settings.inner().clone();
+    thread::spawn(move || {
+        let _guard = guard;
+        swap_towers(&state, &settings, towers);
+    });
+    Ok(Json(format!(
+        "{{\"towers\": {}, \"dropped\": {}}}",
+        report.loaded(),
+        report.dropped.len()
+    )))
 }
 
+fn swap_towers(
+    state: &RwLock<ApplicationState>,
+    settings: &CoverageSettings,
+    mut towers: Vec<Tower>,
+) {
+    let start = Instant::now();
+    let coverage = {
+        let state = state.read().unwrap();
+        let providers = settings.provider_registry(&towers, &state.graph.providers.name_map());
+        match state.graph.tower_coverage(&mut towers, providers, &settings.options()) {
+            Ok(coverage) => coverage,
+            Err(e) => {
+                println!("could not swap in the new towers: {}", e);
+                return;
+            }
+        }
+    };
+    let count = towers.len();
+    let mut state = state.write().unwrap();
+    state.graph.set_tower_coverage(coverage);
+    state.towers = towers;
+    println!(
+        "swapped in {} towers after {:?}",
+        count,
+        Instant::now().duration_since(start)
+    );
+}
+
+/// Matches the GPX track in the request body onto the graph and returns
+/// the route with its covered and uncovered stretches per provider
+#[allow(needless_pass_by_value)]
+#[post("/match", data = "<data>")]
+pub fn match_track(data: Data, state: State<SharedState>) -> Result<Json<String>, Box<Error>> {
+    let state = state.read().unwrap();
+    let report = match_gpx(&state.graph, data.open())?;
+    Ok(Json(serde_json::to_string(&report)?))
+}
 
 #[allow(needless_pass_by_value)]
 #[get("/route?<q>")]
-pub fn route(q: DijkQuery, graph: State<Graph>) -> Json<String> {
+pub fn route(q: DijkQuery, state: State<SharedState>) -> Json<String> {
+    let state = state.read().unwrap();
+    let graph = &state.graph;
     let mut d = graph.dijkstra();
-    let route = d.distance(q.s, q.t, q.goal, q.movement, q.provider);
+    let route = d.distance(
+        q.s,
+        q.t,
+        q.goal,
+        q.movement,
+        q.provider,
+        q.tech,
+        q.penalty,
+    );
     let route = match route {
         Some(r) => r,
         None => {
             return Json(
-                "{\"distance\": 0, \"travel_time\": 0, \"route\": []}".to_string(),
+                "{\"error\": \"no route between the selected nodes\"}".to_string(),
             )
         }
     };
     let geometry = Geometry::new(Value::LineString(
-        route
-            .node_seq
+        graph
+            .path_points(&route.node_seq)
             .iter()
-            .map(|&n| {
-                let node = &graph.node_info[n];
-                vec![node.long, node.lat]
-            })
+            .map(|node| vec![node.long, node.lat])
             .collect(),
     ));
 
@@ -140,11 +425,15 @@ pub fn route(q: DijkQuery, graph: State<Graph>) -> Json<String> {
 
     Json(
         format!(
-            "{{ \"distance\": {:.*}, \"travel_time\": {:.*},   \"route\": {} }}",
+            "{{ \"distance\": {:.*}, \"travel_time\": {:.*}, \"ascent\": {:.*}, \"descent\": {:.*},   \"route\": {} }}",
             2,
             route.distance / 1000.0,
             2,
             route.travel_time / 1000.0,
+            0,
+            route.ascent,
+            0,
+            route.descent,
             geo.to_string()
         ).to_string(),
     )
@@ -156,7 +445,9 @@ pub struct DijkQuery {
     t: NodeId,
     goal: RoutingGoal,
     movement: Movement,
-    provider: Option<Provider>,
+    provider: Option<ProviderSelection>,
+    tech: TechClass,
+    penalty: Penalty,
 }
 
 #[derive(Debug)]
@@ -176,6 +467,8 @@ impl<'f> FromForm<'f> for DijkQuery {
         let mut goal = RoutingGoal::Length;
         let mut movement = Movement::Car;
         let mut provider = None;
+        let mut tech = TechClass::Any;
+        let mut penalty = Penalty::Coverage;
         for item in form_items {
 
             match item.0.as_str() {
@@ -184,6 +477,8 @@ impl<'f> FromForm<'f> for DijkQuery {
                 "goal" => goal = item.1.parse()?,
                 "move" => movement = item.1.parse()?,
                 "provider" => provider = Some(item.1.parse()?), 
+                "tech" => tech = item.1.parse()?,
+                "penalty" => penalty = item.1.parse()?,
                 _ => (),
             };
         }
@@ -199,6 +494,8 @@ impl<'f> FromForm<'f> for DijkQuery {
             goal,
             movement,
             provider,
+            tech,
+            penalty,
         })
     }
 }
@@ -225,20 +522,64 @@ impl FromStr for Movement {
     }
 }
 
+impl FromStr for TechClass {
+    type Err = ParseQueryErr;
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        match string {
+            "any" => Ok(TechClass::Any),
+            "umts" => Ok(TechClass::UmtsPlus),
+            "lte" => Ok(TechClass::Lte),
+            _ => Err(ParseQueryErr::ParseErr),
+        }
+    }
+}
+
+impl<'v> FromFormValue<'v> for TechClass {
+    type Error = ParseQueryErr;
 
-impl FromStr for Provider {
+    fn from_form_value(form_value: &RawStr) -> Result<Self, Self::Error> {
+        form_value.parse()
+    }
+}
+
+impl FromStr for Penalty {
     type Err = ParseQueryErr;
     fn from_str(string: &str) -> Result<Self, Self::Err> {
         match string {
-            "telekom" => Ok(Provider::Telekom),
-            "vodafone" => Ok(Provider::Vodafone),
-            "o2" => Ok(Provider::O2),
+            "coverage" => Ok(Penalty::Coverage),
+            "signal" => Ok(Penalty::Signal),
+            _ => Err(ParseQueryErr::ParseErr),
+        }
+    }
+}
+
+/// Providers are given as MCC and MNC separated by a dash, e.g. `262-01`
+impl FromStr for Provider {
+    type Err = ParseQueryErr;
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let mut parts = string.splitn(2, '-');
+        match (parts.next(), parts.next()) {
+            (Some(mcc), Some(mnc)) => Ok(Provider::new(mcc.parse()?, mnc.parse()?)),
             _ => Err(ParseQueryErr::ParseErr),
         }
     }
 }
 
-impl<'v> FromFormValue<'v> for Provider {
+/// Besides single providers `any`, `all` and `min-<k>` select the
+/// combined coverage of several providers
+impl FromStr for ProviderSelection {
+    type Err = ParseQueryErr;
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        match string {
+            "any" => Ok(ProviderSelection::Any),
+            "all" => Ok(ProviderSelection::All),
+            s if s.starts_with("min-") => Ok(ProviderSelection::AtLeast(s[4..].parse()?)),
+            s => Ok(ProviderSelection::Single(s.parse()?)),
+        }
+    }
+}
+
+impl<'v> FromFormValue<'v> for ProviderSelection {
     type Error = ParseQueryErr;
 
     fn from_form_value(form_value: &RawStr) -> Result<Self, Self::Error> {
@@ -260,13 +601,19 @@ impl From<::std::num::ParseFloatError> for ParseQueryErr {
 
 #[allow(needless_pass_by_value)]
 #[get("/node_at?<q>")]
-pub fn next_node_to(q: NNQuery, graph: State<Graph>) -> Option<NodeInfoWithIndex> {
-    graph.next_node_to(q.lat, q.long)
+pub fn next_node_to(
+    q: NNQuery,
+    state: State<SharedState>,
+) -> Option<NodeInfoWithIndex> {
+    let state = state.read().unwrap();
+    let graph = &state.graph;
+    graph.next_node_to(q.lat, q.long, &q.movement)
 }
 
 pub struct NNQuery {
     lat: f64,
     long: f64,
+    movement: Movement,
 }
 
 impl<'f> FromForm<'f> for NNQuery {
@@ -278,6 +625,7 @@ impl<'f> FromForm<'f> for NNQuery {
         use std::f64;
         let mut lat: f64 = f64::MAX;
         let mut long: f64 = f64::MAX;
+        let mut movement = Movement::Car;
         for item in form_items {
             if item.0 == "lat" {
                 lat = item.1.parse()?;
@@ -285,6 +633,9 @@ impl<'f> FromForm<'f> for NNQuery {
             if item.0 == "long" {
                 long = item.1.parse()?;
             }
+            if item.0 == "move" {
+                movement = item.1.parse()?;
+            }
         }
         if f64::MAX - lat < f64::EPSILON {
             return Err(ParseQueryErr::ItemNotPresen("No parameter \"lat\" present"));
@@ -294,7 +645,11 @@ impl<'f> FromForm<'f> for NNQuery {
                 "No parameter \"long\" present",
             ));
         }
-        Ok(NNQuery { lat, long })
+        Ok(NNQuery {
+            lat,
+            long,
+            movement,
+        })
     }
 }
 
@@ -308,35 +663,67 @@ impl<'a> Responder<'a> for NodeInfoWithIndex {
     }
 }
 
-pub struct GraphDownload(Vec<u8>);
+/// Encoding and byte range a client asked for
+pub struct DownloadRequest {
+    encoding: Encoding,
+    range: Option<ByteRange>,
+    /// ETag the range belongs to
+    if_range: Option<String>,
+}
 
-impl<'a> Responder<'a> for GraphDownload {
-    fn respond_to(self, _: &Request) -> response::Result<'a> {
-        Response::build().sized_body(Cursor::new(self.0)).ok()
+impl<'a, 'r> FromRequest<'a, 'r> for DownloadRequest {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<DownloadRequest, ()> {
+        let headers = request.headers();
+        Outcome::Success(DownloadRequest {
+            encoding: Encoding::negotiate(headers.get_one("Accept-Encoding")),
+            range: headers.get_one("Range").and_then(ByteRange::parse),
+            if_range: headers.get_one("If-Range").map(str::to_string),
+        })
     }
 }
 
-
-#[derive(Serialize)]
-pub struct ApplicationStateRef<'a> {
-    graph: &'a Graph,
-    towers: &'a Vec<Tower>,
+impl<'a> Responder<'a> for Download {
+    fn respond_to(self, _: &Request) -> response::Result<'a> {
+        let stream = match self {
+            Download::Stream(stream) => stream,
+            Download::Unsatisfiable(len) => {
+                return Response::build()
+                    .status(Status::RangeNotSatisfiable)
+                    .raw_header("Content-Range", format!("bytes */{}", len))
+                    .ok()
+            }
+        };
+        let mut response = Response::build();
+        response.raw_header("Accept-Ranges", "bytes");
+        response.raw_header("ETag", stream.etag);
+        if let Some(encoding) = stream.encoding.header() {
+            response.raw_header("Content-Encoding", encoding);
+        }
+        if let Some((first, last, len)) = stream.range {
+            response.status(Status::PartialContent).raw_header(
+                "Content-Range",
+                format!("bytes {}-{}/{}", first, last, len),
+            );
+        }
+        response.streamed_body(stream.body).ok()
+    }
 }
 
+/// Streams the preprocessed graph, compressed if the client accepts it
 #[allow(needless_pass_by_value)]
 #[get("/download_graph")]
 pub fn download(
-    g: State<Graph>,
-    towers: State<Vec<Tower>>,
-) -> Result<GraphDownload, Box<bincode::ErrorKind>> {
-    let state = ApplicationStateRef {
-        graph: g.inner(),
-        towers: towers.inner(),
-    };
-
-    Ok(GraphDownload(
-        bincode::serialize(&state, bincode::Infinite)?,
-    ))
+    state: State<SharedState>,
+    request: DownloadRequest,
+) -> Result<Download, ::error::Error> {
+    stream_graph(
+        state.inner(),
+        request.encoding,
+        request.range,
+        request.if_range.as_ref().map(String::as_str),
+    )
 }
 
 #[get("/files/<path..>")]
@@ -13,24 +13,29 @@ L.tileLayer('http://{s}.tile.openstreetmap.org/{id}/{z}/{x}/{y}.png', {
     id: ''
 }).addTo(map);
 
+var providers = [];
+
 $("input").change(function() {
     calcDistWithCurrentSelection();
 });
 
 panOutMap();
+loadProviders();
 
 function calcDistWithCurrentSelection(){
     var goal = document.querySelector('input[name="goal"]:checked').value;
     var move = document.querySelector('input[name="move"]:checked').value;
-    var provider = document.querySelector('input[name="provider"]:checked').value;
+    var penalty = document.querySelector('input[name="penalty"]:checked').value;
+    var tech = currentTech();
+    var provider = currentProvider();
     geoJson.clearLayers(); 
-    if(provider == "all"){
-	calcDist(goal, move, "telekom");
-	calcDist(goal, move, "vodafone");
-	calcDist(goal, move, "o2");
-	calcDist(goal, move, "none");
+    if(provider == "compare"){
+	providers.forEach(function(p) {
+	    calcDist(goal, move, penalty, tech, p.id);
+	});
+	calcDist(goal, move, penalty, tech, "none");
     }else {
-	calcDist(goal, move, provider);
+	calcDist(goal, move, penalty, tech, provider);
     }
 }
 
@@ -61,17 +66,21 @@ function getNode(id, latlng){
 	    calcDistWithCurrentSelection();
 	}
     };
-    xmlhttp.open("GET", "/node_at?lat="+ latlng.lat  + "&long=" + latlng.lng, true);
+    var move = document.querySelector('input[name="move"]:checked').value;
+    xmlhttp.open("GET", "/node_at?lat="+ latlng.lat  + "&long=" + latlng.lng + "&move=" + move, true);
     xmlhttp.send();
 }
 
-function calcDist(goal, move, provider){
+function calcDist(goal, move, penalty, tech, provider){
     
     var xmlhttp = new XMLHttpRequest();
     
     xmlhttp.responseType = 'json';
     xmlhttp.onload = function() {
-	if (xmlhttp.status == 200) {
+	if (xmlhttp.status == 200 && xmlhttp.response.error) {
+	    document.getElementById("dist").innerHTML = xmlhttp.response.error;
+	}
+	else if (xmlhttp.status == 200) {
 	    var myStyle = {
 		"color": getColor(provider),
 		"weight": 5,
@@ -79,7 +88,9 @@ function calcDist(goal, move, provider){
 	    };
 	    document.getElementById("dist").innerHTML = xmlhttp.response.distance;
 	    document.getElementById("time").innerHTML = xmlhttp.response.travel_time;
-	    document.getElementById("prov").innerHTML = provider;
+	    document.getElementById("ascent").innerHTML = xmlhttp.response.ascent;
+	    document.getElementById("descent").innerHTML = xmlhttp.response.descent;
+	    document.getElementById("prov").innerHTML = providerName(provider);
 	    geoJson.addLayer(L.geoJSON(xmlhttp.response.route.geometry, { style: myStyle }));
 	}
 	else {
@@ -92,7 +103,7 @@ function calcDist(goal, move, provider){
     }
     var s = document.getElementById("start").innerHTML;
     var t = document.getElementById("end").innerHTML;
-    xmlhttp.open("GET", "/route?s="+ s  + "&t=" + t+ "&goal=" + goal + "&move=" + move + provider_param, true);
+    xmlhttp.open("GET", "/route?s="+ s  + "&t=" + t+ "&goal=" + goal + "&move=" + move + "&penalty=" + penalty + "&tech=" + tech + provider_param, true);
     xmlhttp.send();
 }
 
@@ -102,7 +113,7 @@ function renderTowers(){
     
     console.log("loading towers");
     
-    var provider = document.querySelector('input[name="provider"]:checked').value;
+    var provider = currentProvider();
     xmlhttp.responseType = 'json';
     xmlhttp.onload = function() {
 	if (xmlhttp.status == 200) {
@@ -118,12 +129,24 @@ function renderTowers(){
     var latMax = bounds.getNorth();
     var longMin = bounds.getWest();
     var longMax = bounds.getEast();
-    xmlhttp.open("GET", "/towers?lat_min="+ latMin + "&lat_max=" + latMax + "&lon_min="+ longMin + "&lon_max=" + longMax + "&provider=" + provider, true);
+    xmlhttp.open("GET", "/towers?lat_min="+ latMin + "&lat_max=" + latMax + "&lon_min="+ longMin + "&lon_max=" + longMax + "&provider=" + provider + "&tech=" + currentTech(), true);
     xmlhttp.send();
 }
 
+function currentProvider(){
+    var provider = document.querySelector('input[name="provider"]:checked').value;
+    if (provider == "min") {
+	return "min-" + document.getElementById("min-providers").value;
+    }
+    return provider;
+}
+
+function currentTech(){
+    return document.querySelector('input[name="tech"]:checked').value;
+}
+
 function getColor(provider){
-    switch (provider){
+    switch (providerName(provider).toLowerCase()){
     case "telekom":
 	return "#E20074";
     case "vodafone":
@@ -131,9 +154,49 @@ function getColor(provider){
     case "o2":
 	return "#0090D0";
     }
+    var palette = ["#FF8C00", "#2E8B57", "#8A2BE2", "#B8860B", "#008B8B", "#DC143C"];
+    for (var i = 0; i < providers.length; i++) {
+	if (providers[i].id == provider) {
+	    return palette[i % palette.length];
+	}
+    }
     return "#000000";
 }
 
+function providerName(provider){
+    for (var i = 0; i < providers.length; i++) {
+	if (providers[i].id == provider) {
+	    return providers[i].name;
+	}
+    }
+    return provider;
+}
+
+function loadProviders(){
+    var xmlhttp = new XMLHttpRequest();
+    xmlhttp.responseType = "json";
+    xmlhttp.onload = function(){
+	if (xmlhttp.status == 200){
+	    providers = xmlhttp.response;
+	    var group = document.getElementById("providers");
+	    providers.slice().reverse().forEach(function(p) {
+		var label = document.createElement("label");
+		label.className = "btn btn-secondary";
+		var input = document.createElement("input");
+		input.type = "radio";
+		input.name = "provider";
+		input.value = p.id;
+		input.onchange = calcDistWithCurrentSelection;
+		label.appendChild(input);
+		label.appendChild(document.createTextNode(p.name));
+		group.insertBefore(label, group.firstChild);
+	    });
+	}
+    };
+
+    xmlhttp.open("GET", "/providers");
+    xmlhttp.send();
+}
 
 function panOutMap(){
     var xmlhttp = new XMLHttpRequest();
@@ -153,10 +216,13 @@ function showLowCoverageEdges(){
     geoJson.clearLayers();
     var xmlhttp = new XMLHttpRequest();
     
-    var provider = document.querySelector('input[name="provider"]:checked').value;
+    var provider = currentProvider();
     xmlhttp.responseType = 'json';
     xmlhttp.onload = function() {
-	if (xmlhttp.status == 200) {
+	if (xmlhttp.status == 200 && xmlhttp.response.error) {
+	    document.getElementById("dist").innerHTML = xmlhttp.response.error;
+	}
+	else if (xmlhttp.status == 200) {
 	    var myStyle = {
 		"color": getColor(provider),
 		"weight": 5,
@@ -170,6 +236,6 @@ function showLowCoverageEdges(){
     var latMax = bounds.getNorth();
     var longMin = bounds.getWest();
     var longMax = bounds.getEast();
-    xmlhttp.open("GET", "/low_coverage?lat_min="+ latMin + "&lat_max=" + latMax + "&lon_min="+ longMin + "&lon_max=" + longMax + "&provider=" + provider, true);
+    xmlhttp.open("GET", "/low_coverage?lat_min="+ latMin + "&lat_max=" + latMax + "&lon_min="+ longMin + "&lon_max=" + longMax + "&provider=" + provider + "&tech=" + currentTech(), true);
     xmlhttp.send();
 }